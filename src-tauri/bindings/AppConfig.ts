@@ -1,5 +1,7 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { PreviewBackendInfo } from "./PreviewBackendInfo";
 
 export interface AppConfig {
   previewApiUrl: string;
+  previewBackends: Array<PreviewBackendInfo>;
 }
@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DriveMode } from "./DriveMode";
+
+export interface Capture {
+  taken: string;
+  camera: string | null;
+  driveMode: DriveMode | null;
+  sequence: number | null;
+  exposureTime: number | null;
+  fNumber: number | null;
+  iso: number | null;
+  exposureBias: number | null;
+  focalLength: number | null;
+  subjectDistance: number | null;
+}
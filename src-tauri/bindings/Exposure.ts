@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Histogram } from "./Histogram";
+
+export interface Exposure {
+  histogram: Histogram;
+  clippedHighlights: number;
+  crushedShadows: number;
+}
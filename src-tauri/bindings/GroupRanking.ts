@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FrameScore } from "./FrameScore";
+
+export interface GroupRanking {
+  keeper: string;
+  scores: Array<FrameScore>;
+}
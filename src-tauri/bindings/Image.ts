@@ -1,9 +1,18 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Capture } from "./Capture";
 import type { CullState } from "./CullState";
+import type { ImageAnalysis } from "./ImageAnalysis";
+import type { Sequence } from "./Sequence";
 
 export interface Image {
   path: string;
   previewPath: string;
   created: string;
   state: CullState;
+  previewError: string | null;
+  analysis: ImageAnalysis | null;
+  suggestedReject: boolean;
+  blank: boolean;
+  capture: Capture | null;
+  sequence: Sequence | null;
 }
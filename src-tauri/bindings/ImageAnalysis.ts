@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface ImageAnalysis {
+  sharpness: number;
+  subjectContrast: number;
+  clippedHighlights: number;
+  crushedShadows: number;
+  lumaDeviation: number;
+  noise: number;
+  motionBlur: number;
+  quality: number;
+}
@@ -1,8 +1,12 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 import type { Image } from "./Image";
+import type { ImageSort } from "./ImageSort";
+import type { ImageStack } from "./ImageStack";
 
 export interface ImageDir {
   images: Array<Image>;
+  stacks: Array<ImageStack>;
+  sort: ImageSort;
   path: string;
   dirName: string;
 }
@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { StackKind } from "./StackKind";
+
+export interface ImageStack {
+  id: string;
+  kind: StackKind;
+  images: Array<string>;
+}
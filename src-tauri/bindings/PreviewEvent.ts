@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { PreviewEventKind } from "./PreviewEventKind";
+import type { PreviewProgress } from "./PreviewProgress";
+import type { PreviewSize } from "./PreviewSize";
+
+export interface PreviewEvent {
+  kind: PreviewEventKind;
+  path: string | null;
+  size: PreviewSize | null;
+  error: string | null;
+  progress: PreviewProgress;
+}
@@ -0,0 +1,11 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface PreviewProgress {
+  dir: string | null;
+  total: number;
+  queued: number;
+  running: number;
+  done: number;
+  failed: number;
+  etaSecs: number | null;
+}
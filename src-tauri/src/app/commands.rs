@@ -1,12 +1,21 @@
 #![allow(clippy::used_underscore_binding)] // tauri commands fail this lint
 
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use chrono::Datelike;
 use tokio::sync::RwLock;
 
 use super::state::AppState;
-use crate::image::{get_images, read_cull_meta_or_default, CullState, Image, META_EXT};
+use crate::image::{
+    get_images, group_similar, group_stacks, merge_groups, rank_groups, read_cull_meta_or_default,
+    release_waiters, sort_by_capture, sort_by_quality, tag_sequences, update_cull_meta, CacheStats,
+    CullMeta, CullState, GroupRanking, Image, ImageSort, ImageStack, PreviewBackendInfo,
+    PreviewProgress, PreviewStatus, SchedulerConfig, StackEditor, StackKind, META_EXT,
+};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
@@ -15,6 +24,7 @@ use ts_rs::TS;
 #[ts(export)]
 pub(super) struct AppConfig {
     preview_api_url: String,
+    preview_backends: Vec<PreviewBackendInfo>,
 }
 
 #[derive(Serialize, TS)]
@@ -22,6 +32,9 @@ pub(super) struct AppConfig {
 #[ts(export)]
 pub(super) struct ImageDir {
     images: Vec<Image>,
+    /// Bursts, sequences and the stacks edited by the user
+    stacks: Vec<ImageStack>,
+    sort: ImageSort,
     path: PathBuf,
     dir_name: String,
 }
@@ -34,32 +47,27 @@ pub(super) struct CulledImages(HashMap<PathBuf, CullState>);
 pub(super) async fn get_config(app_state: tauri::State<'_, AppState>) -> Result<AppConfig, String> {
     Ok(AppConfig {
         preview_api_url: app_state.preview_api_url().to_owned(),
+        preview_backends: app_state.preview_backends().to_vec(),
     })
 }
 
 #[tauri::command]
-pub(super) async fn cull_images(culled: CulledImages) -> Result<(), String> {
-    // todo: join all writes (parallel using tokio)
-    // todo: first read meta (should already exist from preview gen)
-    // todo: update state & write the files back
-
-    for (mut path, state) in culled.0 {
-        path.set_extension(META_EXT);
-
-        let mut cull_meta = read_cull_meta_or_default(&path).await;
-
-        if cull_meta.cull_state != state {
-            cull_meta.cull_state = state;
-            tokio::fs::write(
-                path,
-                serde_json::to_vec_pretty(&cull_meta).map_err(|e| e.to_string())?,
-            )
-            .await
-            .map_err(|e| e.to_string())?;
-        }
+pub(super) async fn cull_images(
+    app_state: tauri::State<'_, AppState>,
+    culled: CulledImages,
+) -> Result<(), String> {
+    for (preview_path, state) in &culled.0 {
+        app_state
+            .open_dir()
+            .update(preview_path, |img| img.state = state.clone());
     }
 
-    Ok(())
+    update_cull_metas(culled.0.into_iter().map(|(preview_path, state)| {
+        (preview_path, move |meta: &mut CullMeta| {
+            meta.cull_state = state;
+        })
+    }))
+    .await
 }
 
 #[tauri::command]
@@ -67,14 +75,7 @@ pub(super) async fn finish_culling(
     app_state: tauri::State<'_, AppState>,
     edit_dir: String,
 ) -> Result<(), String> {
-    let dir_path = app_state
-        .dir()
-        .lock()
-        .await
-        .clone()
-        .ok_or_else(|| "Dir not selected".to_owned())?;
-
-    let imgs = get_images(&dir_path).await.map_err(|e| e.to_string())?;
+    let (dir_path, imgs) = open_images(&app_state)?;
 
     if imgs.is_empty() {
         return Err("No images to process".to_owned());
@@ -92,8 +93,10 @@ pub(super) async fn finish_culling(
         .created
         .date_naive();
 
-    // todo: config
-    let mut edit_root = PathBuf::from_str("W:\\Photos\\Edit").map_err(|e| e.to_string())?;
+    let mut edit_root = app_state
+        .dirs()
+        .edit()
+        .ok_or_else(|| "Edit dir is not configured".to_owned())?;
     // year
     edit_root.push(min_created.year().to_string());
     // quarter
@@ -109,9 +112,13 @@ pub(super) async fn finish_culling(
         match cull_meta.cull_state {
             // already handled above
             CullState::New => {}
-            // move accepted imgs to an edit folder
+            // move accepted imgs to an edit folder, sequences to a subfolder of it
             CullState::Selected => {
-                let to = edit_root.join(
+                let dir = match &img.sequence {
+                    Some(sequence) => edit_root.join(&sequence.id),
+                    None => edit_root.clone(),
+                };
+                let to = dir.join(
                     img.path
                         .file_name()
                         .ok_or_else(|| format!("Invalid filename {:?}", img.path))?,
@@ -124,9 +131,7 @@ pub(super) async fn finish_culling(
                     .await
                     .map_err(|e| e.to_string())?;
 
-                    tokio::fs::rename(img.path, to)
-                        .await
-                        .map_err(|e| e.to_string())
+                    move_file(&img.path, &to).await.map_err(|e| e.to_string())
                 });
             }
             // trash rejected imgs
@@ -146,29 +151,380 @@ pub(super) async fn finish_culling(
         .await
         .map_err(|e| e.to_string())?;
 
-    app_state.dir().lock().await.take();
+    app_state.open_dir().close();
+
+    Ok(())
+}
+
+/// Falls back to copying when the edit dir is on another drive or mount
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+#[tauri::command]
+pub(super) async fn cache_stats(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<CacheStats, String> {
+    let cache = app_state
+        .preview_cache()
+        .ok_or_else(|| "Preview cache is disabled".to_owned())?;
+
+    tokio::task::spawn_blocking(move || cache.stats())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(super) async fn clear_cache(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<CacheStats, String> {
+    let cache = app_state
+        .preview_cache()
+        .ok_or_else(|| "Preview cache is disabled".to_owned())?;
+
+    // previews of the open dir are still in use
+    let keep = app_state.previews().read().await.keys().cloned().collect();
+    tokio::task::spawn_blocking(move || {
+        cache.clear(&keep)?;
+        cache.stats()
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(super) async fn set_focus_index(
+    app_state: tauri::State<'_, AppState>,
+    index: usize,
+) -> Result<(), String> {
+    app_state.preview_queue().set_focus(index);
+    Ok(())
+}
+
+#[tauri::command]
+pub(super) async fn prefetch(
+    app_state: tauri::State<'_, AppState>,
+    indices: Vec<usize>,
+) -> Result<(), String> {
+    app_state.preview_queue().prefetch(&indices);
+    Ok(())
+}
+
+#[tauri::command]
+pub(super) async fn get_preview_progress(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<PreviewProgress, String> {
+    Ok(app_state.preview_progress().snapshot())
+}
+
+/// Generates the failed previews of the img again
+#[tauri::command]
+pub(super) async fn retry_preview(
+    app_state: tauri::State<'_, AppState>,
+    path: PathBuf,
+) -> Result<(), String> {
+    update_cull_metas([(path.clone(), |meta: &mut CullMeta| {
+        meta.preview_error = None;
+    })])
+    .await?;
+    app_state
+        .open_dir()
+        .update(&path, |img| img.preview_error = None);
+
+    let mut failed = Vec::new();
+    for size in app_state.preview_sizes() {
+        if let Some(status) = app_state.previews().read().await.get(&size.path(&path)) {
+            let mut status = status.write().await;
+            if let PreviewStatus::Failed(_) = *status {
+                *status = PreviewStatus::Pending(Arc::default());
+                failed.push(*size);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let batch = app_state
+        .preview_queue()
+        .retry(&path, &failed)
+        .map_err(|e| e.to_string())?;
+    app_state.preview_progress().retried(failed.len());
+
+    // the workers might be idle already, a pending batch processes the retried jobs anyway
+    match app_state.gen_previews_tx().lock().await.try_send(batch) {
+        Ok(()) | Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+pub(super) async fn get_scheduler_config(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<SchedulerConfig, String> {
+    Ok(app_state.scheduler().config())
+}
+
+/// Applies to the running preview gen as well
+#[tauri::command]
+pub(super) async fn set_scheduler_config(
+    app_state: tauri::State<'_, AppState>,
+    config: SchedulerConfig,
+) -> Result<(), String> {
+    app_state.scheduler().set_config(config);
+    Ok(())
+}
+
+/// Rejects the new imgs of the open dir suggested to be rejected by the analysis
+///
+/// Returns the preview paths of the rejected imgs
+#[tauri::command]
+pub(super) async fn apply_reject_suggestions(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<Vec<PathBuf>, String> {
+    reject_new(&app_state, |img| img.suggested_reject).await
+}
+
+/// Rejects the new blank imgs of the open dir
+///
+/// Returns the preview paths of the rejected imgs
+#[tauri::command]
+pub(super) async fn reject_blank_frames(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<Vec<PathBuf>, String> {
+    reject_new(&app_state, |img| img.blank).await
+}
+
+/// Imgs which have been culled already are left as they are
+async fn reject_new(
+    app_state: &AppState,
+    reject: impl Fn(&Image) -> bool,
+) -> Result<Vec<PathBuf>, String> {
+    let (_, imgs) = open_images(app_state)?;
+    let rejected: Vec<PathBuf> = imgs
+        .into_iter()
+        .filter(|img| img.state == CullState::New && reject(img))
+        .map(|img| img.preview_path)
+        .collect();
+
+    cull(app_state, &rejected, &CullState::Rejected).await?;
+
+    Ok(rejected)
+}
+
+/// Groups of near-identical imgs of the open dir in the order they were taken
+///
+/// The threshold overrides the configured max number of differing bits of the hashes
+#[tauri::command]
+pub(super) async fn find_similar(
+    app_state: tauri::State<'_, AppState>,
+    threshold: Option<u32>,
+) -> Result<Vec<Vec<PathBuf>>, String> {
+    let (_, imgs) = open_images(&app_state)?;
+    let threshold = threshold.unwrap_or(app_state.analysis().similarity_threshold);
+    Ok(group_similar(&imgs, threshold)
+        .into_iter()
+        .map(|group| {
+            group
+                .into_iter()
+                .map(|i| imgs[i].preview_path.clone())
+                .collect()
+        })
+        .collect())
+}
+
+/// Proposes the best frame of every stack and group of similar imgs along with the scores
+///
+/// Nothing is culled, the proposal is applied through `cull_images`
+#[tauri::command]
+pub(super) async fn suggest_keepers(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<Vec<GroupRanking>, String> {
+    let (_, imgs) = open_images(&app_state)?;
+    let analysis = app_state.analysis();
+
+    let groups = merge_groups(
+        StackEditor::new(&imgs, app_state.burst())
+            .stack_indices()
+            .into_iter()
+            .chain(group_similar(&imgs, analysis.similarity_threshold)),
+    );
+    Ok(rank_groups(&imgs, &groups, &analysis.ranking))
+}
+
+/// Moves the imgs from their stacks to a new one, a manual one unless the kind is given
+///
+/// Returns the updated stacks of the open dir
+#[tauri::command]
+pub(super) async fn create_stack(
+    app_state: tauri::State<'_, AppState>,
+    images: Vec<PathBuf>,
+    kind: Option<StackKind>,
+) -> Result<Vec<ImageStack>, String> {
+    edit_stacks(&app_state, |stacks| {
+        stacks.create(&images, kind.unwrap_or(StackKind::Manual))
+    })
+    .await
+}
+
+/// Splits the img and the following frames off its stack
+#[tauri::command]
+pub(super) async fn split_stack(
+    app_state: tauri::State<'_, AppState>,
+    image: PathBuf,
+) -> Result<Vec<ImageStack>, String> {
+    edit_stacks(&app_state, |stacks| stacks.split(&image)).await
+}
+
+/// Joins the stacks of the imgs into one
+#[tauri::command]
+pub(super) async fn merge_stacks(
+    app_state: tauri::State<'_, AppState>,
+    images: Vec<PathBuf>,
+) -> Result<Vec<ImageStack>, String> {
+    edit_stacks(&app_state, |stacks| stacks.merge(&images)).await
+}
+
+/// Culls the whole stack of the img at once, an unstacked img is culled alone
+///
+/// Returns the preview paths of the culled imgs
+#[tauri::command]
+pub(super) async fn cull_stack(
+    app_state: tauri::State<'_, AppState>,
+    image: PathBuf,
+    state: CullState,
+) -> Result<Vec<PathBuf>, String> {
+    let (_, imgs) = open_images(&app_state)?;
+    let stack: Vec<PathBuf> = StackEditor::new(&imgs, app_state.burst())
+        .stack_of(&image)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|i| imgs[i].preview_path.clone())
+        .collect();
+
+    cull(&app_state, &stack, &state).await?;
+
+    Ok(stack)
+}
+
+/// Sets the state of the imgs of the open dir
+async fn cull(
+    app_state: &AppState,
+    preview_paths: &[PathBuf],
+    state: &CullState,
+) -> Result<(), String> {
+    for preview_path in preview_paths {
+        app_state
+            .open_dir()
+            .update(preview_path, |img| img.state = state.clone());
+    }
+
+    update_cull_metas(preview_paths.iter().map(|preview_path| {
+        let state = state.clone();
+        (preview_path.clone(), move |meta: &mut CullMeta| {
+            meta.cull_state = state;
+        })
+    }))
+    .await
+}
+
+/// Applies the edit to the stacks of the open dir and persists the stacks of the edited imgs
+async fn edit_stacks(
+    app_state: &AppState,
+    edit: impl FnOnce(&mut StackEditor) -> anyhow::Result<()>,
+) -> Result<Vec<ImageStack>, String> {
+    let (_, imgs) = open_images(app_state)?;
+    let mut stacks = StackEditor::new(&imgs, app_state.burst());
+    edit(&mut stacks).map_err(|e| e.to_string())?;
+
+    let edited = stacks.edited();
+    for (img, stack) in &edited {
+        app_state
+            .open_dir()
+            .update(&img.preview_path, |img| img.stack = Some(stack.clone()));
+    }
+
+    update_cull_metas(edited.into_iter().map(|(img, stack)| {
+        (img.preview_path.clone(), move |meta: &mut CullMeta| {
+            meta.stack = Some(stack);
+        })
+    }))
+    .await?;
+
+    Ok(stacks.stacks())
+}
+
+/// Writes the cull meta of the imgs in parallel
+async fn update_cull_metas<F>(updates: impl IntoIterator<Item = (PathBuf, F)>) -> Result<(), String>
+where
+    F: FnOnce(&mut CullMeta) + Send + 'static,
+{
+    let mut task_set = tokio::task::JoinSet::new();
+    for (preview_path, update) in updates {
+        task_set.spawn_blocking(move || {
+            update_cull_meta(&preview_path.with_extension(META_EXT), update)
+        });
+    }
+
+    while let Some(res) = task_set.join_next().await {
+        res.map_err(|e| e.to_string())?.map_err(|e| e.to_string())?;
+    }
 
     Ok(())
 }
 
+/// Imgs of the open dir in the capture order
+///
+/// The analysis might have finished after the dir has been opened,
+/// so the suggestions and the sequences depending on it are derived again
+fn open_images(app_state: &AppState) -> Result<(PathBuf, Vec<Image>), String> {
+    let (path, mut imgs) = app_state
+        .open_dir()
+        .get()
+        .ok_or_else(|| "Dir not selected".to_owned())?;
+
+    let analysis = app_state.analysis();
+    for img in &mut imgs {
+        img.suggested_reject = analysis.suggests_reject(img.analysis.as_ref());
+        img.blank = analysis.is_blank(img.analysis.as_ref());
+    }
+    tag_sequences(
+        &mut imgs,
+        app_state.sequence(),
+        analysis.similarity_threshold,
+    );
+
+    Ok((path, imgs))
+}
+
 // the cmd has to be is async to start on a different thread
 // blocking file dalog would otherwise block main thread
 #[tauri::command]
 pub(super) async fn open_dir_picker(
     window: tauri::Window,
     app_state: tauri::State<'_, AppState>,
+    sort: Option<ImageSort>,
 ) -> Result<ImageDir, String> {
-    let dir = tauri::api::dialog::blocking::FileDialogBuilder::default()
+    let mut dialog = tauri::api::dialog::blocking::FileDialogBuilder::default()
         .set_title("Select culled folder")
         // set the parent to force focus on the dialog
         // this will block interaction with the app until the dialog is closed
-        .set_parent(&window)
-        // todo: conf
-        .set_directory("D:\\Photos\\Culling")
-        .pick_folder();
+        .set_parent(&window);
+    if let Some(culling_dir) = app_state.dirs().culling() {
+        dialog = dialog.set_directory(culling_dir);
+    }
+    let dir = dialog.pick_folder();
 
     match dir {
-        Some(p) => open_img_dir(p, app_state).await,
+        Some(p) => open_img_dir(p, sort.unwrap_or_default(), app_state).await,
         None => Err("Dialog was closed".to_owned()),
     }
 }
@@ -177,6 +533,7 @@ pub(super) async fn open_dir_picker(
 pub(super) async fn open_dir(
     app_state: tauri::State<'_, AppState>,
     path: String,
+    sort: Option<ImageSort>,
 ) -> Result<ImageDir, String> {
     let path: PathBuf = path.into();
     if !path.is_dir() {
@@ -184,7 +541,7 @@ pub(super) async fn open_dir(
     }
 
     match path.try_exists() {
-        Ok(true) => open_img_dir(path, app_state).await,
+        Ok(true) => open_img_dir(path, sort.unwrap_or_default(), app_state).await,
         Ok(false) => Err(format!("Path '{path:?}' does not exist")),
         Err(e) => Err(e.to_string()),
     }
@@ -192,45 +549,71 @@ pub(super) async fn open_dir(
 
 async fn open_img_dir(
     path: PathBuf,
+    sort: ImageSort,
     app_state: tauri::State<'_, AppState>,
 ) -> Result<ImageDir, String> {
-    let mut images = get_images(&path).await.map_err(|e| e.to_string())?;
+    let mut images = get_images(
+        &path,
+        app_state.preview_extensions(),
+        app_state.preview_cache().as_deref(),
+        app_state.analysis(),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
 
     if images.is_empty() {
         return Err("No images".to_owned());
     }
 
-    // set dir
-    *app_state.dir().lock().await = Some(path.clone());
+    // set dir, the imgs are kept in the capture order
+    sort_by_capture(&mut images);
+    tag_sequences(
+        &mut images,
+        app_state.sequence(),
+        app_state.analysis().similarity_threshold,
+    );
+    app_state.open_dir().open(path.clone(), images.clone());
+
+    // stacks are detected in the capture order
+    let stacks = group_stacks(&images, app_state.burst());
+    if sort == ImageSort::Quality {
+        let stacked = StackEditor::new(&images, app_state.burst()).stack_indices();
+        sort_by_quality(&mut images, &stacked);
+    }
 
-    // sort by creation
-    images.sort_by(|a, b| a.created.cmp(&b.created));
+    // abort the gen of the previous dir
+    let batch = app_state
+        .preview_queue()
+        .start_batch(path.clone(), images.clone());
+    release_waiters(app_state.previews()).await;
 
     // reset current previews
-    let mut previews = app_state.previews().write().await;
-    previews.clear();
-    previews.extend(images.iter().map(|img| {
-        (
-            img.preview_path.clone(),
-            RwLock::new(if img.preview_path.exists() {
-                None
-            } else {
-                Some(tokio::sync::Notify::new())
-            }),
-        )
-    }));
+    {
+        let mut previews = app_state.previews().write().await;
+        previews.clear();
+        previews.extend(images.iter().flat_map(|img| {
+            app_state.preview_sizes().iter().map(|size| {
+                let path = size.path(&img.preview_path);
+                let status = PreviewStatus::new(&path, img.preview_error.as_deref());
+
+                (path, RwLock::new(status))
+            })
+        }));
+    }
 
     // start gen
     app_state
         .gen_previews_tx()
         .lock()
         .await
-        .send(images.clone().into())
+        .send(batch)
         .await
         .map_err(|e| e.to_string())?;
 
     Ok(ImageDir {
         images,
+        stacks,
+        sort,
         dir_name: path
             .file_name()
             .expect("Path is valid")
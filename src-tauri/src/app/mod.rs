@@ -2,7 +2,15 @@
 use tauri::LogicalSize;
 use tauri::Manager;
 
-use crate::{image::process_previews, preview_api};
+use crate::{
+    config::Config,
+    image::{
+        process_previews, throttle_events, PreviewBatch, PreviewCache, PreviewEvent,
+        PreviewGenerators, PreviewPipeline, PreviewProgressReporter, Scheduler, TileRenderer,
+        PREVIEW_PROGRESS_EVENT, PROGRESS_INTERVAL,
+    },
+    preview_api,
+};
 use std::{collections::HashMap, sync::Arc};
 
 mod commands;
@@ -10,7 +18,6 @@ mod state;
 
 pub(crate) fn run_app() -> tauri::Result<()> {
     let (preview_processing_tx, preview_processing_rx) = tokio::sync::mpsc::channel(1);
-    let previews = Arc::new(tokio::sync::RwLock::new(HashMap::with_capacity(500)));
 
     tauri::Builder::default()
         .plugin(tauri_plugin_store::Builder::default().build())
@@ -20,6 +27,22 @@ pub(crate) fn run_app() -> tauri::Result<()> {
             commands::open_dir_picker,
             commands::cull_images,
             commands::finish_culling,
+            commands::cache_stats,
+            commands::clear_cache,
+            commands::set_focus_index,
+            commands::prefetch,
+            commands::get_preview_progress,
+            commands::retry_preview,
+            commands::get_scheduler_config,
+            commands::set_scheduler_config,
+            commands::apply_reject_suggestions,
+            commands::reject_blank_frames,
+            commands::find_similar,
+            commands::suggest_keepers,
+            commands::create_stack,
+            commands::split_stack,
+            commands::merge_stacks,
+            commands::cull_stack,
         ])
         .setup(|app| {
             #[cfg(debug_assertions)]
@@ -32,25 +55,48 @@ pub(crate) fn run_app() -> tauri::Result<()> {
                 }
             }
 
+            let config = Config::load(app.path_resolver().app_config_dir());
+            config.preview.clipping.clone().apply();
+            let generators = Arc::new(PreviewGenerators::new(&config.preview));
+            let scheduler = Arc::new(Scheduler::new(config.scheduler.clone()));
+
+            // preview progress
+            let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+            spawn_progress_events(app.handle(), progress_rx);
+
+            let pipeline = PreviewPipeline {
+                previews: Arc::new(tokio::sync::RwLock::new(HashMap::with_capacity(500))),
+                open_dir: Arc::default(),
+                cache: PreviewCache::new(
+                    &config.cache,
+                    app.path_resolver()
+                        .app_cache_dir()
+                        .map(|dir| dir.join("previews")),
+                )
+                .map(Arc::new),
+                queue: Arc::default(),
+                progress: Arc::new(PreviewProgressReporter::new(progress_tx)),
+                tiles: Arc::new(TileRenderer::new(&generators, &scheduler)),
+                generators,
+                scheduler,
+            };
+
             // preview processing
-            // use a channel to report processing progress?
-            let p = Arc::clone(&previews);
-            tauri::async_runtime::spawn(
-                async move { process_previews(preview_processing_rx, p).await },
-            );
+            spawn_preview_processing(preview_processing_rx, pipeline.clone())?;
 
             // preview API
             let app_handle = app.handle();
             tauri::async_runtime::spawn(async move {
                 let (address, preview_server) =
-                    preview_api::get_preview_api_server(Arc::clone(&previews)).await;
+                    preview_api::get_preview_api_server(&pipeline).await;
 
                 println!("Preview API: http://localhost:{}", address.port());
 
                 app_handle.manage(state::AppState::new(
-                    Arc::clone(&previews),
+                    pipeline,
                     preview_processing_tx,
                     address.to_string(),
+                    config,
                 ));
 
                 preview_server.await?;
@@ -61,3 +107,35 @@ pub(crate) fn run_app() -> tauri::Result<()> {
         })
         .run(tauri::generate_context!())
 }
+
+/// Forwards the preview progress to the frontend
+fn spawn_progress_events(
+    app_handle: tauri::AppHandle,
+    progress_rx: tokio::sync::mpsc::UnboundedReceiver<PreviewEvent>,
+) {
+    tauri::async_runtime::spawn(throttle_events(
+        progress_rx,
+        PROGRESS_INTERVAL,
+        move |event| {
+            if let Err(e) = app_handle.emit_all(PREVIEW_PROGRESS_EVENT, event) {
+                println!("Failed to emit preview progress: {e}");
+            }
+        },
+    ));
+}
+
+/// The workers block on the scheduler and the backends, so they get a thread of their own
+fn spawn_preview_processing(
+    input_rx: tokio::sync::mpsc::Receiver<PreviewBatch>,
+    pipeline: PreviewPipeline,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name("preview-processing".to_owned())
+        .spawn(move || {
+            if let Err(e) = process_previews(input_rx, &pipeline) {
+                println!("Preview processing has stopped: {e:#}");
+            }
+        })?;
+
+    Ok(())
+}
@@ -1,35 +1,58 @@
-use std::{collections::VecDeque, path::PathBuf};
+use std::sync::Arc;
 
-use crate::image::{Image, PreviewMap};
+use crate::config::{Config, DirsConfig};
+use crate::image::{
+    AnalysisConfig, BurstConfig, OpenDir, PreviewBackendInfo, PreviewBatch, PreviewCache,
+    PreviewMap, PreviewPipeline, PreviewProgressReporter, PreviewQueue, PreviewSize, Scheduler,
+    SequenceConfig,
+};
 
 pub(super) struct AppState {
-    previews: PreviewMap,
-    dir: tokio::sync::Mutex<Option<PathBuf>>,
-    gen_previews_tx: tokio::sync::Mutex<tokio::sync::mpsc::Sender<VecDeque<Image>>>,
+    pipeline: PreviewPipeline,
+    gen_previews_tx: tokio::sync::Mutex<tokio::sync::mpsc::Sender<PreviewBatch>>,
     preview_api_url: String,
+    preview_backends: Vec<PreviewBackendInfo>,
+    /// Listed besides the raws
+    preview_extensions: Vec<String>,
+    preview_sizes: Vec<PreviewSize>,
+    dirs: DirsConfig,
+    analysis: AnalysisConfig,
+    burst: BurstConfig,
+    sequence: SequenceConfig,
 }
 
 impl AppState {
     pub fn new(
-        previews: PreviewMap,
-        gen_previews_tx: tokio::sync::mpsc::Sender<VecDeque<Image>>,
+        pipeline: PreviewPipeline,
+        gen_previews_tx: tokio::sync::mpsc::Sender<PreviewBatch>,
         preview_api_url: String,
+        config: Config,
     ) -> Self {
         Self {
-            dir: tokio::sync::Mutex::new(None),
-            previews,
             gen_previews_tx: tokio::sync::Mutex::new(gen_previews_tx),
             preview_api_url,
+            preview_backends: pipeline.generators.info(),
+            preview_extensions: pipeline.generators.extensions(),
+            preview_sizes: config.preview.sizes(),
+            pipeline,
+            dirs: config.dirs,
+            analysis: config.analysis,
+            burst: config.burst,
+            sequence: config.sequence,
         }
     }
 
     pub(super) fn previews(&self) -> &PreviewMap {
-        &self.previews
+        &self.pipeline.previews
+    }
+
+    pub(super) fn open_dir(&self) -> &OpenDir {
+        &self.pipeline.open_dir
     }
 
     pub(super) fn gen_previews_tx(
         &self,
-    ) -> &tokio::sync::Mutex<tokio::sync::mpsc::Sender<VecDeque<Image>>> {
+    ) -> &tokio::sync::Mutex<tokio::sync::mpsc::Sender<PreviewBatch>> {
         &self.gen_previews_tx
     }
 
@@ -37,7 +60,47 @@ impl AppState {
         &self.preview_api_url
     }
 
-    pub(super) fn dir(&self) -> &tokio::sync::Mutex<Option<PathBuf>> {
-        &self.dir
+    pub(super) fn preview_backends(&self) -> &[PreviewBackendInfo] {
+        &self.preview_backends
+    }
+
+    pub(super) fn preview_extensions(&self) -> &[String] {
+        &self.preview_extensions
+    }
+
+    pub(super) fn preview_sizes(&self) -> &[PreviewSize] {
+        &self.preview_sizes
+    }
+
+    pub(super) fn preview_cache(&self) -> Option<Arc<PreviewCache>> {
+        self.pipeline.cache.clone()
+    }
+
+    pub(super) fn preview_queue(&self) -> &PreviewQueue {
+        &self.pipeline.queue
+    }
+
+    pub(super) fn preview_progress(&self) -> &PreviewProgressReporter {
+        &self.pipeline.progress
+    }
+
+    pub(super) fn scheduler(&self) -> &Scheduler {
+        &self.pipeline.scheduler
+    }
+
+    pub(super) fn dirs(&self) -> &DirsConfig {
+        &self.dirs
+    }
+
+    pub(super) fn analysis(&self) -> &AnalysisConfig {
+        &self.analysis
+    }
+
+    pub(super) fn burst(&self) -> &BurstConfig {
+        &self.burst
+    }
+
+    pub(super) fn sequence(&self) -> &SequenceConfig {
+        &self.sequence
     }
 }
@@ -0,0 +1,59 @@
+use crate::image::{
+    AnalysisConfig, BurstConfig, CacheConfig, PreviewConfig, SchedulerConfig, SequenceConfig,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.json";
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct Config {
+    pub(crate) dirs: DirsConfig,
+    pub(crate) preview: PreviewConfig,
+    pub(crate) cache: CacheConfig,
+    pub(crate) scheduler: SchedulerConfig,
+    pub(crate) analysis: AnalysisConfig,
+    pub(crate) burst: BurstConfig,
+    pub(crate) sequence: SequenceConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct DirsConfig {
+    /// Where the dir picker starts, defaults to the pictures dir
+    culling: Option<PathBuf>,
+    /// Selected imgs are moved to `<edit>/<year>/Q<quarter>/<dir name>`,
+    /// frames of a sequence to a subfolder of it,
+    /// defaults to `Edit` in the pictures dir
+    edit: Option<PathBuf>,
+}
+
+impl DirsConfig {
+    pub(crate) fn culling(&self) -> Option<PathBuf> {
+        self.culling.clone().or_else(tauri::api::path::picture_dir)
+    }
+
+    pub(crate) fn edit(&self) -> Option<PathBuf> {
+        self.edit
+            .clone()
+            .or_else(|| tauri::api::path::picture_dir().map(|dir| dir.join("Edit")))
+    }
+}
+
+impl Config {
+    /// Reads the config from the app config dir or falls back to the defaults
+    pub(crate) fn load(dir: Option<PathBuf>) -> Self {
+        let Some(path) = dir.map(|dir| dir.join(CONFIG_FILE)) else {
+            return Self::default();
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!("Invalid config {path:?}: {e}");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+}
@@ -0,0 +1,651 @@
+use anyhow::Context;
+use image::{imageops::FilterType, GrayImage, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+use ts_rs::TS;
+
+use super::{
+    cull_meta::{read_cull_meta, update_cull_meta},
+    quality::quality_score,
+    ranking::RankingConfig,
+    Image, META_EXT,
+};
+
+/// Bumped whenever a metric is added or changed, so the stored analysis is recomputed
+const ANALYSIS_VERSION: u32 = 7;
+/// The sharpness is measured in every cell of the grid, the sharpest one being the focus region
+const SHARPNESS_GRID: u32 = 8;
+/// Channel values at the ends of the range, with a bit of slack for the rounding of the encoder
+const HIGHLIGHT_CLIP: u8 = 254;
+const SHADOW_CLIP: u8 = 1;
+/// The hash compares the neighbouring pixels of the preview shrunk to a grid of this size
+const HASH_SIZE: u32 = 8;
+/// Steps of the directions the edges are compared in, along the axes and the diagonals
+const BLUR_DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+/// Preview px the edges are measured over, longer smears count as fully blurred
+const BLUR_DISTANCE: i32 = 4;
+/// Luma differences over the distance lower than this are gradients or noise rather than edges
+const BLUR_EDGE: f64 = 16.;
+/// Directions with fewer edges than this share of the direction with the most are not judged
+const MIN_EDGE_SHARE: f64 = 0.02;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct AnalysisConfig {
+    /// Imgs less sharp than this are suggested to be rejected
+    pub(crate) blur_threshold: f32,
+    /// Imgs whose luma deviates less than this are blank, eg. lens cap shots
+    pub(crate) blank_threshold: f32,
+    /// Max number of differing bits of the hashes of similar imgs
+    pub(crate) similarity_threshold: u32,
+    /// How the frames of a burst or a similarity group are ranked
+    pub(crate) ranking: RankingConfig,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            blur_threshold: 100.,
+            blank_threshold: 5.,
+            similarity_threshold: 10,
+            ranking: RankingConfig::default(),
+        }
+    }
+}
+
+impl AnalysisConfig {
+    pub(crate) fn suggests_reject(&self, analysis: Option<&ImageAnalysis>) -> bool {
+        analysis.is_some_and(|analysis| analysis.sharpness < self.blur_threshold)
+    }
+
+    pub(crate) fn is_blank(&self, analysis: Option<&ImageAnalysis>) -> bool {
+        analysis.is_some_and(|analysis| analysis.luma_deviation < self.blank_threshold)
+    }
+}
+
+/// Metrics computed from the preview of an img
+#[derive(Clone, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct ImageAnalysis {
+    /// Variance of the Laplacian in the sharpest region
+    pub(crate) sharpness: f32,
+    /// Standard deviation of the luma in the sharpest region, which is likely the subject
+    pub(crate) subject_contrast: f32,
+    /// % of pixels with a channel blown out
+    pub(crate) clipped_highlights: f32,
+    /// % of black pixels
+    pub(crate) crushed_shadows: f32,
+    /// Standard deviation of the luma of the whole preview, nearly uniform frames are blank
+    pub(crate) luma_deviation: f32,
+    /// Estimated standard deviation of the noise in 8bit levels
+    pub(crate) noise: f32,
+    /// How much more the edges are smeared in one direction than in another from 0 to 1
+    pub(crate) motion_blur: f32,
+    /// Technical quality from 0 to 1 combining the sharpness, noise, clipping and motion blur
+    pub(crate) quality: f32,
+    /// Difference hash of the preview, near-identical imgs differ in a few bits
+    // not needed by the UI and it doesn't fit a JS number anyway
+    #[ts(skip)]
+    pub(crate) dhash: u64,
+    #[ts(skip)]
+    version: u32,
+}
+
+/// Pixel counts of every channel value, stored in the cull meta
+#[derive(Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub(crate) struct Histogram {
+    red: Vec<u32>,
+    green: Vec<u32>,
+    blue: Vec<u32>,
+    luma: Vec<u32>,
+}
+
+impl Histogram {
+    fn new(rgb: &RgbImage, luma: &GrayImage) -> Self {
+        let mut histogram = Self {
+            red: vec![0; 256],
+            green: vec![0; 256],
+            blue: vec![0; 256],
+            luma: vec![0; 256],
+        };
+        for image::Rgb([r, g, b]) in rgb.pixels() {
+            histogram.red[usize::from(*r)] += 1;
+            histogram.green[usize::from(*g)] += 1;
+            histogram.blue[usize::from(*b)] += 1;
+        }
+        for image::Luma([l]) in luma.pixels() {
+            histogram.luma[usize::from(*l)] += 1;
+        }
+
+        histogram
+    }
+}
+
+/// Histogram of the preview along with its clipping
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct Exposure {
+    histogram: Histogram,
+    clipped_highlights: f32,
+    crushed_shadows: f32,
+}
+
+impl ImageAnalysis {
+    pub(crate) fn is_current(&self) -> bool {
+        self.version == ANALYSIS_VERSION
+    }
+
+    /// Current analysis with every metric zeroed
+    #[cfg(test)]
+    pub(super) fn zeroed() -> Self {
+        Self {
+            sharpness: 0.,
+            subject_contrast: 0.,
+            clipped_highlights: 0.,
+            crushed_shadows: 0.,
+            luma_deviation: 0.,
+            noise: 0.,
+            motion_blur: 0.,
+            quality: 0.,
+            dhash: 0,
+            version: ANALYSIS_VERSION,
+        }
+    }
+}
+
+/// Analyzes the generated preview and stores the metrics in the cull meta
+///
+/// Imgs with a current analysis are skipped
+pub(crate) fn analyze_preview(img: &Image) -> anyhow::Result<ImageAnalysis> {
+    analyze(&img.preview_path).map(|(analysis, _)| analysis)
+}
+
+/// Analyzes the preview unless it's been done already
+pub(crate) fn exposure(preview_path: &Path) -> anyhow::Result<Exposure> {
+    let (analysis, histogram) = analyze(preview_path)?;
+
+    Ok(Exposure {
+        histogram,
+        clipped_highlights: analysis.clipped_highlights,
+        crushed_shadows: analysis.crushed_shadows,
+    })
+}
+
+fn analyze(preview_path: &Path) -> anyhow::Result<(ImageAnalysis, Histogram)> {
+    let meta_path = preview_path.with_extension(META_EXT);
+    let meta = read_cull_meta(&meta_path);
+    if let (Some(analysis), Some(histogram)) = (meta.analysis, meta.histogram) {
+        if analysis.is_current() {
+            return Ok((analysis, histogram));
+        }
+    }
+
+    let rgb = image::open(preview_path)
+        .context(format!("Failed to open preview {preview_path:?}"))?
+        .to_rgb8();
+    let luma = image::imageops::grayscale(&rgb);
+    let (clipped_highlights, crushed_shadows) = clipping(&rgb);
+    let focus = focus_region(&luma);
+    let mut analysis = ImageAnalysis {
+        sharpness: focus.sharpness,
+        subject_contrast: focus.contrast,
+        clipped_highlights,
+        crushed_shadows,
+        luma_deviation: luma_deviation(&luma),
+        noise: noise(&luma),
+        motion_blur: motion_blur(&luma),
+        quality: 0.,
+        dhash: dhash(&luma),
+        version: ANALYSIS_VERSION,
+    };
+    analysis.quality = quality_score(&analysis);
+    let histogram = Histogram::new(&rgb, &luma);
+
+    update_cull_meta(&meta_path, |meta| {
+        meta.analysis = Some(analysis.clone());
+        meta.histogram = Some(histogram.clone());
+    })?;
+
+    Ok((analysis, histogram))
+}
+
+/// Indices of the imgs grouped by similarity, imgs without a current analysis are left out
+///
+/// Similarity is transitive, so a group holds a whole sequence of slightly changing frames
+pub(crate) fn group_similar(imgs: &[Image], threshold: u32) -> Vec<Vec<usize>> {
+    let hashes: Vec<_> = imgs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, img)| {
+            img.analysis
+                .as_ref()
+                .filter(|analysis| analysis.is_current())
+                .map(|analysis| (i, analysis.dhash))
+        })
+        .collect();
+
+    // union-find with the lowest index as the root of a group
+    let mut roots: Vec<usize> = (0..imgs.len()).collect();
+
+    for (pos, (i, hash)) in hashes.iter().enumerate() {
+        for (j, other) in &hashes[pos + 1..] {
+            if (hash ^ other).count_ones() <= threshold {
+                let (root_i, root_j) = (root(&mut roots, *i), root(&mut roots, *j));
+                roots[root_i.max(root_j)] = root_i.min(root_j);
+            }
+        }
+    }
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, _) in &hashes {
+        groups.entry(root(&mut roots, *i)).or_default().push(*i);
+    }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// Root of the group of the img, flattening the path to it on the way
+fn root(roots: &mut [usize], mut i: usize) -> usize {
+    while roots[i] != i {
+        roots[i] = roots[roots[i]];
+        i = roots[i];
+    }
+    i
+}
+
+/// Every bit says whether a pixel of the shrunk preview is darker than its right neighbour
+fn dhash(luma: &GrayImage) -> u64 {
+    let small = image::imageops::resize(luma, HASH_SIZE + 1, HASH_SIZE, FilterType::Triangle);
+
+    let mut hash = 0;
+    for y in 0..HASH_SIZE {
+        for x in 0..HASH_SIZE {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// % of the pixels with clipped highlights and crushed shadows
+#[allow(clippy::cast_precision_loss)]
+fn clipping(rgb: &RgbImage) -> (f32, f32) {
+    let (mut clipped, mut crushed) = (0_u64, 0_u64);
+    for image::Rgb(channels) in rgb.pixels() {
+        if channels.iter().any(|c| *c >= HIGHLIGHT_CLIP) {
+            clipped += 1;
+        }
+        if channels.iter().all(|c| *c <= SHADOW_CLIP) {
+            crushed += 1;
+        }
+    }
+
+    let total = rgb.pixels().len().max(1) as f32;
+    (clipped as f32 / total * 100., crushed as f32 / total * 100.)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn luma_deviation(luma: &GrayImage) -> f32 {
+    let (mut sum, mut sum_sq) = (0_u64, 0_u64);
+    for image::Luma([l]) in luma.pixels() {
+        sum += u64::from(*l);
+        sum_sq += u64::from(*l).pow(2);
+    }
+
+    let count = luma.pixels().len().max(1) as f64;
+    let mean = sum as f64 / count;
+    (sum_sq as f64 / count - mean * mean).max(0.).sqrt() as f32
+}
+
+/// Fast noise variance estimation by Immerkær, the mask cancels out the structure of the img
+#[allow(clippy::cast_possible_truncation)]
+fn noise(luma: &GrayImage) -> f32 {
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.;
+    }
+
+    let pixel = |x: u32, y: u32| f64::from(luma.get_pixel(x, y).0[0]);
+    let mut sum = 0.;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let corners = pixel(x - 1, y - 1)
+                + pixel(x + 1, y - 1)
+                + pixel(x - 1, y + 1)
+                + pixel(x + 1, y + 1);
+            let sides = pixel(x, y - 1) + pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y + 1);
+            sum += (corners - 2. * sides + 4. * pixel(x, y)).abs();
+        }
+    }
+
+    let count = f64::from(width - 2) * f64::from(height - 2);
+    (sum * std::f64::consts::FRAC_PI_2.sqrt() / (6. * count)) as f32
+}
+
+/// Difference between the widths the edges are smeared over in the different directions
+///
+/// The widths are relative to the edges of the direction, so neither strongly oriented scenes
+/// nor defocus, which smears all the directions alike, count as motion blur
+#[allow(clippy::cast_possible_truncation)]
+fn motion_blur(luma: &GrayImage) -> f32 {
+    let (width, height) = luma.dimensions();
+    let margin = BLUR_DISTANCE.unsigned_abs();
+    if width <= 2 * margin || height <= 2 * margin {
+        return 0.;
+    }
+
+    let pixel = |x: u32, y: u32| f64::from(luma.get_pixel(x, y).0[0]);
+    // squared differences of the neighbours and of the px the distance apart across the edges
+    let energies: Vec<(f64, f64)> = BLUR_DIRECTIONS
+        .iter()
+        .map(|&(dx, dy)| {
+            let (mut near, mut far) = (0., 0.);
+            // the margin keeps the steps within the img
+            for y in margin..height - margin {
+                for x in margin..width - margin {
+                    let step = |n: i32| {
+                        pixel(x.wrapping_add_signed(dx * n), y.wrapping_add_signed(dy * n))
+                            - pixel(x, y)
+                    };
+                    let across = step(BLUR_DISTANCE);
+                    if across.abs() >= BLUR_EDGE {
+                        near += step(1).powi(2);
+                        far += across.powi(2);
+                    }
+                }
+            }
+            (near, far)
+        })
+        .collect();
+
+    // a sharp edge changes within a px, so the far differences add up to the distance times
+    // the near ones, while an edge smeared over the distance adds up to its square
+    let most = energies.iter().map(|(_, far)| *far).fold(0., f64::max);
+    let distance = f64::from(BLUR_DISTANCE);
+    let widths = energies
+        .into_iter()
+        .filter(|(near, far)| *near > 0. && *far > most * MIN_EDGE_SHARE)
+        .map(|(near, far)| ((far / near / distance - 1.) / (distance - 1.)).clamp(0., 1.));
+    let (narrowest, widest) = widths.fold((1., 0.), |(min, max): (f64, f64), width| {
+        (min.min(width), max.max(width))
+    });
+    (widest - narrowest).max(0.) as f32
+}
+
+/// Grid cell with the highest variance of the Laplacian
+#[derive(Default)]
+struct FocusRegion {
+    sharpness: f32,
+    contrast: f32,
+}
+
+/// The sharpest of the grid cells, so a sharp subject isn't outweighed by a blurred background
+#[allow(clippy::cast_possible_truncation)]
+fn focus_region(luma: &GrayImage) -> FocusRegion {
+    let (width, height) = luma.dimensions();
+    let cell_width = width / SHARPNESS_GRID;
+    let cell_height = height / SHARPNESS_GRID;
+    if cell_width < 3 || cell_height < 3 {
+        return FocusRegion::default();
+    }
+
+    let pixel = |x: u32, y: u32| f64::from(luma.get_pixel(x, y).0[0]);
+    let variance = |sum: f64, sum_sq: f64, count: f64| sum_sq / count - (sum / count).powi(2);
+    let (mut sharpest, mut contrast): (f64, f64) = (0., 0.);
+    for cell_y in 0..SHARPNESS_GRID {
+        for cell_x in 0..SHARPNESS_GRID {
+            let (left, top) = (cell_x * cell_width, cell_y * cell_height);
+            // the Laplacian needs the neighbours, which are missing at the img edges
+            let xs = left.max(1)..(left + cell_width).min(width - 1);
+            let ys = top.max(1)..(top + cell_height).min(height - 1);
+
+            let (mut sum, mut sum_sq, mut count) = (0., 0., 0.);
+            let (mut luma_sum, mut luma_sum_sq) = (0., 0.);
+            for y in ys {
+                for x in xs.clone() {
+                    let laplacian =
+                        pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1)
+                            - 4. * pixel(x, y);
+                    sum += laplacian;
+                    sum_sq += laplacian * laplacian;
+                    luma_sum += pixel(x, y);
+                    luma_sum_sq += pixel(x, y).powi(2);
+                    count += 1.;
+                }
+            }
+
+            if count > 0. && variance(sum, sum_sq, count) > sharpest {
+                sharpest = variance(sum, sum_sq, count);
+                contrast = variance(luma_sum, luma_sum_sq, count).max(0.).sqrt();
+            }
+        }
+    }
+
+    FocusRegion {
+        sharpness: sharpest as f32,
+        contrast: contrast as f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::analyzed;
+
+    fn hashed(hashes: &[u64]) -> Vec<Image> {
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| analyzed(&i.to_string(), |analysis| analysis.dhash = *hash))
+            .collect()
+    }
+
+    /// Horizontal waves, shifted by the phase
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn waves(period: f32, phase: f32, gain: f32) -> GrayImage {
+        GrayImage::from_fn(600, 400, |x, y| {
+            let wave = ((x as f32 + phase) / period).sin() + (y as f32 / period / 2.).cos();
+            image::Luma([(128. + 50. * wave + gain).clamp(0., 255.) as u8])
+        })
+    }
+
+    /// Sharp edges 32px apart, vertical ones only unless checkered
+    fn stripes(checkered: bool) -> GrayImage {
+        GrayImage::from_fn(600, 400, |x, y| {
+            let row = if checkered { y / 32 } else { 0 };
+            image::Luma([if (x / 32 + row) % 2 == 0 { 60 } else { 190 }])
+        })
+    }
+
+    /// Smeared horizontally over the given px, as by a pan
+    #[allow(clippy::cast_possible_truncation)]
+    fn box_blur(luma: &GrayImage, len: u32) -> GrayImage {
+        let (width, height) = luma.dimensions();
+        GrayImage::from_fn(width, height, |x, y| {
+            let xs = x.saturating_sub(len / 2)..(x + len / 2 + 1).min(width);
+            let sum: u32 = xs
+                .clone()
+                .map(|x| u32::from(luma.get_pixel(x, y).0[0]))
+                .sum();
+            image::Luma([(sum / xs.len() as u32) as u8])
+        })
+    }
+
+    #[test]
+    fn transitive_groups() {
+        // 3 bits between the neighbours, 6 between the ends
+        let imgs = hashed(&[0b0, 0b111, 0b111_111, u64::MAX, u64::MAX - 1]);
+        assert_eq!(group_similar(&imgs, 3), [vec![0, 1, 2], vec![3, 4]]);
+        assert_eq!(group_similar(&imgs, 0), Vec::<Vec<usize>>::new());
+    }
+
+    #[test]
+    fn unanalyzed_left_out() {
+        let mut imgs = hashed(&[0, 0, 0, 0]);
+        imgs[1].analysis = None;
+        imgs[2].analysis.as_mut().unwrap().version = ANALYSIS_VERSION - 1;
+        assert_eq!(group_similar(&imgs, 0), [vec![0, 3]]);
+        assert!(group_similar(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn similar_hashes() {
+        let config = AnalysisConfig::default();
+        let hash = dhash(&waves(40., 0., 0.));
+        // a slight pan and exposure change keep the gradients
+        let moved = dhash(&waves(40., 3., 10.));
+        assert!((hash ^ moved).count_ones() <= config.similarity_threshold);
+        let other = dhash(&waves(90., 50., 0.));
+        assert!((hash ^ other).count_ones() > config.similarity_threshold);
+    }
+
+    #[test]
+    fn motion_blurred() {
+        // oriented scenes are as sharp in every direction they have edges in
+        assert!(motion_blur(&stripes(false)) < 0.05);
+        assert!(motion_blur(&stripes(true)) < 0.05);
+        assert!(motion_blur(&waves(40., 0., 0.)) < 0.05);
+
+        // the longer the pan, the more the edges across it are smeared
+        let short = motion_blur(&box_blur(&stripes(true), 3));
+        let long = motion_blur(&box_blur(&stripes(true), 9));
+        assert!(short > 0.3);
+        assert!(long > short);
+
+        // defocus smears every direction alike
+        assert!(motion_blur(&image::imageops::blur(&stripes(true), 3.)) < 0.1);
+    }
+
+    #[test]
+    fn sharpest_region() {
+        let config = AnalysisConfig::default();
+        let soft = image::imageops::blur(&stripes(true), 3.);
+        let soft_region = focus_region(&soft);
+        assert!(soft_region.sharpness < config.blur_threshold);
+
+        // a small sharp subject in a single grid cell of the soft frame
+        let sharp = stripes(true);
+        let mut subject = soft.clone();
+        for (x, y, pixel) in subject.enumerate_pixels_mut() {
+            if (225..300).contains(&x) && (150..200).contains(&y) {
+                *pixel = *sharp.get_pixel(x, y);
+            }
+        }
+        let subject_region = focus_region(&subject);
+        assert!(subject_region.sharpness > config.blur_threshold);
+        assert!(subject_region.contrast > soft_region.contrast);
+
+        // too small to be split into cells
+        assert!(focus_region(&GrayImage::new(16, 16)).sharpness.abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn blur_threshold() {
+        let config = AnalysisConfig::default();
+        let sharpness = |sharpness| {
+            let mut analysis = ImageAnalysis::zeroed();
+            analysis.sharpness = sharpness;
+            config.suggests_reject(Some(&analysis))
+        };
+
+        assert!(sharpness(99.9));
+        // at the threshold it's sharp enough
+        assert!(!sharpness(100.));
+        assert!(!sharpness(100.1));
+        // not analyzed yet
+        assert!(!config.suggests_reject(None));
+    }
+
+    /// 100x100 px with the given numbers of rows of each colour from the top, the rest mid grey
+    fn rows(colors: &[(u32, [u8; 3])]) -> RgbImage {
+        RgbImage::from_fn(100, 100, |_, y| {
+            let mut start = 0;
+            for (count, color) in colors {
+                if (start..start + count).contains(&y) {
+                    return image::Rgb(*color);
+                }
+                start += count;
+            }
+            image::Rgb([128; 3])
+        })
+    }
+
+    #[test]
+    fn clipped_share() {
+        let rgb = rows(&[
+            // a single channel blown out is enough
+            (10, [255, 0, 0]),
+            (5, [254, 128, 128]),
+            (5, [253, 253, 253]),
+            (20, [1, 1, 0]),
+            (5, [2, 0, 0]),
+        ]);
+        let (clipped, crushed) = clipping(&rgb);
+        assert!((clipped - 15.).abs() < 0.01);
+        assert!((crushed - 20.).abs() < 0.01);
+
+        let (clipped, crushed) = clipping(&RgbImage::new(0, 0));
+        assert!(clipped.abs() < f32::EPSILON && crushed.abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn histogram_counts() {
+        let rgb = rows(&[(10, [255, 0, 0]), (30, [0, 0, 255])]);
+        let luma = image::DynamicImage::ImageRgb8(rgb.clone()).to_luma8();
+        let histogram = Histogram::new(&rgb, &luma);
+
+        assert_eq!(histogram.red[255], 1000);
+        assert_eq!(histogram.red[0], 3000);
+        assert_eq!(histogram.green[0], 4000);
+        assert_eq!(histogram.blue[255], 3000);
+        assert_eq!(histogram.blue[128], 6000);
+        assert_eq!(histogram.luma[128], 6000);
+        for channel in [
+            &histogram.red,
+            &histogram.green,
+            &histogram.blue,
+            &histogram.luma,
+        ] {
+            assert_eq!(channel.len(), 256);
+            assert_eq!(channel.iter().sum::<u32>(), 10_000);
+        }
+    }
+
+    #[test]
+    fn blank_frames() {
+        let config = AnalysisConfig::default();
+        let is_blank = |luma: &GrayImage| {
+            let mut analysis = ImageAnalysis::zeroed();
+            analysis.luma_deviation = luma_deviation(luma);
+            config.is_blank(Some(&analysis))
+        };
+
+        let black = GrayImage::new(600, 400);
+        assert!(luma_deviation(&black) < f32::EPSILON);
+        assert!(is_blank(&black));
+
+        // sensor noise of a few levels in a lens cap shot
+        let lens_cap = GrayImage::from_fn(600, 400, |x, y| {
+            image::Luma([u8::try_from(2 + (x * 7919 + y * 104_729) % 7).unwrap()])
+        });
+        assert!(luma_deviation(&lens_cap) > 1.);
+        assert!(is_blank(&lens_cap));
+
+        // a dim subject on a dark background
+        let low_key =
+            GrayImage::from_fn(600, 400, |x, _| image::Luma([if x < 180 { 30 } else { 8 }]));
+        assert!(!is_blank(&low_key));
+        assert!(!config.is_blank(None));
+    }
+}
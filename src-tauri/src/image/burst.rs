@@ -0,0 +1,214 @@
+use chrono::{NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::Image;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct BurstConfig {
+    /// Frames taken closer together belong to the same burst unless the maker note says otherwise
+    pub(crate) max_gap_ms: i64,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        Self { max_gap_ms: 500 }
+    }
+}
+
+/// When and how the img was taken as recorded in its EXIF
+#[derive(Clone, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct Capture {
+    /// Local time of the camera, with sub-second precision if recorded
+    pub(crate) taken: NaiveDateTime,
+    pub(crate) camera: Option<String>,
+    /// Only read from Sony maker notes so far
+    pub(crate) drive_mode: Option<DriveMode>,
+    /// Position of the frame in a continuous burst starting at 1
+    pub(crate) sequence: Option<u32>,
+    /// Seconds
+    pub(crate) exposure_time: Option<f64>,
+    pub(crate) f_number: Option<f64>,
+    pub(crate) iso: Option<u32>,
+    /// EV
+    pub(crate) exposure_bias: Option<f64>,
+    /// Millimetres
+    pub(crate) focal_length: Option<f64>,
+    /// Metres, few cameras record it
+    pub(crate) subject_distance: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum DriveMode {
+    Single,
+    Continuous,
+    Bracketing,
+}
+
+/// Sorts by the capture time, imgs without one fall back to the file time
+pub(crate) fn sort_by_capture(imgs: &mut [Image]) {
+    imgs.sort_by_key(|img| {
+        img.capture
+            .as_ref()
+            .map_or_else(|| img.created.naive_utc(), |capture| capture.taken)
+    });
+}
+
+/// Indices of the sorted imgs grouped by bursts, single shots are left out
+pub(crate) fn burst_indices(imgs: &[Image], config: &BurstConfig) -> Vec<Vec<usize>> {
+    let mut bursts = Vec::new();
+    let mut burst: Vec<usize> = Vec::new();
+
+    for (i, img) in imgs.iter().enumerate() {
+        if burst
+            .last()
+            .is_some_and(|prev| !continues_burst(&imgs[*prev], img, config))
+        {
+            push_burst(&mut bursts, &mut burst);
+        }
+        burst.push(i);
+    }
+    push_burst(&mut bursts, &mut burst);
+
+    bursts
+}
+
+fn push_burst(bursts: &mut Vec<Vec<usize>>, burst: &mut Vec<usize>) {
+    if burst.len() > 1 {
+        bursts.push(std::mem::take(burst));
+    }
+    burst.clear();
+}
+
+fn continues_burst(prev: &Image, next: &Image, config: &BurstConfig) -> bool {
+    // the frames of a sequence are stacked on their own
+    if prev.sequence.is_some() || next.sequence.is_some() {
+        return false;
+    }
+    let (Some(prev), Some(next)) = (&prev.capture, &next.capture) else {
+        return false;
+    };
+    // frames of multiple bodies might interleave
+    if prev.camera != next.camera {
+        return false;
+    }
+
+    match (prev.drive_mode, next.drive_mode) {
+        (Some(DriveMode::Continuous), Some(DriveMode::Continuous)) => {
+            // the counter restarts with every burst
+            if let (Some(prev), Some(next)) = (prev.sequence, next.sequence) {
+                return next == prev + 1;
+            }
+        }
+        // single shots and brackets are not bursts however close they are
+        (Some(_), _) | (_, Some(_)) => return false,
+        (None, None) => {}
+    }
+
+    next.taken - prev.taken <= TimeDelta::milliseconds(config.max_gap_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{
+        sequence::SequenceKind,
+        test_util::{capture, captured, image, names},
+        Sequence,
+    };
+    use chrono::{TimeZone, Utc};
+    use std::path::Path;
+
+    fn bursts(captures: impl IntoIterator<Item = Capture>) -> Vec<Vec<usize>> {
+        burst_indices(&captured(captures), &BurstConfig::default())
+    }
+
+    fn drive(ms: i64, drive_mode: DriveMode, sequence: Option<u32>) -> Capture {
+        Capture {
+            drive_mode: Some(drive_mode),
+            sequence,
+            ..capture(ms)
+        }
+    }
+
+    #[test]
+    fn time_gaps() {
+        // the max gap is still part of the burst
+        let captures = [0, 100, 600, 2000, 2400, 5000].map(capture);
+        assert_eq!(bursts(captures), [vec![0, 1, 2], vec![3, 4]]);
+        assert!(bursts([]).is_empty());
+        assert!(bursts([capture(0)]).is_empty());
+    }
+
+    #[test]
+    fn drive_modes() {
+        use DriveMode::{Bracketing, Continuous, Single};
+
+        // the counter restarts with a new burst however close it is
+        let counted = [1, 2, 3, 1, 2].map(|n| drive(i64::from(n) * 10, Continuous, Some(n)));
+        assert_eq!(bursts(counted), [vec![0, 1, 2], vec![3, 4]]);
+        // falls back to the time without a counter
+        let uncounted = [0, 100, 1000].map(|ms| drive(ms, Continuous, None));
+        assert_eq!(bursts(uncounted), [vec![0, 1]]);
+        // quick single shots and brackets are not bursts
+        assert!(bursts([0, 100].map(|ms| drive(ms, Single, None))).is_empty());
+        assert!(bursts([0, 100].map(|ms| drive(ms, Bracketing, None))).is_empty());
+        assert!(bursts([capture(0), drive(100, Continuous, Some(1))]).is_empty());
+    }
+
+    #[test]
+    fn cameras_and_sequences() {
+        let body = |ms, camera: &str| Capture {
+            camera: Some(camera.to_owned()),
+            ..capture(ms)
+        };
+        // interleaved bodies
+        assert!(bursts([body(0, "a"), body(100, "b"), body(200, "a")]).is_empty());
+        assert_eq!(
+            bursts([body(0, "a"), body(100, "a"), body(200, "b")]),
+            [vec![0, 1]]
+        );
+
+        let mut imgs = captured([0, 100, 200, 300].map(capture));
+        imgs[2].sequence = Some(Sequence {
+            kind: SequenceKind::Bracket,
+            id: "hdr".to_owned(),
+        });
+        imgs[3].capture = None;
+        assert_eq!(burst_indices(&imgs, &BurstConfig::default()), [vec![0, 1]]);
+    }
+
+    #[test]
+    fn capture_order() {
+        let dir = Path::new("");
+        let created = |name, secs| Image {
+            created: Utc.timestamp_opt(secs, 0).unwrap(),
+            ..image(dir, name)
+        };
+        let mut imgs = vec![
+            Image {
+                capture: Some(capture(2000)),
+                ..created("b", 0)
+            },
+            created("c", capture(3000).taken.and_utc().timestamp()),
+            Image {
+                capture: Some(capture(1000)),
+                // the file time is ignored
+                ..created("a", 4_000_000_000)
+            },
+            // ties keep their order
+            Image {
+                capture: Some(capture(2000)),
+                ..created("b2", 0)
+            },
+        ];
+        sort_by_capture(&mut imgs);
+        let paths: Vec<_> = imgs.iter().map(|img| &img.path).collect();
+        assert_eq!(names(paths), ["a", "b", "b2", "c"]);
+    }
+}
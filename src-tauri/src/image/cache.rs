@@ -0,0 +1,347 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use ts_rs::TS;
+
+use super::cull_meta::META_EXT;
+
+// the start & the end of the file identify it well enough without hashing whole raws
+const HASHED_CHUNK_LEN: u64 = 64 * 1024;
+const PREVIEW_EXT: &str = "webp";
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct CacheConfig {
+    /// Keep previews in a central dir instead of a `_cull` dir next to the imgs
+    pub(crate) enabled: bool,
+    /// Defaults to the app cache dir
+    pub(crate) dir: Option<PathBuf>,
+    pub(crate) max_size_mb: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            max_size_mb: 10 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct CacheStats {
+    dir: PathBuf,
+    previews: usize,
+    size_bytes: u64,
+    max_size_bytes: u64,
+}
+
+/// Content addressed preview storage with LRU eviction
+///
+/// Previews are touched when served, so their modification time is the last access
+pub(crate) struct PreviewCache {
+    dir: PathBuf,
+    max_size_bytes: u64,
+}
+
+impl PreviewCache {
+    pub(crate) fn new(config: &CacheConfig, default_dir: Option<PathBuf>) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let Some(dir) = config.dir.clone().or(default_dir) else {
+            println!("Preview cache is enabled, but there's no cache dir");
+            return None;
+        };
+
+        Some(Self {
+            dir,
+            max_size_bytes: config.max_size_mb * 1024 * 1024,
+        })
+    }
+
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir)
+    }
+
+    /// Preview path keyed by the content of the img
+    /// which survives moving or re-importing the img
+    pub(crate) async fn preview_path(&self, img_path: &Path) -> anyhow::Result<PathBuf> {
+        let key = content_key(img_path).await?;
+
+        Ok(self
+            .dir
+            .join(&key[..2])
+            .join(key)
+            .with_extension(PREVIEW_EXT))
+    }
+
+    /// Marks the preview as recently used
+    pub(crate) fn touch(&self, path: &Path) {
+        if self.contains(path) {
+            let _ = std::fs::File::options()
+                .write(true)
+                .open(path)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+        }
+    }
+
+    pub(crate) fn stats(&self) -> anyhow::Result<CacheStats> {
+        let entries = self.entries()?;
+
+        Ok(CacheStats {
+            dir: self.dir.clone(),
+            previews: entries.len(),
+            size_bytes: entries.iter().map(|e| e.len).sum(),
+            max_size_bytes: self.max_size_bytes,
+        })
+    }
+
+    /// Removes the renders of the least recently used imgs until the cache fits into its budget
+    pub(crate) fn evict(&self, keep: &HashSet<PathBuf>) -> anyhow::Result<()> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|e| e.len).sum();
+
+        if size <= self.max_size_bytes {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|e| e.used);
+
+        for entry in entries.iter().filter(|e| !keep.contains(&e.path)) {
+            if size <= self.max_size_bytes {
+                break;
+            }
+
+            if entry.remove().is_ok() {
+                size -= entry.len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the renders of all imgs apart from the kept ones
+    pub(crate) fn clear(&self, keep: &HashSet<PathBuf>) -> anyhow::Result<()> {
+        for entry in self.entries()? {
+            if !keep.contains(&entry.path) {
+                entry
+                    .remove()
+                    .context(format!("Failed to remove the renders of {:?}", entry.path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders grouped by the preview they're named after
+    fn entries(&self) -> anyhow::Result<Vec<CacheEntry>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = HashMap::new();
+        for shard in std::fs::read_dir(&self.dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+
+            for render in std::fs::read_dir(&shard)? {
+                let render = render?.path();
+                let Some((key, ext)) = render
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.split_once('.'))
+                else {
+                    continue;
+                };
+                if ext == META_EXT {
+                    continue;
+                }
+                // removed or renamed meanwhile
+                let Ok((len, used)) = disk_usage(&render) else {
+                    continue;
+                };
+
+                let path = shard.join(key).with_extension(PREVIEW_EXT);
+                let entry = entries.entry(path.clone()).or_insert(CacheEntry {
+                    path,
+                    renders: Vec::new(),
+                    len: 0,
+                    used: SystemTime::UNIX_EPOCH,
+                });
+                entry.renders.push(render);
+                entry.len += len;
+                entry.used = entry.used.max(used);
+            }
+        }
+
+        Ok(entries.into_values().collect())
+    }
+}
+
+/// Previews, overlays & tiles of an img, which are removed together
+///
+/// The cull meta holds the decisions and is tiny, so it's neither counted nor removed
+struct CacheEntry {
+    /// Preview path the renders are named after
+    path: PathBuf,
+    renders: Vec<PathBuf>,
+    len: u64,
+    /// Latest use of any of the renders
+    used: SystemTime,
+}
+
+impl CacheEntry {
+    fn remove(&self) -> std::io::Result<()> {
+        for render in &self.renders {
+            if render.is_dir() {
+                std::fs::remove_dir_all(render)?;
+            } else {
+                std::fs::remove_file(render)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Size & latest modification of a file or of all the files in a dir
+fn disk_usage(path: &Path) -> std::io::Result<(u64, SystemTime)> {
+    let meta = std::fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok((meta.len(), meta.modified()?));
+    }
+
+    let mut usage = (0, SystemTime::UNIX_EPOCH);
+    for entry in std::fs::read_dir(path)? {
+        let (len, used) = disk_usage(&entry?.path())?;
+        usage = (usage.0 + len, usage.1.max(used));
+    }
+
+    Ok(usage)
+}
+
+async fn content_key(path: &Path) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .context(format!("Failed to open {path:?}"))?;
+    let len = file.metadata().await?.len();
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+
+    let mut chunk = vec![0; usize::try_from(HASHED_CHUNK_LEN.min(len))?];
+    file.read_exact(&mut chunk).await?;
+    hasher.update(&chunk);
+
+    if len > HASHED_CHUNK_LEN {
+        file.seek(SeekFrom::End(-i64::try_from(chunk.len())?))
+            .await?;
+        file.read_exact(&mut chunk).await?;
+        hasher.update(&chunk);
+    }
+
+    Ok(hasher.finalize().to_hex()[..32].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::temp_dir;
+    use std::time::Duration;
+
+    fn cache(name: &str, max_size_bytes: u64) -> PreviewCache {
+        PreviewCache {
+            dir: temp_dir(name),
+            max_size_bytes,
+        }
+    }
+
+    /// Preview, thumbnail, a tile & the cull meta of 100 bytes each, used `age` secs ago
+    fn add(cache: &PreviewCache, key: &str, age: u64) -> PathBuf {
+        let preview = cache
+            .dir
+            .join(&key[..2])
+            .join(key)
+            .with_extension(PREVIEW_EXT);
+        let tile = preview.with_extension("tiles").join("0").join("0_0.webp");
+        std::fs::create_dir_all(tile.parent().unwrap()).unwrap();
+
+        let used = SystemTime::now() - Duration::from_secs(age);
+        for path in [
+            &preview,
+            &preview.with_extension("thumb.webp"),
+            &tile,
+            &preview.with_extension(META_EXT),
+        ] {
+            std::fs::write(path, [0; 100]).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(used)
+                .unwrap();
+        }
+
+        preview
+    }
+
+    fn exists(preview: &Path) -> [bool; 4] {
+        [
+            preview.exists(),
+            preview.with_extension("thumb.webp").exists(),
+            preview.with_extension("tiles").exists(),
+            preview.with_extension(META_EXT).exists(),
+        ]
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache("cache-evict", 700);
+        let open = add(&cache, "aa01", 400);
+        let old = add(&cache, "ab02", 300);
+        let used = add(&cache, "ab03", 200);
+        let new = add(&cache, "ac04", 100);
+        // serving any of the renders counts as a use
+        cache.touch(&used.with_extension("thumb.webp"));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.previews, stats.size_bytes), (4, 1200));
+
+        // within the budget after removing the renders of 2 imgs
+        cache.evict(&HashSet::from([open.clone()])).unwrap();
+        assert_eq!(exists(&open), [true; 4]);
+        assert_eq!(exists(&old), [false, false, false, true]);
+        assert_eq!(exists(&used), [true; 4]);
+        assert_eq!(exists(&new), [false, false, false, true]);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.previews, stats.size_bytes), (2, 600));
+        cache.evict(&HashSet::new()).unwrap();
+        assert_eq!(cache.stats().unwrap().previews, 2);
+    }
+
+    #[test]
+    fn clear_keeps_open_dir() {
+        let cache = cache("cache-clear", u64::MAX);
+        let open = add(&cache, "aa01", 100);
+        let other = add(&cache, "aa02", 0);
+
+        cache.clear(&HashSet::from([open.clone()])).unwrap();
+        assert_eq!(exists(&open), [true; 4]);
+        assert_eq!(exists(&other), [false, false, false, true]);
+        assert_eq!(cache.stats().unwrap().previews, 1);
+    }
+}
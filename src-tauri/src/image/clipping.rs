@@ -0,0 +1,156 @@
+use anyhow::Context;
+use image::RgbaImage;
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use super::{
+    file::{temp_path, write_atomic},
+    generator::PreviewGenerators,
+    scheduler::JobContext,
+    Image, PreviewSize,
+};
+
+const HIGHLIGHT_COLOR: [u8; 4] = [255, 0, 0, 255];
+const SHADOW_COLOR: [u8; 4] = [0, 64, 255, 255];
+
+/// Thresholds of the running app, the overlays are named after them
+static THRESHOLDS: OnceLock<ClippingConfig> = OnceLock::new();
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ClippingConfig {
+    /// Pixels with any channel at or above are blown out
+    pub(crate) highlights: u8,
+    /// Pixels with all channels at or below are blocked
+    pub(crate) shadows: u8,
+}
+
+impl Default for ClippingConfig {
+    fn default() -> Self {
+        Self {
+            highlights: 250,
+            shadows: 5,
+        }
+    }
+}
+
+impl ClippingConfig {
+    /// Sets the thresholds of the overlays for the rest of the run
+    pub(crate) fn apply(self) {
+        if THRESHOLDS.set(self).is_err() {
+            println!("Clipping thresholds are set already");
+        }
+    }
+
+    fn current() -> &'static Self {
+        THRESHOLDS.get_or_init(Self::default)
+    }
+}
+
+/// Path of the overlay of the preview, the overlays of other thresholds are rendered again
+pub(super) fn overlay_path(preview_path: &Path) -> PathBuf {
+    let config = ClippingConfig::current();
+    preview_path.with_extension(format!(
+        "clipping-{}-{}.webp",
+        config.highlights, config.shadows
+    ))
+}
+
+/// Marks the clipped pixels of the original at full res on a transparent background
+///
+/// The overlay has the size of the preview, a preview pixel is marked
+/// if any of the original pixels it covers is clipped so even small blown areas show
+pub(super) fn create_clipping_overlay(
+    img: &Image,
+    path: &Path,
+    generators: &PreviewGenerators,
+    ctx: &JobContext,
+) -> anyhow::Result<()> {
+    let config = ClippingConfig::current();
+    let decoded = generators.decode_full_res(img, &temp_path(&path.with_extension("png")), ctx)?;
+    let overlay = clipping_overlay(&decoded.img.to_rgb8(), config);
+
+    let webp = webp::Encoder::from_rgba(&overlay, overlay.width(), overlay.height())
+        .encode_lossless()
+        .to_vec();
+    write_atomic(path, webp).context(format!("Failed to write clipping overlay {path:?}"))
+}
+
+fn clipping_overlay(rgb: &image::RgbImage, config: &ClippingConfig) -> RgbaImage {
+    let (width, height) = rgb.dimensions();
+    let (overlay_width, overlay_height) = fit(width, height, PreviewSize::Preview.bounds());
+
+    let mut overlay = RgbaImage::new(overlay_width, overlay_height);
+    for (x, y, image::Rgb(channels)) in rgb.enumerate_pixels() {
+        let color = if channels.iter().any(|c| *c >= config.highlights) {
+            HIGHLIGHT_COLOR
+        } else if channels.iter().all(|c| *c <= config.shadows) {
+            SHADOW_COLOR
+        } else {
+            continue;
+        };
+
+        let overlay_x = scale(x, width, overlay_width);
+        let overlay_y = scale(y, height, overlay_height);
+        let pixel = overlay.get_pixel_mut(overlay_x, overlay_y);
+        // blown highlights are the bigger problem
+        if pixel.0 != HIGHLIGHT_COLOR {
+            *pixel = image::Rgba(color);
+        }
+    }
+
+    overlay
+}
+
+/// Dimensions of the img fitted into the bounds the same way as the previews
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn fit(width: u32, height: u32, (max_width, max_height): (u32, u32)) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+
+    let ratio = f64::min(
+        f64::from(max_width) / f64::from(width),
+        f64::from(max_height) / f64::from(height),
+    );
+    (
+        ((f64::from(width) * ratio).round() as u32).clamp(1, max_width),
+        ((f64::from(height) * ratio).round() as u32).clamp(1, max_height),
+    )
+}
+
+/// Maps a pixel coordinate of the original to the overlay
+fn scale(coord: u32, size: u32, overlay_size: u32) -> u32 {
+    let scaled = u64::from(coord) * u64::from(overlay_size) / u64::from(size);
+    u32::try_from(scaled).expect("The overlay is not larger than the original")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_clipped_pixels() {
+        let config = ClippingConfig::default();
+        // a blown and a blocked column on a mid grey 4000px wide original
+        let rgb = image::RgbImage::from_fn(4000, 100, |x, _| match x {
+            0 => image::Rgb([255, 120, 120]),
+            3999 => image::Rgb([2, 3, 4]),
+            _ => image::Rgb([128, 128, 128]),
+        });
+
+        let overlay = clipping_overlay(&rgb, &config);
+        assert_eq!(overlay.dimensions(), (2000, 50));
+        // a single px column of the original still shows
+        assert_eq!(overlay.get_pixel(0, 10).0, HIGHLIGHT_COLOR);
+        assert_eq!(overlay.get_pixel(1999, 10).0, SHADOW_COLOR);
+        assert_eq!(overlay.get_pixel(1000, 10).0, [0; 4]);
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use image::DynamicImage;
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use std::path::{Path, PathBuf};
+
+use super::file::write_atomic;
+
+// https://developers.google.com/speed/webp/docs/riff_container#extended_file_format
+const VP8X_ICC: u8 = 0x20;
+const VP8X_ALPHA: u8 = 0x10;
+
+/// Colour space of an img without an ICC profile as signalled by its EXIF
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ExifColorSpace {
+    Srgb,
+    AdobeRgb,
+}
+
+/// What the colours of a decoded img are relative to, none of it means sRGB
+#[derive(Default)]
+pub(crate) struct SourceColor {
+    pub(crate) icc: Option<Vec<u8>>,
+    pub(crate) exif: Option<ExifColorSpace>,
+}
+
+/// Profile the previews are converted to and tagged with
+pub(crate) struct ColorTarget {
+    profile: ColorProfile,
+    icc: Vec<u8>,
+    /// File of the display profile, sRGB otherwise
+    display: Option<PathBuf>,
+}
+
+impl ColorTarget {
+    pub(crate) fn srgb() -> Self {
+        let profile = ColorProfile::new_srgb();
+        Self {
+            icc: profile.encode().unwrap_or_default(),
+            profile,
+            display: None,
+        }
+    }
+
+    /// Loads the ICC profile of the display
+    pub(crate) fn display(path: &Path) -> anyhow::Result<Self> {
+        let icc = std::fs::read(path).context(format!("Failed to read profile {path:?}"))?;
+        let profile = ColorProfile::new_from_slice(&icc)
+            .map_err(|e| anyhow!("Invalid profile {path:?}: {e}"))?;
+        ensure!(
+            profile.color_space == DataColorSpace::Rgb,
+            "Display profile {path:?} is not an RGB profile"
+        );
+
+        Ok(Self {
+            profile,
+            icc,
+            display: Some(path.to_owned()),
+        })
+    }
+
+    /// Profile files for external backends to convert through in order
+    ///
+    /// sRGB comes first, so imgs without a profile are taken as sRGB like by the native decoders
+    pub(crate) fn profile_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let srgb = std::env::temp_dir().join("chela-srgb.icc");
+        if !srgb.exists() {
+            let icc = ColorProfile::new_srgb()
+                .encode()
+                .map_err(|e| anyhow!("Failed to encode the sRGB profile: {e}"))?;
+            write_atomic(&srgb, icc)?;
+        }
+
+        Ok([srgb].into_iter().chain(self.display.clone()).collect())
+    }
+
+    /// Converts the img from its source profile, imgs without one are assumed to be sRGB
+    pub(crate) fn convert(
+        &self,
+        img: &mut DynamicImage,
+        source: &SourceColor,
+    ) -> anyhow::Result<()> {
+        let source = match (&source.icc, source.exif) {
+            (Some(icc), _) => ColorProfile::new_from_slice(icc)
+                .map_err(|e| anyhow!("Invalid embedded profile: {e}"))?,
+            (None, Some(ExifColorSpace::AdobeRgb)) => ColorProfile::new_adobe_rgb(),
+            (None, _) if self.display.is_none() => return Ok(()),
+            (None, _) => ColorProfile::new_srgb(),
+        };
+        if source.color_space != DataColorSpace::Rgb {
+            bail!("Unsupported {:?} profile", source.color_space);
+        }
+
+        // the previews are 8bit anyway
+        let alpha = img.color().has_alpha();
+        let layout = if alpha { Layout::Rgba } else { Layout::Rgb };
+        let transform = source
+            .create_transform_8bit(layout, &self.profile, layout, TransformOptions::default())
+            .map_err(|e| anyhow!("Failed to create colour transform: {e}"))?;
+        let convert = |src: &[u8], dst: &mut [u8]| {
+            transform
+                .transform(src, dst)
+                .map_err(|e| anyhow!("Failed to convert colours: {e}"))
+        };
+
+        *img = if alpha {
+            let src = img.to_rgba8();
+            let mut dst = src.clone();
+            convert(&src, &mut dst)?;
+            DynamicImage::ImageRgba8(dst)
+        } else {
+            let src = img.to_rgb8();
+            let mut dst = src.clone();
+            convert(&src, &mut dst)?;
+            DynamicImage::ImageRgb8(dst)
+        };
+
+        Ok(())
+    }
+
+    /// Embeds the profile into the encoded WebP, which takes the extended format
+    pub(crate) fn tag_webp(&self, webp: &[u8], width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            webp.len() >= 12 && &webp[..4] == b"RIFF" && &webp[8..12] == b"WEBP",
+            "Not a WebP"
+        );
+
+        let mut flags = VP8X_ICC;
+        let mut body = Vec::with_capacity(webp.len());
+        let mut chunks = &webp[12..];
+        while chunks.len() >= 8 {
+            let len = usize::try_from(u32::from_le_bytes([
+                chunks[4], chunks[5], chunks[6], chunks[7],
+            ]))?;
+            // chunks are padded to an even size
+            let end = 8 + len + len % 2;
+            let chunk = chunks.get(..end).context("Truncated WebP chunk")?;
+            match &chunk[..4] {
+                // rewritten below
+                b"VP8X" => flags |= chunk.get(8).copied().unwrap_or_default(),
+                b"ICCP" => {}
+                fourcc => {
+                    if fourcc == b"ALPH" {
+                        flags |= VP8X_ALPHA;
+                    }
+                    body.extend_from_slice(chunk);
+                }
+            }
+            chunks = &chunks[end..];
+        }
+
+        let mut vp8x = [0; 10];
+        vp8x[0] = flags;
+        vp8x[4..7].copy_from_slice(&width.saturating_sub(1).to_le_bytes()[..3]);
+        vp8x[7..10].copy_from_slice(&height.saturating_sub(1).to_le_bytes()[..3]);
+
+        let mut chunks = Vec::with_capacity(body.len() + self.icc.len() + 32);
+        push_chunk(&mut chunks, *b"VP8X", &vp8x)?;
+        push_chunk(&mut chunks, *b"ICCP", &self.icc)?;
+        chunks.extend_from_slice(&body);
+
+        let mut tagged = Vec::with_capacity(chunks.len() + 12);
+        tagged.extend_from_slice(b"RIFF");
+        tagged.extend_from_slice(&u32::try_from(chunks.len() + 4)?.to_le_bytes());
+        tagged.extend_from_slice(b"WEBP");
+        tagged.extend_from_slice(&chunks);
+
+        Ok(tagged)
+    }
+}
+
+fn push_chunk(chunks: &mut Vec<u8>, fourcc: [u8; 4], data: &[u8]) -> anyhow::Result<()> {
+    chunks.extend_from_slice(&fourcc);
+    chunks.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+    chunks.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunks.push(0);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::cast_possible_truncation)]
+mod tests {
+    use super::*;
+    use image::{ImageDecoder, RgbImage, RgbaImage};
+
+    /// Ids and data of the chunks after checking the RIFF header
+    fn chunks(webp: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&webp[..4], b"RIFF");
+        let riff_len = u32::from_le_bytes(webp[4..8].try_into().unwrap());
+        assert_eq!(usize::try_from(riff_len).unwrap() + 8, webp.len());
+
+        let mut chunks = Vec::new();
+        let mut rest = &webp[12..];
+        while !rest.is_empty() {
+            let len = usize::try_from(u32::from_le_bytes(rest[4..8].try_into().unwrap())).unwrap();
+            chunks.push((rest[..4].try_into().unwrap(), rest[8..8 + len].to_vec()));
+            rest = &rest[8 + len + len % 2..];
+        }
+        chunks
+    }
+
+    fn fourccs(webp: &[u8]) -> Vec<[u8; 4]> {
+        chunks(webp).into_iter().map(|(fourcc, _)| fourcc).collect()
+    }
+
+    fn simple_webp() -> Vec<u8> {
+        let img = RgbImage::from_fn(30, 20, |x, y| image::Rgb([x as u8 * 8, y as u8 * 12, 90]));
+        webp::Encoder::from_rgb(&img, 30, 20).encode(80.).to_vec()
+    }
+
+    #[test]
+    fn tag_simple_webp() {
+        let target = ColorTarget::srgb();
+        let webp = simple_webp();
+        assert_eq!(fourccs(&webp), [*b"VP8 "]);
+
+        let tagged = target.tag_webp(&webp, 30, 20).unwrap();
+        let chunks = chunks(&tagged);
+        assert_eq!(fourccs(&tagged), [*b"VP8X", *b"ICCP", *b"VP8 "]);
+        // 24bit canvas size minus one
+        assert_eq!(chunks[0].1, [VP8X_ICC, 0, 0, 0, 29, 0, 0, 19, 0, 0]);
+        assert_eq!(chunks[1].1, target.icc);
+        assert_eq!(chunks[2].1, self::chunks(&webp)[0].1);
+
+        let mut decoder =
+            image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(&tagged)).unwrap();
+        assert_eq!(decoder.dimensions(), (30, 20));
+        assert_eq!(decoder.icc_profile().unwrap(), Some(target.icc.clone()));
+
+        // the profile is replaced rather than added again
+        assert_eq!(target.tag_webp(&tagged, 30, 20).unwrap(), tagged);
+    }
+
+    #[test]
+    fn tag_alpha_webp() {
+        let img = RgbaImage::from_fn(16, 16, |x, _| image::Rgba([200, 10, 10, x as u8 * 16]));
+        let webp = webp::Encoder::from_rgba(&img, 16, 16).encode(80.).to_vec();
+        assert!(fourccs(&webp).contains(b"ALPH"));
+
+        let tagged = ColorTarget::srgb().tag_webp(&webp, 16, 16).unwrap();
+        let chunks = chunks(&tagged);
+        assert_eq!(chunks[0].1[0], VP8X_ICC | VP8X_ALPHA);
+        assert_eq!(fourccs(&tagged)[2..], fourccs(&webp)[1..]);
+    }
+
+    #[test]
+    fn tag_odd_sized_profile() {
+        let target = ColorTarget {
+            icc: vec![1, 2, 3],
+            ..ColorTarget::srgb()
+        };
+        let tagged = target.tag_webp(&simple_webp(), 30, 20).unwrap();
+        // padded to an even size
+        assert_eq!(tagged.len() % 2, 0);
+        assert_eq!(chunks(&tagged)[1].1, [1, 2, 3]);
+
+        let retagged = ColorTarget::srgb().tag_webp(&tagged, 30, 20).unwrap();
+        assert_eq!(fourccs(&retagged), [*b"VP8X", *b"ICCP", *b"VP8 "]);
+    }
+
+    #[test]
+    fn tag_invalid_webp() {
+        let target = ColorTarget::srgb();
+        assert!(target.tag_webp(b"", 1, 1).is_err());
+        assert!(target.tag_webp(b"RIFF\0\0\0\0WEBQ", 1, 1).is_err());
+
+        let mut webp = simple_webp();
+        webp.truncate(webp.len() - 10);
+        assert!(target.tag_webp(&webp, 30, 20).is_err());
+    }
+
+    #[test]
+    fn profile_files_for_magick() {
+        let srgb = ColorTarget::srgb().profile_files().unwrap();
+        assert_eq!(srgb.len(), 1);
+        let profile = ColorProfile::new_from_slice(&std::fs::read(&srgb[0]).unwrap()).unwrap();
+        assert_eq!(profile.color_space, DataColorSpace::Rgb);
+
+        // untagged imgs are taken as sRGB on the way to the display
+        let display = ColorTarget {
+            display: Some(PathBuf::from("display.icc")),
+            ..ColorTarget::srgb()
+        };
+        assert_eq!(
+            display.profile_files().unwrap(),
+            [srgb[0].clone(), PathBuf::from("display.icc")]
+        );
+    }
+}
@@ -1,13 +1,37 @@
-use super::CullState;
+use super::{
+    analysis::{Histogram, ImageAnalysis},
+    file::write_atomic,
+    stack::StackMembership,
+    CullState,
+};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
 
 pub(crate) const META_EXT: &str = "cull.json";
 
+/// Metas being updated, the commands & the preview workers update different fields of the same meta
+static META_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(Mutex::default);
+
 #[derive(Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct CullMeta {
     pub(crate) cull_state: CullState,
+    /// Why the last preview gen has failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) preview_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) analysis: Option<ImageAnalysis>,
+    /// Not part of the analysis, so it's not sent along with every img
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) histogram: Option<Histogram>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) stack: Option<StackMembership>,
 }
 
 pub(crate) async fn read_cull_meta_or_default(path: impl AsRef<Path>) -> CullMeta {
@@ -16,3 +40,83 @@ pub(crate) async fn read_cull_meta_or_default(path: impl AsRef<Path>) -> CullMet
         |m| serde_json::from_str(&m).unwrap_or_default(),
     )
 }
+
+/// Used by the preview workers, which run outside of the async runtime
+pub(crate) fn read_cull_meta(path: &Path) -> CullMeta {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|m| serde_json::from_str(&m).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the updated meta unless nothing has changed
+///
+/// The meta is read again under a lock of its path, so only the fields changed by the update are written
+pub(crate) fn update_cull_meta(
+    path: &Path,
+    update: impl FnOnce(&mut CullMeta),
+) -> anyhow::Result<()> {
+    let lock = meta_lock(path);
+    let _guard = lock.lock().expect("Cull meta lock is not poisoned");
+
+    let current = std::fs::read_to_string(path).ok();
+    let mut cull_meta: CullMeta = current
+        .as_deref()
+        .and_then(|m| serde_json::from_str(m).ok())
+        .unwrap_or_default();
+
+    update(&mut cull_meta);
+
+    let updated = serde_json::to_string_pretty(&cull_meta)?;
+    if current.as_deref() != Some(updated.as_str()) {
+        write_atomic(path, updated).context(format!("Failed to write cull meta {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn meta_lock(path: &Path) -> Arc<Mutex<()>> {
+    let mut locks = META_LOCKS.lock().expect("Cull meta locks are not poisoned");
+    // only the metas being updated right now are kept
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    Arc::clone(locks.entry(path.to_owned()).or_default())
+}
+
+pub(crate) fn set_preview_error(path: &Path, preview_error: Option<&str>) -> anyhow::Result<()> {
+    update_cull_meta(path, |cull_meta| {
+        cull_meta.preview_error = preview_error.map(str::to_owned);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::temp_dir;
+
+    #[test]
+    fn concurrent_updates() {
+        let path = temp_dir("cull-meta").join("img.cull.json");
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    if i % 2 == 0 {
+                        update_cull_meta(path, |meta| meta.cull_state = CullState::Selected)
+                    } else {
+                        set_preview_error(path, Some("failed"))
+                    }
+                    .unwrap();
+                });
+            }
+        });
+
+        let meta = read_cull_meta(&path);
+        assert_eq!(meta.cull_state, CullState::Selected);
+        assert_eq!(meta.preview_error.as_deref(), Some("failed"));
+        // no temp files are left behind
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+}
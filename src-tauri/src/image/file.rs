@@ -0,0 +1,72 @@
+use anyhow::Context;
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Unique path next to the given one to write to before renaming it into place
+///
+/// The extension is kept, so backends still pick the right format
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_extension(format!("{}-{n}.part.{ext}", std::process::id()))
+}
+
+/// Moves the written temp file into place, or removes it if that fails
+pub(crate) fn persist(temp: &Path, path: &Path) -> anyhow::Result<()> {
+    std::fs::rename(temp, path).map_err(|e| {
+        let _ = std::fs::remove_file(temp);
+        anyhow::Error::new(e).context(format!("Failed to move {temp:?} to {path:?}"))
+    })
+}
+
+/// Readers never see a partially written file
+pub(crate) fn write_atomic(path: &Path, data: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let temp = temp_path(path);
+    if let Err(e) = std::fs::write(&temp, data) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e).context(format!("Failed to write {path:?}"));
+    }
+
+    persist(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::temp_dir;
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn no_partial_files() {
+        let dir = temp_dir("no-partial-files");
+        let path = dir.join("a.thumb.webp");
+        let temp = temp_path(&path);
+        assert_ne!(temp, temp_path(&path));
+        assert!(temp.to_string_lossy().ends_with(".part.webp"));
+
+        write_atomic(&path, "first").unwrap();
+        write_atomic(&path, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(files(&dir), ["a.thumb.webp"]);
+
+        std::fs::write(&temp, "partial").unwrap();
+        assert!(persist(&temp, &dir.join("missing").join("a.webp")).is_err());
+        assert!(write_atomic(&dir.join("missing").join("a.webp"), "data").is_err());
+        assert_eq!(files(&dir), ["a.thumb.webp"]);
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::Context;
+use image::{GrayImage, RgbaImage};
+use std::path::{Path, PathBuf};
+
+use super::file::write_atomic;
+
+/// Gradient magnitude above which an edge is considered sharp,
+/// a hard luma step is 255 while a blurred one is spread over several pixels
+const PEAKING_THRESHOLD: u32 = 64;
+const PEAKING_COLOR: [u8; 4] = [255, 32, 32, 255];
+
+/// Overlay path of the preview size
+fn overlay_path(preview_path: &Path) -> PathBuf {
+    preview_path.with_extension("focus.webp")
+}
+
+/// Highlights the sharp edges of the preview on a transparent background,
+/// so the overlay has the exact dimensions of the preview
+pub(crate) fn create_focus_overlay(preview_path: &Path) -> anyhow::Result<PathBuf> {
+    let path = overlay_path(preview_path);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let luma = image::open(preview_path)
+        .context(format!("Failed to open preview {preview_path:?}"))?
+        .to_luma8();
+    let overlay = peaking_overlay(&luma);
+
+    let webp = webp::Encoder::from_rgba(&overlay, overlay.width(), overlay.height())
+        .encode_lossless()
+        .to_vec();
+    write_atomic(&path, webp).context(format!("Failed to write focus overlay {path:?}"))?;
+
+    Ok(path)
+}
+
+fn peaking_overlay(luma: &GrayImage) -> RgbaImage {
+    let (width, height) = luma.dimensions();
+    let mut overlay = RgbaImage::new(width, height);
+
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            if sobel(luma, x, y) > PEAKING_THRESHOLD {
+                overlay.put_pixel(x, y, image::Rgba(PEAKING_COLOR));
+            }
+        }
+    }
+
+    overlay
+}
+
+/// Approximate gradient magnitude normalized to the 0-255 range of the luma
+fn sobel(luma: &GrayImage, x: u32, y: u32) -> u32 {
+    let p = |dx: u32, dy: u32| i32::from(luma.get_pixel(x + dx - 1, y + dy - 1).0[0]);
+
+    let gx = p(2, 0) + 2 * p(2, 1) + p(2, 2) - p(0, 0) - 2 * p(0, 1) - p(0, 2);
+    let gy = p(0, 2) + 2 * p(1, 2) + p(2, 2) - p(0, 0) - 2 * p(1, 0) - p(2, 0);
+
+    (gx.unsigned_abs() + gy.unsigned_abs()) / 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked(overlay: &RgbaImage, y: u32) -> Vec<u32> {
+        (0..overlay.width())
+            .filter(|x| overlay.get_pixel(*x, y).0 == PEAKING_COLOR)
+            .collect()
+    }
+
+    #[test]
+    fn hard_edge_is_marked() {
+        let edge = GrayImage::from_fn(40, 20, |x, _| image::Luma([if x < 20 { 0 } else { 255 }]));
+        assert_eq!(sobel(&edge, 19, 10), 255);
+        assert_eq!(sobel(&edge, 10, 10), 0);
+
+        let overlay = peaking_overlay(&edge);
+        assert_eq!(overlay.dimensions(), (40, 20));
+        assert_eq!(marked(&overlay, 10), [19, 20]);
+        // the border has no neighbours to compare with
+        assert!(marked(&overlay, 0).is_empty());
+        assert_eq!(overlay.get_pixel(5, 10).0, [0; 4]);
+    }
+
+    #[test]
+    fn flat_and_soft_are_not_marked() {
+        let flat = GrayImage::from_pixel(40, 20, image::Luma([128]));
+        assert!(peaking_overlay(&flat).pixels().all(|p| p.0 == [0; 4]));
+
+        // the same step spread over 16px
+        let soft = GrayImage::from_fn(40, 20, |x, _| {
+            image::Luma([u8::try_from((x.saturating_sub(12) * 16).min(255)).unwrap()])
+        });
+        assert!(peaking_overlay(&soft).pixels().all(|p| p.0 == [0; 4]));
+    }
+}
@@ -0,0 +1,479 @@
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+use ts_rs::TS;
+
+use super::{
+    clipping::ClippingConfig,
+    color::ColorTarget,
+    file,
+    native::{self, DecodedImage, NativeGenerator},
+    process::{command, run},
+    queue::CancelToken,
+    raw_preview::{self, EmbeddedJpegGenerator},
+    scheduler::JobContext,
+    Image, PreviewSize,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) trait PreviewGenerator: Send + Sync {
+    fn backend(&self) -> PreviewBackend;
+
+    /// Checks whether the backend can run on this machine
+    fn probe(&self) -> bool;
+
+    /// Long running backends should give up once the job is cancelled
+    ///
+    /// The output is a temp path moved into place once the preview is complete
+    fn generate(
+        &self,
+        img: &Image,
+        size: PreviewSize,
+        output: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<GeneratedPreview>;
+}
+
+pub(crate) enum PreviewOutput {
+    /// The generator has written the preview to the output path
+    File,
+    /// Encoded WebP which still has to be written
+    Bytes(Vec<u8>),
+}
+
+pub(crate) struct GeneratedPreview {
+    pub(crate) output: PreviewOutput,
+    pub(crate) diagnostics: Vec<String>,
+}
+
+impl GeneratedPreview {
+    pub(crate) fn bytes(webp: Vec<u8>, diagnostics: Vec<String>) -> Self {
+        Self {
+            output: PreviewOutput::Bytes(webp),
+            diagnostics,
+        }
+    }
+
+    pub(crate) fn file(diagnostics: Vec<String>) -> Self {
+        Self {
+            output: PreviewOutput::File,
+            diagnostics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum PreviewBackend {
+    Native,
+    EmbeddedJpeg,
+    Magick,
+    /// A command line from the config
+    Command(String),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CommandLine {
+    program: String,
+    /// `{input}`, `{output}`, `{width}`, `{height}` and `{threads}` are replaced
+    /// by the img, the preview path, the max preview dimensions and the per job thread limit
+    #[serde(default)]
+    args: Vec<String>,
+    /// Overrides [`PreviewConfig::timeout_secs`]
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct PreviewConfig {
+    /// Backends to try in order, keyed by a lowercase file extension
+    backends: HashMap<String, Vec<PreviewBackend>>,
+    commands: HashMap<String, CommandLine>,
+    /// Also generate 1:1 previews
+    full_size: bool,
+    /// External processes running longer are killed
+    timeout_secs: u64,
+    /// ICC profile the previews are converted to instead of sRGB
+    display_profile: Option<PathBuf>,
+    /// Thresholds of the clipping overlay
+    pub(crate) clipping: ClippingConfig,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            backends: HashMap::new(),
+            commands: HashMap::new(),
+            full_size: false,
+            timeout_secs: 60,
+            display_profile: None,
+            clipping: ClippingConfig::default(),
+        }
+    }
+}
+
+impl PreviewConfig {
+    pub(crate) fn sizes(&self) -> Vec<PreviewSize> {
+        PreviewSize::ALL
+            .into_iter()
+            .filter(|size| self.full_size || *size != PreviewSize::Full)
+            .collect()
+    }
+}
+
+#[derive(Clone, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct PreviewBackendInfo {
+    backend: PreviewBackend,
+    available: bool,
+}
+
+pub(crate) struct PreviewGenerators {
+    generators: Vec<(Box<dyn PreviewGenerator>, bool)>,
+    backends: HashMap<String, Vec<PreviewBackend>>,
+    color: Arc<ColorTarget>,
+}
+
+impl PreviewGenerators {
+    /// Creates all generators and probes which of them are usable
+    pub(crate) fn new(config: &PreviewConfig) -> Self {
+        let color = Arc::new(config.display_profile.as_deref().map_or_else(
+            ColorTarget::srgb,
+            |path| {
+                ColorTarget::display(path).unwrap_or_else(|e| {
+                    println!("Falling back to sRGB previews: {e:#}");
+                    ColorTarget::srgb()
+                })
+            },
+        ));
+
+        let mut generators: Vec<Box<dyn PreviewGenerator>> = vec![
+            Box::new(NativeGenerator {
+                color: color.clone(),
+            }),
+            Box::new(EmbeddedJpegGenerator {
+                color: color.clone(),
+            }),
+            Box::new(MagickGenerator {
+                timeout: Duration::from_secs(config.timeout_secs),
+                color: color.clone(),
+            }),
+        ];
+        generators.extend(config.commands.iter().map(|(name, cmd)| {
+            Box::new(CommandGenerator {
+                name: name.clone(),
+                cmd: cmd.clone(),
+                timeout: Duration::from_secs(cmd.timeout_secs.unwrap_or(config.timeout_secs)),
+            }) as Box<dyn PreviewGenerator>
+        }));
+
+        Self {
+            generators: generators
+                .into_iter()
+                .map(|g| {
+                    let available = g.probe();
+                    if !available {
+                        println!("Preview backend {:?} is not available", g.backend());
+                    }
+                    (g, available)
+                })
+                .collect(),
+            backends: config
+                .backends
+                .iter()
+                .map(|(ext, backends)| (ext.to_lowercase(), backends.clone()))
+                .collect(),
+            color,
+        }
+    }
+
+    /// Profile the previews are converted to
+    pub(crate) fn color(&self) -> Arc<ColorTarget> {
+        Arc::clone(&self.color)
+    }
+
+    pub(crate) fn info(&self) -> Vec<PreviewBackendInfo> {
+        self.generators
+            .iter()
+            .map(|(g, available)| PreviewBackendInfo {
+                backend: g.backend(),
+                available: *available,
+            })
+            .collect()
+    }
+
+    /// Extensions with configured backends
+    pub(crate) fn extensions(&self) -> Vec<String> {
+        self.backends.keys().cloned().collect()
+    }
+
+    /// Available generators for the img in the order they should be tried
+    pub(crate) fn for_path(&self, path: &Path) -> Vec<&dyn PreviewGenerator> {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase)
+            .unwrap_or_default();
+
+        let backends = self
+            .backends
+            .get(&ext)
+            .cloned()
+            .unwrap_or_else(|| default_backends(path));
+
+        backends
+            .iter()
+            .filter_map(|backend| {
+                self.generators
+                    .iter()
+                    .find(|(g, available)| *available && g.backend() == *backend)
+                    .map(|(g, _)| g.as_ref())
+            })
+            .collect()
+    }
+
+    /// Available backends rendering the img from its full source rather than an embedded preview,
+    /// the configured ones or else magick
+    pub(crate) fn external_for_path(&self, path: &Path) -> Vec<&dyn PreviewGenerator> {
+        let external = |g: &&dyn PreviewGenerator| {
+            matches!(
+                g.backend(),
+                PreviewBackend::Magick | PreviewBackend::Command(_)
+            )
+        };
+        let generators: Vec<_> = self.for_path(path).into_iter().filter(external).collect();
+        if !generators.is_empty() {
+            return generators;
+        }
+
+        self.generators
+            .iter()
+            .filter(|(g, available)| *available && g.backend() == PreviewBackend::Magick)
+            .map(|(g, _)| g.as_ref())
+            .collect()
+    }
+
+    /// Natively supported imgs are decoded as they are, sharing the decode with the other sizes,
+    /// the others are rendered at full res by an external backend as raws only embed smaller JPEGs
+    ///
+    /// The scratch path is where the backends write the rendition, it's removed once decoded
+    pub(crate) fn decode_full_res(
+        &self,
+        img: &Image,
+        scratch: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<Arc<DecodedImage>> {
+        if native::is_supported(&img.path) {
+            return ctx.decoded.get_or_decode(PreviewBackend::Native, || {
+                let data = ctx
+                    .scheduler
+                    .disk_read(|| std::fs::read(&img.path))
+                    .context(format!("Failed to read {:?}", img.path))?;
+                native::decode_oriented(data, &img.path, None)
+            });
+        }
+
+        let mut errors = Vec::new();
+        for generator in self.external_for_path(&img.path) {
+            let res = generator
+                .generate(img, PreviewSize::Full, scratch, ctx)
+                .and_then(|rendered| {
+                    let data = match rendered.output {
+                        PreviewOutput::File => std::fs::read(scratch)?,
+                        PreviewOutput::Bytes(data) => data,
+                    };
+                    native::decode_oriented(data, &img.path, None)
+                });
+            let _ = std::fs::remove_file(scratch);
+            match res {
+                Ok(decoded) => return Ok(Arc::new(decoded)),
+                Err(_) if ctx.cancel.is_cancelled() => bail!("{:?} has been cancelled", img.path),
+                Err(e) => errors.push(format!("{:?}: {e:#}", generator.backend())),
+            }
+        }
+
+        bail!(
+            "No backend could render {:?} at full res [{}]",
+            img.path,
+            errors.join(", ")
+        )
+    }
+}
+
+fn default_backends(path: &Path) -> Vec<PreviewBackend> {
+    if native::is_supported(path) {
+        vec![PreviewBackend::Native, PreviewBackend::Magick]
+    } else if raw_preview::is_supported(path) {
+        vec![PreviewBackend::EmbeddedJpeg, PreviewBackend::Magick]
+    } else {
+        vec![PreviewBackend::Magick]
+    }
+}
+
+/// Moves the preview into place, a failed one is removed
+///
+/// The diagnostics of the backend are only kept when the preview can't be written
+pub(crate) fn write_preview(
+    path: &Path,
+    output: &Path,
+    preview: GeneratedPreview,
+) -> anyhow::Result<()> {
+    let diagnostics = if preview.diagnostics.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", preview.diagnostics.join("; "))
+    };
+
+    match preview.output {
+        PreviewOutput::File => {
+            if !output.exists() {
+                bail!("Backend has not written preview {path:?}{diagnostics}");
+            }
+            file::persist(output, path)
+        }
+        PreviewOutput::Bytes(webp) => file::write_atomic(path, webp),
+    }
+    .context(format!("Failed to write preview {path:?}{diagnostics}"))
+}
+
+/// Looks up the program in `PATH` unless it's a path already
+fn find_executable(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_owned());
+    }
+
+    let exts: &[&str] = if cfg!(target_os = "windows") {
+        &["", ".exe", ".cmd", ".bat"]
+    } else {
+        &[""]
+    };
+
+    env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+        exts.iter()
+            .map(|ext| dir.join(format!("{program}{ext}")))
+            .find(|p| p.is_file())
+    })
+}
+
+fn path_arg(path: &Path) -> anyhow::Result<&str> {
+    path.to_str().ok_or(anyhow!("Invalid path {path:?}"))
+}
+
+/// Windows paths are quoted by hand as magick parses the raw command line itself
+#[cfg_attr(not(target_os = "windows"), allow(clippy::unnecessary_wraps))]
+fn push_path(cmd: &mut Command, path: &Path) -> anyhow::Result<()> {
+    #[cfg(target_os = "windows")]
+    cmd.raw_arg(format!("\"{}\"", path_arg(path)?));
+    #[cfg(not(target_os = "windows"))]
+    cmd.arg(path);
+
+    Ok(())
+}
+
+struct MagickGenerator {
+    timeout: Duration,
+    color: Arc<ColorTarget>,
+}
+
+impl PreviewGenerator for MagickGenerator {
+    fn backend(&self) -> PreviewBackend {
+        PreviewBackend::Magick
+    }
+
+    fn probe(&self) -> bool {
+        let mut cmd = command("magick", false);
+        cmd.arg("-version");
+        run(cmd, &CancelToken::default(), PROBE_TIMEOUT).is_ok()
+    }
+
+    fn generate(
+        &self,
+        img: &Image,
+        size: PreviewSize,
+        output: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<GeneratedPreview> {
+        let (width, height) = size.bounds();
+
+        let profiles = self.color.profile_files()?;
+
+        let mut cmd = command("magick", ctx.scheduler.low_priority());
+        push_path(&mut cmd, &img.path)?;
+        // the first profile is assigned to untagged imgs, the others are converted to
+        for profile in &profiles {
+            cmd.arg("-profile");
+            push_path(&mut cmd, profile)?;
+        }
+        cmd.arg("-auto-orient")
+            .arg("-resize")
+            .arg(format!("{width}x{height}>"))
+            .arg("-limit")
+            .arg("thread")
+            .arg(ctx.scheduler.job_threads().to_string());
+        push_path(&mut cmd, output)?;
+
+        let diagnostics = run(cmd, ctx.cancel, self.timeout)
+            .context(format!("magick failed to generate preview {output:?}"))?;
+
+        Ok(GeneratedPreview::file(diagnostics))
+    }
+}
+
+struct CommandGenerator {
+    name: String,
+    cmd: CommandLine,
+    timeout: Duration,
+}
+
+impl PreviewGenerator for CommandGenerator {
+    fn backend(&self) -> PreviewBackend {
+        PreviewBackend::Command(self.name.clone())
+    }
+
+    fn probe(&self) -> bool {
+        find_executable(&self.cmd.program).is_some()
+    }
+
+    fn generate(
+        &self,
+        img: &Image,
+        size: PreviewSize,
+        output: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<GeneratedPreview> {
+        let input = path_arg(&img.path)?;
+        let output_arg = path_arg(output)?;
+        let (width, height) = size.bounds();
+        let threads = ctx.scheduler.job_threads();
+
+        let mut cmd = command(&self.cmd.program, ctx.scheduler.low_priority());
+        cmd.args(self.cmd.args.iter().map(|arg| {
+            arg.replace("{input}", input)
+                .replace("{output}", output_arg)
+                .replace("{width}", &width.to_string())
+                .replace("{height}", &height.to_string())
+                .replace("{threads}", &threads.to_string())
+        }));
+
+        let diagnostics = run(cmd, ctx.cancel, self.timeout).context(format!(
+            "Command '{}' failed to generate preview {output:?}",
+            self.name
+        ))?;
+
+        Ok(GeneratedPreview::file(diagnostics))
+    }
+}
@@ -1,17 +1,31 @@
-use super::{read_cull_meta_or_default, Image, META_EXT};
+use super::{
+    analysis::AnalysisConfig,
+    cache::PreviewCache,
+    native::NATIVE_EXTENSIONS,
+    raw_preview::{self, RAW_EXTENSIONS},
+    read_cull_meta_or_default, Image, META_EXT,
+};
 use anyhow::anyhow;
 use std::path::{Path, PathBuf};
 use tokio_stream::StreamExt;
 
-pub(crate) async fn get_images(path: &Path) -> anyhow::Result<Vec<Image>> {
-    let paths = globwalk::GlobWalkerBuilder::from_patterns(path, &["*.arw"])
+/// Lists the raws and the other extensions with configured backends,
+/// or the natively supported imgs if there are none
+pub(crate) async fn get_images(
+    path: &Path,
+    extensions: &[String],
+    cache: Option<&PreviewCache>,
+    analysis: &AnalysisConfig,
+) -> anyhow::Result<Vec<Image>> {
+    let (patterns, fallback_patterns) = patterns(extensions);
+    let paths = globwalk::GlobWalkerBuilder::from_patterns(path, &patterns)
         .case_insensitive(true)
         .build()?
         .into_iter();
     let mut paths = paths.peekable();
     if paths.peek().is_none() {
         // no raw imgs, look for jpgs and pngs
-        paths = globwalk::GlobWalkerBuilder::from_patterns(path, &["*.jpg", "*.jpeg", "*.png"])
+        paths = globwalk::GlobWalkerBuilder::from_patterns(path, &fallback_patterns)
             .case_insensitive(true)
             .build()?
             .into_iter()
@@ -29,10 +43,15 @@ pub(crate) async fn get_images(path: &Path) -> anyhow::Result<Vec<Image>> {
         }
         let p = entry.into_path();
 
-        let preview_path = get_preview_path(&p).ok_or(anyhow!("Failed to get preview path"))?;
-        let (meta, cull_meta) = tokio::join!(
+        let preview_path = match cache {
+            Some(cache) => cache.preview_path(&p).await?,
+            None => get_preview_path(&p).ok_or(anyhow!("Failed to get preview path"))?,
+        };
+        let capture_path = p.clone();
+        let (meta, cull_meta, capture) = tokio::join!(
             tokio::fs::metadata(&p),
-            read_cull_meta_or_default(preview_path.with_extension(META_EXT))
+            read_cull_meta_or_default(preview_path.with_extension(META_EXT)),
+            tokio::task::spawn_blocking(move || raw_preview::read_capture(&capture_path))
         );
 
         let meta = meta?;
@@ -43,12 +62,36 @@ pub(crate) async fn get_images(path: &Path) -> anyhow::Result<Vec<Image>> {
             created: meta.created()?.min(meta.modified()?).into(),
             // todo: get serialized state - DB or maybe just a json/toml?
             state: cull_meta.cull_state,
+            preview_error: cull_meta.preview_error,
+            suggested_reject: analysis.suggests_reject(cull_meta.analysis.as_ref()),
+            blank: analysis.is_blank(cull_meta.analysis.as_ref()),
+            analysis: cull_meta.analysis,
+            capture: capture.ok().flatten(),
+            // needs the neighbouring imgs
+            sequence: None,
+            stack: cull_meta.stack,
         });
     }
 
     Ok(res)
 }
 
+/// Patterns of the raws & configured extensions and of the native ones,
+/// which are only listed without the former as they're usually shot alongside
+fn patterns(extensions: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut primary: Vec<String> = RAW_EXTENSIONS.map(str::to_owned).to_vec();
+    let mut fallback: Vec<String> = NATIVE_EXTENSIONS.map(str::to_owned).to_vec();
+    for ext in extensions {
+        let ext = ext.to_lowercase();
+        if !primary.contains(&ext) && !fallback.contains(&ext) {
+            primary.push(ext);
+        }
+    }
+
+    let to_patterns = |exts: Vec<String>| exts.into_iter().map(|ext| format!("*.{ext}")).collect();
+    (to_patterns(primary), to_patterns(fallback))
+}
+
 fn get_preview_path(path: &Path) -> Option<PathBuf> {
     let mut preview_path = path.with_extension("webp");
     let filename = preview_path.file_name()?.to_owned();
@@ -58,3 +101,35 @@ fn get_preview_path(path: &Path) -> Option<PathBuf> {
 
     Some(preview_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::{names, temp_dir};
+
+    async fn listed(dir: &Path, extensions: &[&str]) -> Vec<String> {
+        let extensions: Vec<String> = extensions.iter().map(|ext| (*ext).to_owned()).collect();
+        let imgs = get_images(dir, &extensions, None, &AnalysisConfig::default())
+            .await
+            .unwrap();
+        let mut names = names(imgs.iter().map(|img| &img.path));
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn configured_extensions() {
+        let dir = temp_dir("configured-extensions");
+        assert!(listed(&dir, &[]).await.is_empty());
+
+        for name in ["a.JPG", "b.png", "c.heic"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        assert_eq!(listed(&dir, &[]).await, ["a", "b"]);
+        assert_eq!(listed(&dir, &["HEIC"]).await, ["c"]);
+        // configuring a native extension doesn't list it with the raws
+        std::fs::write(dir.join("d.arw"), []).unwrap();
+        assert_eq!(listed(&dir, &["heic", "jpg"]).await, ["c", "d"]);
+        assert_eq!(listed(&dir, &[]).await, ["d"]);
+    }
+}
@@ -3,9 +3,31 @@ use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use ts_rs::TS;
 
+mod analysis;
+mod burst;
+mod cache;
+mod clipping;
+mod color;
 mod cull_meta;
+mod file;
+mod focus;
+mod generator;
 mod image;
+mod native;
+mod open_dir;
 mod preview;
+mod process;
+mod progress;
+mod quality;
+mod queue;
+mod ranking;
+mod raw_preview;
+mod scheduler;
+mod sequence;
+mod stack;
+#[cfg(test)]
+mod test_util;
+mod tiles;
 
 #[derive(Clone, Serialize, TS)]
 #[serde(rename_all = "camelCase")]
@@ -15,6 +37,20 @@ pub(crate) struct Image {
     pub(crate) preview_path: PathBuf,
     pub(crate) created: DateTime<Utc>,
     pub(crate) state: CullState,
+    /// Why the preview gen has failed
+    pub(crate) preview_error: Option<String>,
+    /// None until the preview has been analyzed
+    pub(crate) analysis: Option<ImageAnalysis>,
+    /// The analysis suggests the img is blurred, the state is left as it is until confirmed
+    pub(crate) suggested_reject: bool,
+    /// Nearly uniform, eg. a lens cap, pocket or blown flash shot
+    pub(crate) blank: bool,
+    pub(crate) capture: Option<Capture>,
+    /// Bracket, focus stack or panorama the img is a frame of
+    pub(crate) sequence: Option<Sequence>,
+    /// Stack edited by the user, the UI gets the stacks of the dir instead
+    #[serde(skip)]
+    pub(crate) stack: Option<StackMembership>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
@@ -27,6 +63,25 @@ pub(crate) enum CullState {
     Rejected,
 }
 
+pub(crate) use analysis::{exposure, group_similar, AnalysisConfig, Exposure, ImageAnalysis};
+pub(crate) use burst::{sort_by_capture, BurstConfig, Capture};
+pub(crate) use cache::{CacheConfig, CacheStats, PreviewCache};
 pub(crate) use cull_meta::*;
+pub(crate) use focus::create_focus_overlay;
+pub(crate) use generator::{PreviewBackendInfo, PreviewConfig, PreviewGenerators};
 pub(crate) use image::get_images;
-pub(crate) use preview::{process_previews, PreviewMap};
+pub(crate) use open_dir::OpenDir;
+pub(crate) use preview::{
+    process_previews, release_waiters, PreviewMap, PreviewPipeline, PreviewSize, PreviewStatus,
+};
+pub(crate) use progress::{
+    throttle_events, PreviewEvent, PreviewProgress, PreviewProgressReporter,
+    PREVIEW_PROGRESS_EVENT, PROGRESS_INTERVAL,
+};
+pub(crate) use quality::{sort_by_quality, ImageSort};
+pub(crate) use queue::{PreviewBatch, PreviewQueue};
+pub(crate) use ranking::{merge_groups, rank_groups, GroupRanking};
+pub(crate) use scheduler::{Scheduler, SchedulerConfig};
+pub(crate) use sequence::{tag_sequences, Sequence, SequenceConfig};
+pub(crate) use stack::{group_stacks, ImageStack, StackEditor, StackKind, StackMembership};
+pub(crate) use tiles::{TileInfo, TileRenderer};
@@ -0,0 +1,269 @@
+use anyhow::Context;
+use image::{imageops::FilterType, metadata::Orientation, DynamicImage, ImageDecoder, ImageReader};
+use std::{
+    io::Cursor,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    color::{ColorTarget, SourceColor},
+    generator::{GeneratedPreview, PreviewBackend, PreviewGenerator},
+    raw_preview,
+    scheduler::JobContext,
+    Image, PreviewSize,
+};
+
+const WEBP_QUALITY: f32 = 85.;
+
+/// Formats decoded by the image crate
+pub(crate) const NATIVE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Extensions which can be decoded without any external tool
+pub(crate) fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            NATIVE_EXTENSIONS
+                .iter()
+                .any(|supported| ext.eq_ignore_ascii_case(supported))
+        })
+}
+
+pub(crate) struct NativeGenerator {
+    pub(crate) color: Arc<ColorTarget>,
+}
+
+impl PreviewGenerator for NativeGenerator {
+    fn backend(&self) -> PreviewBackend {
+        PreviewBackend::Native
+    }
+
+    fn probe(&self) -> bool {
+        true
+    }
+
+    fn generate(
+        &self,
+        img: &Image,
+        size: PreviewSize,
+        _output: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<GeneratedPreview> {
+        let decoded = ctx.decoded.get_or_decode(self.backend(), || {
+            let data = ctx
+                .scheduler
+                .disk_read(|| std::fs::read(&img.path))
+                .context(format!("Failed to read {:?}", img.path))?;
+            decode_oriented(data, &img.path, None)
+        })?;
+        encode_preview(&decoded, size, &self.color, Vec::new())
+    }
+}
+
+/// Decoded img along with its colour space
+pub(crate) struct DecodedImage {
+    pub(crate) img: DynamicImage,
+    pub(crate) color: SourceColor,
+}
+
+/// Source decoded by a backend, kept for the other sizes of the img generated along
+#[derive(Default)]
+pub(crate) struct DecodedSource(Mutex<Option<(PreviewBackend, Arc<DecodedImage>)>>);
+
+impl DecodedSource {
+    pub(crate) fn get_or_decode(
+        &self,
+        backend: PreviewBackend,
+        decode: impl FnOnce() -> anyhow::Result<DecodedImage>,
+    ) -> anyhow::Result<Arc<DecodedImage>> {
+        let mut source = self.0.lock().expect("Decoded source lock is not poisoned");
+        if let Some((decoded_by, decoded)) = &*source {
+            if *decoded_by == backend {
+                return Ok(Arc::clone(decoded));
+            }
+        }
+
+        let decoded = Arc::new(decode()?);
+        *source = Some((backend, Arc::clone(&decoded)));
+        Ok(decoded)
+    }
+}
+
+/// Decodes the read img and applies the EXIF orientation unless overridden
+pub(crate) fn decode_oriented(
+    data: Vec<u8>,
+    path: &Path,
+    orientation: Option<Orientation>,
+) -> anyhow::Result<DecodedImage> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()
+        .context(format!("Unsupported img {path:?}"))?;
+    let orientation = match orientation {
+        Some(o) => o,
+        None => decoder.orientation()?,
+    };
+    // broken metadata shouldn't prevent showing the img
+    let color = SourceColor {
+        icc: decoder.icc_profile().ok().flatten(),
+        exif: decoder
+            .exif_metadata()
+            .ok()
+            .flatten()
+            .and_then(|exif| raw_preview::exif_color_space(&exif)),
+    };
+    let mut img =
+        DynamicImage::from_decoder(decoder).context(format!("Failed to decode {path:?}"))?;
+    img.apply_orientation(orientation);
+
+    Ok(DecodedImage { img, color })
+}
+
+/// Fits the img into the size, converts it to the target profile and encodes it
+pub(crate) fn encode_preview(
+    decoded: &DecodedImage,
+    size: PreviewSize,
+    color: &ColorTarget,
+    mut diagnostics: Vec<String>,
+) -> anyhow::Result<GeneratedPreview> {
+    let webp = encode_managed(
+        fit_preview(&decoded.img, size),
+        &decoded.color,
+        color,
+        &mut diagnostics,
+    )?;
+    Ok(GeneratedPreview::bytes(webp, diagnostics))
+}
+
+/// Converts the img to the target profile and encodes it as a WebP tagged with the profile
+pub(crate) fn encode_managed(
+    mut img: DynamicImage,
+    source: &SourceColor,
+    color: &ColorTarget,
+    diagnostics: &mut Vec<String>,
+) -> anyhow::Result<Vec<u8>> {
+    if let Err(e) = color.convert(&mut img, source) {
+        // an unmanaged preview is still better than none
+        diagnostics.push(format!("{e:#}"));
+    }
+
+    color.tag_webp(&encode_webp(&img)?, img.width(), img.height())
+}
+
+/// Shrinks the img to fit the size bounds, smaller imgs are kept as they are
+pub(crate) fn fit_preview(img: &DynamicImage, size: PreviewSize) -> DynamicImage {
+    let (max_width, max_height) = size.bounds();
+    if img.width() <= max_width && img.height() <= max_height {
+        img.clone()
+    } else if size == PreviewSize::Thumbnail {
+        // faster & good enough for tiny imgs
+        img.thumbnail(max_width, max_height)
+    } else {
+        img.resize(max_width, max_height, FilterType::Lanczos3)
+    }
+}
+
+fn encode_webp(img: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+    // the encoder only takes 8bit RGB(A)
+    let rgb;
+    let img = match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img,
+        _ if img.color().has_alpha() => {
+            rgb = DynamicImage::ImageRgba8(img.to_rgba8());
+            &rgb
+        }
+        _ => {
+            rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+            &rgb
+        }
+    };
+
+    Ok(webp::Encoder::from_image(img)
+        .map_err(|e| anyhow::anyhow!("Failed to encode preview: {e}"))?
+        .encode(WEBP_QUALITY)
+        .to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::jpeg;
+
+    /// Inserts an EXIF segment with just the orientation after the start of image marker
+    fn with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        // orientation tag, a short, a single value
+        tiff.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0]);
+        tiff.extend(orientation.to_le_bytes());
+        tiff.extend([0; 6]);
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff);
+        let len = u16::try_from(app1.len() + 2).unwrap();
+
+        let mut data = jpeg[..2].to_vec();
+        data.extend([0xff, 0xe1]);
+        data.extend(len.to_be_bytes());
+        data.extend(app1);
+        data.extend(&jpeg[2..]);
+        data
+    }
+
+    fn dimensions(img: &DynamicImage) -> (u32, u32) {
+        (img.width(), img.height())
+    }
+
+    #[test]
+    fn fits_preview_bounds() {
+        let img = |width, height| DynamicImage::new_rgb8(width, height);
+
+        let landscape = img(6000, 4000);
+        assert_eq!(
+            dimensions(&fit_preview(&landscape, PreviewSize::Thumbnail)),
+            (480, 320)
+        );
+        assert_eq!(
+            dimensions(&fit_preview(&landscape, PreviewSize::Preview)),
+            (2000, 1333)
+        );
+        assert_eq!(
+            dimensions(&fit_preview(&landscape, PreviewSize::Full)),
+            (6000, 4000)
+        );
+        // the height limits portraits
+        assert_eq!(
+            dimensions(&fit_preview(&img(2000, 3000), PreviewSize::Preview)),
+            (933, 1400)
+        );
+        // not upscaled
+        assert_eq!(
+            dimensions(&fit_preview(&img(300, 200), PreviewSize::Preview)),
+            (300, 200)
+        );
+    }
+
+    #[test]
+    fn applies_exif_orientation() {
+        let path = Path::new("a.jpg");
+        let data = jpeg(60, 40);
+        let decoded = |data: &[u8], orientation| {
+            dimensions(
+                &decode_oriented(data.to_vec(), path, orientation)
+                    .unwrap()
+                    .img,
+            )
+        };
+
+        assert_eq!(decoded(&data, None), (60, 40));
+        // rotated by 90° in the camera
+        let rotated = with_orientation(&data, 6);
+        assert_eq!(decoded(&rotated, None), (40, 60));
+        // the orientation of a raw overrides the one of its embedded JPEG
+        assert_eq!(decoded(&rotated, Some(Orientation::NoTransforms)), (60, 40));
+
+        // the gradient runs along the width, so the left column ends up at the top
+        let img = decode_oriented(rotated, path, None).unwrap().img.to_rgb8();
+        assert!(img.get_pixel(20, 0)[0] < img.get_pixel(20, 59)[0]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use super::Image;
+
+/// Imgs of the open dir in the capture order, kept in sync with their cull metas
+/// so the commands don't have to list and read the dir again
+#[derive(Default)]
+pub(crate) struct OpenDir(RwLock<Option<(PathBuf, Vec<Image>)>>);
+
+impl OpenDir {
+    pub(crate) fn open(&self, path: PathBuf, imgs: Vec<Image>) {
+        *self.write() = Some((path, imgs));
+    }
+
+    pub(crate) fn close(&self) {
+        *self.write() = None;
+    }
+
+    /// Path and a copy of the imgs
+    pub(crate) fn get(&self) -> Option<(PathBuf, Vec<Image>)> {
+        self.read().clone()
+    }
+
+    /// Updates the img with the preview, which is no longer there if the dir has changed
+    pub(crate) fn update(&self, preview_path: &Path, update: impl FnOnce(&mut Image)) {
+        if let Some((_, imgs)) = &mut *self.write() {
+            if let Some(img) = imgs.iter_mut().find(|img| img.preview_path == preview_path) {
+                update(img);
+            }
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Option<(PathBuf, Vec<Image>)>> {
+        self.0.read().expect("Open dir lock is not poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Option<(PathBuf, Vec<Image>)>> {
+        self.0.write().expect("Open dir lock is not poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{test_util::image, CullState};
+
+    #[test]
+    fn updates() {
+        let dir = OpenDir::default();
+        let imgs = vec![image(Path::new(""), "a"), image(Path::new(""), "b")];
+        let preview_path = imgs[1].preview_path.clone();
+
+        // nothing to update without a dir
+        dir.update(&preview_path, |img| img.state = CullState::Selected);
+        assert!(dir.get().is_none());
+
+        dir.open("dir".into(), imgs);
+        dir.update(&preview_path, |img| img.state = CullState::Selected);
+        dir.update(Path::new("other.webp"), |img| {
+            img.state = CullState::Rejected;
+        });
+        let (path, imgs) = dir.get().unwrap();
+        assert_eq!(path, Path::new("dir"));
+        let states: Vec<_> = imgs.iter().map(|img| img.state.clone()).collect();
+        assert_eq!(states, [CullState::New, CullState::Selected]);
+
+        dir.close();
+        assert!(dir.get().is_none());
+    }
+}
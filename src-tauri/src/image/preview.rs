@@ -1,129 +1,351 @@
-use anyhow::{anyhow, Context};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
     fs::create_dir_all,
-    os::windows::process::CommandExt,
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
     sync::Arc,
-    thread,
-    time::Duration,
 };
 use tokio::sync::Notify;
+use ts_rs::TS;
 
-use super::Image;
+use super::{
+    analysis::analyze_preview,
+    cache::PreviewCache,
+    clipping::{create_clipping_overlay, overlay_path},
+    file,
+    generator::{write_preview, PreviewGenerators},
+    native::DecodedSource,
+    open_dir::OpenDir,
+    progress::PreviewProgressReporter,
+    queue::{PreviewBatch, PreviewJob, PreviewQueue, PREVIEW_ATTEMPTS},
+    scheduler::{JobContext, Scheduler},
+    set_preview_error,
+    tiles::TileRenderer,
+    Image, ImageAnalysis, META_EXT,
+};
 
+/// Keyed by the path of a single preview size
 pub(crate) type PreviewMap =
-    Arc<tokio::sync::RwLock<HashMap<PathBuf, tokio::sync::RwLock<Option<Notify>>>>>;
+    Arc<tokio::sync::RwLock<HashMap<PathBuf, tokio::sync::RwLock<PreviewStatus>>>>;
 
-// https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#CREATE_NO_WINDOW
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+/// Handles shared by the preview workers, the preview API and the commands
+#[derive(Clone)]
+pub(crate) struct PreviewPipeline {
+    pub(crate) previews: PreviewMap,
+    pub(crate) open_dir: Arc<OpenDir>,
+    pub(crate) generators: Arc<PreviewGenerators>,
+    pub(crate) cache: Option<Arc<PreviewCache>>,
+    pub(crate) queue: Arc<PreviewQueue>,
+    pub(crate) progress: Arc<PreviewProgressReporter>,
+    pub(crate) scheduler: Arc<Scheduler>,
+    pub(crate) tiles: Arc<TileRenderer>,
+}
 
-pub(crate) fn create_preview(raw_img: &Image) -> anyhow::Result<()> {
-    if !raw_img.preview_path.exists() {
-        let dir = raw_img
-            .preview_path
-            .parent()
-            .ok_or(anyhow::anyhow!("Invalid raw img path: no parent"))?;
+pub(crate) enum PreviewStatus {
+    /// Requests wait for the notification of the gen finishing
+    Pending(Arc<Notify>),
+    Ready,
+    /// A placeholder is served instead
+    Failed(String),
+}
 
-        if !dir.exists() {
-            create_dir_all(dir)?;
+impl PreviewStatus {
+    /// Imgs which have failed before are not retried until asked to
+    pub(crate) fn new(path: &Path, preview_error: Option<&str>) -> Self {
+        if path.exists() {
+            PreviewStatus::Ready
+        } else if let Some(error) = preview_error {
+            PreviewStatus::Failed(error.to_owned())
+        } else {
+            PreviewStatus::Pending(Arc::default())
         }
+    }
 
-        let mut cmd = Command::new("magick");
-        #[cfg(target_os = "windows")]
-        {
-            cmd.creation_flags(CREATE_NO_WINDOW);
+    pub(crate) fn is_pending(&self) -> bool {
+        matches!(self, PreviewStatus::Pending(_))
+    }
+
+    /// Replaces the status and wakes up the requests waiting for the pending one
+    pub(crate) fn settle(&mut self, status: PreviewStatus) {
+        if let PreviewStatus::Pending(notify) = std::mem::replace(self, status) {
+            notify.notify_waiters();
         }
-        cmd.raw_arg(format!(
-            "\"{}\"",
-            raw_img
-                .path
-                .to_str()
-                .ok_or(anyhow!("Invalid raw img path {:?}", raw_img.path))?
-        ))
-        .arg("-auto-orient")
-        .arg("-resize")
-        .arg("2000x1400>")
-        .arg("-limit")
-        .arg("thread")
-        .arg(1.to_string())
-        .raw_arg(format!(
-            "\"{}\"",
-            raw_img
-                .preview_path
-                .to_str()
-                .ok_or(anyhow!("Invalid preview path"))?
-        ))
-        .status()
-        .context(format!(
-            "Failed to generate preview {:?}",
-            raw_img.preview_path
-        ))?;
     }
+}
 
-    Ok(())
+#[derive(
+    Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TS,
+)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum PreviewSize {
+    /// Filmstrip
+    Thumbnail,
+    /// Fits the screen
+    #[default]
+    Preview,
+    /// 1:1 for checking focus
+    Full,
+    /// Overlay of the clipped pixels of the original aligned with the preview
+    Clipping,
 }
 
-pub(crate) async fn process_previews(
-    mut input_rx: tokio::sync::mpsc::Receiver<VecDeque<Image>>,
-    previews: PreviewMap,
+impl PreviewSize {
+    /// Smaller sizes are generated first to make the overview usable asap
+    pub(crate) const ALL: [PreviewSize; 4] = [
+        PreviewSize::Thumbnail,
+        PreviewSize::Preview,
+        PreviewSize::Full,
+        PreviewSize::Clipping,
+    ];
+
+    /// Max width and height, smaller imgs are not upscaled
+    pub(crate) fn bounds(self) -> (u32, u32) {
+        match self {
+            PreviewSize::Thumbnail => (480, 320),
+            PreviewSize::Preview | PreviewSize::Clipping => (2000, 1400),
+            // max WebP dimensions
+            PreviewSize::Full => (16383, 16383),
+        }
+    }
+
+    /// Path of this size derived from the [`Image::preview_path`]
+    pub(crate) fn path(self, preview_path: &Path) -> PathBuf {
+        match self {
+            PreviewSize::Thumbnail => preview_path.with_extension("thumb.webp"),
+            PreviewSize::Preview => preview_path.to_owned(),
+            PreviewSize::Full => preview_path.with_extension("full.webp"),
+            PreviewSize::Clipping => overlay_path(preview_path),
+        }
+    }
+
+    /// Rendered from the whole original, so it's only prioritized when asked for explicitly
+    pub(crate) fn is_full_res(self) -> bool {
+        self == PreviewSize::Full
+    }
+}
+
+pub(crate) fn create_preview(
+    raw_img: &Image,
+    size: PreviewSize,
+    generators: &PreviewGenerators,
+    ctx: &JobContext,
+    attempt: usize,
 ) -> anyhow::Result<()> {
-    // leave 3 cores available
-    let thread_count = (num_cpus::get_physical() - 3).max(1);
-    let thread_pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(thread_count)
-        .build()?;
-
-    while let Some(mut imgs) = input_rx.recv().await {
-        if let Some(first) = imgs.pop_back() {
-            // also should just use a part of cores, not all to allow the PC to be usable
-            thread_pool.scope_fifo(|scope| {
-                // start with the last image which will be previewed at the start
-                imgs.push_front(first);
-
-                while let Some(img) = imgs.pop_front() {
-                    let previews = Arc::clone(&previews);
-                    scope.spawn_fifo(move |_| {
-                        let path: PathBuf = img.preview_path.clone();
-
-                        if !previews.blocking_read().contains_key(&path) {
-                            // the culled dir has changed
-                            // bail out early to stop the preview gen for the old dir
-                            return;
-                        }
-
-                        create_preview(&img).unwrap_or_else(|_| {
-                            panic!("Preview {:?} has failed to generate", &path)
-                        });
-
-                        if let Some(process_notification) = previews.blocking_read().get(&path) {
-                            // retry requiring the write guard to prevent deadlock if reads come
-                            // before getting the write guard but after notifying current readers
-                            loop {
-                                match process_notification.try_write() {
-                                    Ok(mut notify) => {
-                                        // mark as processed
-                                        notify.take();
-                                        break;
-                                    }
-                                    Err(_) => {
-                                        // notify ongoing readers the preview is ready
-                                        if let Some(notify) =
-                                            process_notification.blocking_read().as_ref()
-                                        {
-                                            notify.notify_waiters();
-                                            thread::sleep(Duration::from_millis(10));
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    });
-                }
+    let path = size.path(&raw_img.preview_path);
+    if path.exists() {
+        return Ok(());
+    }
+
+    let dir = path
+        .parent()
+        .ok_or(anyhow::anyhow!("Invalid raw img path: no parent"))?;
+
+    if !dir.exists() {
+        create_dir_all(dir)?;
+    }
+
+    if size == PreviewSize::Clipping {
+        // an overlay rather than a rendition of the img, so it's not up to the backends
+        return create_clipping_overlay(raw_img, &path, generators, ctx);
+    }
+
+    let mut generators = generators.for_path(&raw_img.path);
+    // retries start with a different backend
+    let rotation = attempt % generators.len().max(1);
+    generators.rotate_left(rotation);
+
+    let output = file::temp_path(&path);
+    let mut errors = Vec::new();
+    for generator in generators {
+        let res = generator.generate(raw_img, size, &output, ctx);
+        if res.is_err() {
+            // a failed or killed backend might have left a partial preview behind
+            let _ = std::fs::remove_file(&output);
+        }
+        match res {
+            Ok(preview) => return write_preview(&path, &output, preview),
+            Err(_) if ctx.cancel.is_cancelled() => bail!("Preview {path:?} has been cancelled"),
+            // try the next backend
+            Err(e) => errors.push(format!("{:?}: {e:#}", generator.backend())),
+        }
+    }
+
+    bail!(
+        "No preview backend could generate {path:?} [{}]",
+        errors.join(", ")
+    )
+}
+
+/// Blocks until the sender is dropped, so it has to run on a thread of its own
+pub(crate) fn process_previews(
+    mut input_rx: tokio::sync::mpsc::Receiver<PreviewBatch>,
+    pipeline: &PreviewPipeline,
+) -> anyhow::Result<()> {
+    let PreviewPipeline {
+        previews,
+        open_dir,
+        cache,
+        queue,
+        progress,
+        scheduler,
+        ..
+    } = pipeline;
+    let mut thread_pool = worker_pool()?;
+    while let Some(batch) = input_rx.blocking_recv() {
+        if batch.cancel.is_cancelled() {
+            // a newer dir has been opened in the meantime
+            continue;
+        }
+
+        // an empty batch only wakes up the workers for retried jobs
+        if !batch.imgs.is_empty() {
+            let previews = previews.blocking_read();
+            let queued = queue.reset(&batch, |path| {
+                previews
+                    .get(path)
+                    .and_then(|status| status.try_read().ok())
+                    .is_some_and(|status| status.is_pending())
             });
+            progress.queued(&batch, previews.len(), queued);
+        }
+
+        // an unprivileged thread can't always raise its priority back
+        if !scheduler.low_priority() && scheduler.take_lowered() {
+            thread_pool = worker_pool()?;
+        }
+        thread_pool.scope(|scope| {
+            // workers pull from the queue, so the viewer can reprioritize the remaining jobs
+            for _ in 0..thread_pool.current_num_threads() {
+                scope.spawn(|_| loop {
+                    let _permit = scheduler.acquire_worker();
+                    let Some(jobs) = queue.pop() else {
+                        break;
+                    };
+                    let decoded = DecodedSource::default();
+                    for job in jobs {
+                        process_job(job, &decoded, pipeline);
+                    }
+                });
+            }
+
+            // previews generated before the analysis has been added or changed
+            for img in batch.imgs.iter().filter(|img| {
+                !img.analysis.as_ref().is_some_and(ImageAnalysis::is_current)
+                    && img.preview_path.exists()
+            }) {
+                scope.spawn(|_| {
+                    let _permit = scheduler.acquire_worker();
+                    if batch.cancel.is_cancelled() {
+                        return;
+                    }
+                    analyze(img, open_dir);
+                });
+            }
+        });
+
+        if let Some(cache) = cache {
+            // previews of the open dir are still in use
+            let keep = previews.blocking_read().keys().cloned().collect();
+            if let Err(e) = cache.evict(&keep) {
+                println!("Preview cache eviction has failed: {e:#}");
+            }
         }
     }
 
     Ok(())
 }
+
+/// The scheduler decides how many of the workers run at once, so the limit can change mid batch
+fn worker_pool() -> anyhow::Result<rayon::ThreadPool> {
+    Ok(rayon::ThreadPoolBuilder::new()
+        .num_threads(Scheduler::max_threads())
+        .thread_name(|i| format!("preview-worker-{i}"))
+        .build()?)
+}
+
+/// Wakes up requests waiting for the previews of a dir which is being closed
+pub(crate) async fn release_waiters(previews: &PreviewMap) {
+    for status in previews.read().await.values() {
+        let mut status = status.write().await;
+        if status.is_pending() {
+            status.settle(PreviewStatus::Failed("The dir has been closed".to_owned()));
+        }
+    }
+}
+
+fn process_job(job: PreviewJob, decoded: &DecodedSource, pipeline: &PreviewPipeline) {
+    let PreviewPipeline {
+        previews,
+        open_dir,
+        generators,
+        queue,
+        progress,
+        scheduler,
+        ..
+    } = pipeline;
+    if job.cancel.is_cancelled() {
+        // the culled dir has changed
+        return;
+    }
+
+    progress.started(&job);
+    let ctx = JobContext {
+        cancel: &job.cancel,
+        scheduler,
+        decoded,
+    };
+    let status = match create_preview(&job.img, job.size, generators, &ctx, job.attempt) {
+        Ok(()) => {
+            progress.finished(&job);
+            PreviewStatus::Ready
+        }
+        Err(_) if job.cancel.is_cancelled() => return,
+        Err(e) if job.attempt + 1 < PREVIEW_ATTEMPTS => {
+            println!(
+                "Preview {:?} has failed to generate, retrying: {e:#}",
+                &job.path
+            );
+            progress.requeued(&job);
+            queue.requeue(job);
+            return;
+        }
+        Err(e) => {
+            let error = format!("{e:#}");
+            println!("Preview {:?} has failed to generate: {error}", &job.path);
+            // remember the failure, so the img is not retried on every open
+            // a missing overlay doesn't make the previews unusable though
+            if job.size != PreviewSize::Clipping {
+                if let Err(e) =
+                    set_preview_error(&job.img.preview_path.with_extension(META_EXT), Some(&error))
+                {
+                    println!("Failed to store the preview error: {e:#}");
+                }
+                open_dir.update(&job.img.preview_path, |img| {
+                    img.preview_error = Some(error.clone());
+                });
+            }
+            progress.failed(&job, error.clone());
+            PreviewStatus::Failed(error)
+        }
+    };
+
+    let ready = matches!(status, PreviewStatus::Ready);
+    if let Some(entry) = previews.blocking_read().get(&job.path) {
+        entry.blocking_write().settle(status);
+    }
+
+    // the waiting requests are served first
+    if ready && job.size == PreviewSize::Preview {
+        analyze(&job.img, open_dir);
+    }
+}
+
+/// Keeps the open dir up to date with the analysis stored in the cull meta
+fn analyze(img: &Image, open_dir: &OpenDir) {
+    match analyze_preview(img) {
+        Ok(analysis) => open_dir.update(&img.preview_path, |img| img.analysis = Some(analysis)),
+        Err(e) => println!("Failed to analyze {:?}: {e:#}", img.path),
+    }
+}
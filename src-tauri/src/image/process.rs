@@ -0,0 +1,255 @@
+use anyhow::bail;
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+use std::{
+    io::Read,
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::queue::CancelToken;
+
+// https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#CREATE_NO_WINDOW
+#[cfg(target_os = "windows")]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+// https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#BELOW_NORMAL_PRIORITY_CLASS
+#[cfg(target_os = "windows")]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const STDERR_TIMEOUT: Duration = Duration::from_secs(1);
+// the end of the output has the actual error usually
+const MAX_STDERR_LEN: usize = 4 * 1024;
+
+/// Low priority processes yield to the app and the rest of the PC
+pub(crate) fn command(program: &str, low_priority: bool) -> Command {
+    let mut cmd = Command::new(program);
+    #[cfg(target_os = "windows")]
+    {
+        let priority = if low_priority {
+            BELOW_NORMAL_PRIORITY_CLASS
+        } else {
+            0
+        };
+        cmd.creation_flags(CREATE_NO_WINDOW | priority);
+    }
+    #[cfg(unix)]
+    {
+        // own group to kill the delegates (eg. of magick) as well
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+    }
+    #[cfg(target_os = "macos")]
+    if low_priority {
+        // SAFETY: only an async-signal-safe syscall runs in the forked process
+        unsafe {
+            std::os::unix::process::CommandExt::pre_exec(&mut cmd, || {
+                libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG);
+                Ok(())
+            });
+        }
+    }
+    // linux processes inherit the nice and IO priority of the worker thread
+    #[cfg(target_os = "linux")]
+    let _ = low_priority;
+
+    cmd
+}
+
+/// Runs the command under supervision and turns a non-zero exit status into an error
+///
+/// The process is killed once it times out or the job is cancelled,
+/// the error contains the captured stderr
+pub(crate) fn run(
+    mut cmd: Command,
+    cancel: &CancelToken,
+    timeout: Duration,
+) -> anyhow::Result<Vec<String>> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // the job is closed on return, which kills whatever the process has left behind
+    #[cfg(target_os = "windows")]
+    let _job = windows_job::assign(&child)
+        .inspect_err(|e| println!("Failed to put {cmd:?} into a job object: {e}"));
+
+    // drain stderr on another thread, so the process can't block on a full pipe
+    let mut stderr_pipe = child.stderr.take().expect("Stderr is piped");
+    let (stderr_tx, stderr_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = stderr_pipe.read_to_end(&mut stderr);
+        let _ = stderr_tx.send(stderr);
+    });
+    let stderr = || {
+        // processes spawned by the killed one might keep the pipe open
+        let stderr = stderr_rx.recv_timeout(STDERR_TIMEOUT).unwrap_or_default();
+        let start = stderr.len().saturating_sub(MAX_STDERR_LEN);
+        String::from_utf8_lossy(&stderr[start..]).trim().to_owned()
+    };
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if cancel.is_cancelled() {
+            kill(&mut child);
+            bail!("Cancelled");
+        }
+
+        if started.elapsed() > timeout {
+            kill(&mut child);
+            bail!("Timed out after {}s ({})", timeout.as_secs(), stderr());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let stderr = stderr();
+    if !status.success() {
+        bail!("{status} ({stderr})");
+    }
+
+    Ok(if stderr.is_empty() {
+        Vec::new()
+    } else {
+        vec![stderr]
+    })
+}
+
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: plain syscall, the group is the child's own
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+
+    let _ = child.kill();
+    // reap it, so no zombie is left behind
+    let _ = child.wait();
+}
+
+/// Windows has no process groups, a job object kills the process tree once closed instead
+///
+/// Processes spawned before the child is assigned to the job escape it,
+/// the backends don't spawn any that early though
+#[cfg(target_os = "windows")]
+mod windows_job {
+    use std::{os::windows::io::AsRawHandle, process::Child};
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        },
+    };
+
+    pub(super) struct Job(HANDLE);
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            // SAFETY: the handle is owned by the job
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+
+    pub(super) fn assign(child: &Child) -> std::io::Result<Job> {
+        // SAFETY: an anonymous job with the default security
+        let handle = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if handle == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let job = Job(handle);
+
+        // SAFETY: the info is a plain struct which is valid when zeroed
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        let info_len = u32::try_from(std::mem::size_of_val(&info)).expect("The info is small");
+
+        // SAFETY: the job & the process handles are valid as long as the job & the child live
+        unsafe {
+            if SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                std::ptr::addr_of!(info).cast(),
+                info_len,
+            ) == 0
+                || AssignProcessToJobObject(job.0, child.as_raw_handle() as HANDLE) == 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(job)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = command("sh", false);
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn kills_process_tree_on_timeout() {
+        let started = Instant::now();
+        // the background sleep keeps the stderr pipe open if it survives
+        let result = run(
+            sh("sleep 10 & sleep 10"),
+            &CancelToken::default(),
+            Duration::from_millis(200),
+        );
+
+        assert!(result.unwrap_err().to_string().starts_with("Timed out"));
+        assert!(started.elapsed() < STDERR_TIMEOUT);
+    }
+
+    #[test]
+    fn kills_process_on_cancel() {
+        let cancel = CancelToken::default();
+        let started = Instant::now();
+        thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.cancel();
+            }
+        });
+
+        let result = run(sh("sleep 10"), &cancel, Duration::from_secs(10));
+        assert_eq!(result.unwrap_err().to_string(), "Cancelled");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn reports_stderr() {
+        let error = run(
+            sh("echo broken >&2; exit 3"),
+            &CancelToken::default(),
+            Duration::from_secs(10),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.ends_with("(broken)"), "{error}");
+
+        let warnings = run(
+            sh("echo careful >&2"),
+            &CancelToken::default(),
+            Duration::from_secs(10),
+        );
+        assert_eq!(warnings.unwrap(), ["careful"]);
+    }
+}
@@ -0,0 +1,325 @@
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use ts_rs::TS;
+
+use super::{
+    queue::{PreviewBatch, PreviewJob},
+    PreviewSize,
+};
+
+/// Name of the Tauri event the [`PreviewEvent`]s are emitted as
+pub(crate) const PREVIEW_PROGRESS_EVENT: &str = "preview-progress";
+/// Least time between progress events, the UI only shows the counts
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum PreviewEventKind {
+    /// The previews of a newly opened dir have been queued
+    Queued,
+    Started,
+    Finished,
+    Failed,
+}
+
+#[derive(Clone, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct PreviewEvent {
+    kind: PreviewEventKind,
+    /// Failed preview of a single size, none otherwise
+    path: Option<PathBuf>,
+    size: Option<PreviewSize>,
+    error: Option<String>,
+    progress: PreviewProgress,
+}
+
+/// Preview generation counts of the open dir, one preview per img and size
+#[derive(Clone, Default, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct PreviewProgress {
+    dir: Option<PathBuf>,
+    total: usize,
+    queued: usize,
+    running: usize,
+    /// Including the previews which have already existed
+    done: usize,
+    failed: usize,
+    /// Estimated from the throughput of the dir so far
+    eta_secs: Option<u32>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    progress: PreviewProgress,
+    started: Option<Instant>,
+    /// Generated since the start, the already existing previews would skew the ETA
+    generated: usize,
+}
+
+/// Tracks the progress of the open dir and reports every change, see [`throttle_events`]
+pub(crate) struct PreviewProgressReporter {
+    state: Mutex<ProgressState>,
+    events_tx: UnboundedSender<PreviewEvent>,
+}
+
+impl PreviewProgressReporter {
+    pub(crate) fn new(events_tx: UnboundedSender<PreviewEvent>) -> Self {
+        Self {
+            state: Mutex::default(),
+            events_tx,
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> PreviewProgress {
+        self.state
+            .lock()
+            .expect("Progress lock is not poisoned")
+            .progress
+            .clone()
+    }
+
+    pub(crate) fn queued(&self, batch: &PreviewBatch, total: usize, queued: usize) {
+        let mut state = self.state.lock().expect("Progress lock is not poisoned");
+        if batch.cancel.is_cancelled() {
+            return;
+        }
+
+        *state = ProgressState {
+            progress: PreviewProgress {
+                dir: Some(batch.dir.clone()),
+                total,
+                queued,
+                done: total.saturating_sub(queued),
+                ..Default::default()
+            },
+            started: Some(Instant::now()),
+            generated: 0,
+        };
+
+        self.report(&state, PreviewEventKind::Queued, None, None);
+    }
+
+    pub(crate) fn started(&self, job: &PreviewJob) {
+        self.update(job, PreviewEventKind::Started, None, |progress| {
+            progress.queued = progress.queued.saturating_sub(1);
+            progress.running += 1;
+        });
+    }
+
+    /// The failed job is going to be retried
+    pub(crate) fn requeued(&self, job: &PreviewJob) {
+        self.update(job, PreviewEventKind::Queued, None, |progress| {
+            progress.running = progress.running.saturating_sub(1);
+            progress.queued += 1;
+        });
+    }
+
+    /// Failed previews have been queued again by the user
+    pub(crate) fn retried(&self, count: usize) {
+        let mut state = self.state.lock().expect("Progress lock is not poisoned");
+        state.progress.failed = state.progress.failed.saturating_sub(count);
+        state.progress.queued += count;
+
+        self.report(&state, PreviewEventKind::Queued, None, None);
+    }
+
+    pub(crate) fn finished(&self, job: &PreviewJob) {
+        self.update(job, PreviewEventKind::Finished, None, |progress| {
+            progress.running = progress.running.saturating_sub(1);
+            progress.done += 1;
+        });
+    }
+
+    pub(crate) fn failed(&self, job: &PreviewJob, error: String) {
+        self.update(job, PreviewEventKind::Failed, Some(error), |progress| {
+            progress.running = progress.running.saturating_sub(1);
+            progress.failed += 1;
+        });
+    }
+
+    fn update(
+        &self,
+        job: &PreviewJob,
+        kind: PreviewEventKind,
+        error: Option<String>,
+        update: impl FnOnce(&mut PreviewProgress),
+    ) {
+        let mut state = self.state.lock().expect("Progress lock is not poisoned");
+        if job.cancel.is_cancelled() {
+            // the counts belong to a newer dir already
+            return;
+        }
+
+        update(&mut state.progress);
+        if matches!(kind, PreviewEventKind::Finished | PreviewEventKind::Failed) {
+            state.generated += 1;
+            state.progress.eta_secs = state
+                .eta()
+                .map(|eta| u32::try_from(eta.as_secs()).unwrap_or(u32::MAX));
+        }
+
+        // the rest only changes the counts
+        let job = (kind == PreviewEventKind::Failed).then_some(job);
+        self.report(&state, kind, job, error);
+    }
+
+    fn report(
+        &self,
+        state: &ProgressState,
+        kind: PreviewEventKind,
+        job: Option<&PreviewJob>,
+        error: Option<String>,
+    ) {
+        // nobody listens anymore when the app is closing
+        let _ = self.events_tx.send(PreviewEvent {
+            kind,
+            path: job.map(|job| job.path.clone()),
+            size: job.map(|job| job.size),
+            error,
+            progress: state.progress.clone(),
+        });
+    }
+}
+
+impl ProgressState {
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.progress.queued + self.progress.running;
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+
+        let elapsed = self.started?.elapsed();
+        let per_preview = elapsed.checked_div(u32::try_from(self.generated).ok()?)?;
+        per_preview.checked_mul(u32::try_from(remaining).ok()?)
+    }
+}
+
+/// Passes failures on right away and coalesces the other events into the latest one per interval,
+/// as a dir of thousands of previews would flood the webview otherwise
+pub(crate) async fn throttle_events(
+    mut events_rx: UnboundedReceiver<PreviewEvent>,
+    interval: Duration,
+    mut emit: impl FnMut(PreviewEvent),
+) {
+    let mut pending: Option<PreviewEvent> = None;
+    let mut next_emit = tokio::time::Instant::now();
+    loop {
+        let event = if pending.is_some() {
+            if let Ok(event) = tokio::time::timeout_at(next_emit, events_rx.recv()).await {
+                event
+            } else {
+                // nothing newer within the interval
+                emit(pending.take().expect("An event is pending"));
+                next_emit = tokio::time::Instant::now() + interval;
+                continue;
+            }
+        } else {
+            events_rx.recv().await
+        };
+        let Some(event) = event else {
+            break;
+        };
+
+        if event.kind == PreviewEventKind::Failed || tokio::time::Instant::now() >= next_emit {
+            pending = None;
+            emit(event);
+            next_emit = tokio::time::Instant::now() + interval;
+        } else {
+            pending = Some(event);
+        }
+    }
+
+    if let Some(event) = pending {
+        emit(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{queue::CancelToken, test_util::image};
+    use std::path::Path;
+    use tokio::sync::mpsc;
+
+    fn job(name: &str) -> PreviewJob {
+        PreviewJob::new(image(Path::new("dir"), name), PreviewSize::Preview)
+    }
+
+    #[test]
+    fn eta_from_throughput() {
+        let mut state = ProgressState {
+            progress: PreviewProgress {
+                queued: 8,
+                running: 2,
+                ..Default::default()
+            },
+            started: Instant::now().checked_sub(Duration::from_secs(10)),
+            generated: 0,
+        };
+        // nothing to extrapolate from yet
+        assert_eq!(state.eta(), None);
+
+        state.generated = 5;
+        let eta = state.eta().unwrap();
+        assert!(eta >= Duration::from_secs(20) && eta < Duration::from_secs(21));
+
+        state.progress.queued = 0;
+        state.progress.running = 0;
+        assert_eq!(state.eta(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn events_are_throttled() {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let reporter = PreviewProgressReporter::new(events_tx);
+        let batch = PreviewBatch {
+            dir: PathBuf::from("dir"),
+            imgs: Vec::new(),
+            cancel: CancelToken::default(),
+        };
+        reporter.queued(&batch, 1000, 1000);
+        for i in 0..1000 {
+            let job = job(&i.to_string());
+            reporter.started(&job);
+            if i == 500 {
+                reporter.failed(&job, "broken".to_owned());
+            } else {
+                reporter.finished(&job);
+            }
+        }
+        drop(reporter);
+
+        let mut events = Vec::new();
+        throttle_events(events_rx, Duration::from_secs(10), |event| {
+            events.push(event);
+        })
+        .await;
+
+        let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                PreviewEventKind::Queued,
+                PreviewEventKind::Failed,
+                PreviewEventKind::Finished
+            ]
+        );
+        // only failures name their preview
+        assert_eq!(events[1].path, Some(PathBuf::from("dir/500.webp")));
+        assert_eq!(events[2].path, None);
+        // the last event has the final counts
+        let progress = &events[2].progress;
+        assert_eq!(
+            (progress.done, progress.failed, progress.running),
+            (999, 1, 0)
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use ts_rs::TS;
+
+use super::{analysis::ImageAnalysis, Image};
+
+/// Sharpness scored 0.5, around the default blur threshold
+const SHARPNESS_KNEE: f32 = 100.;
+/// Standard deviation of the noise in 8bit levels at which the noise score drops to 0
+const MAX_NOISE: f32 = 10.;
+/// % of clipped pixels at which the exposure score drops to 0
+const MAX_CLIPPING: f32 = 10.;
+const SHARPNESS_WEIGHT: f32 = 0.4;
+const NOISE_WEIGHT: f32 = 0.2;
+const EXPOSURE_WEIGHT: f32 = 0.2;
+const MOTION_WEIGHT: f32 = 0.2;
+
+/// Order of the imgs of an opened dir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum ImageSort {
+    #[default]
+    Capture,
+    /// The weakest imgs first
+    Quality,
+}
+
+/// Technical quality of the img between 0 and 1 regardless of the other imgs
+pub(super) fn quality_score(analysis: &ImageAnalysis) -> f32 {
+    let sharpness = analysis.sharpness / (analysis.sharpness + SHARPNESS_KNEE).max(f32::EPSILON);
+    let noise = 1. - (analysis.noise / MAX_NOISE).min(1.);
+    let motion = 1. - analysis.motion_blur.clamp(0., 1.);
+
+    sharpness * SHARPNESS_WEIGHT
+        + noise * NOISE_WEIGHT
+        + exposure_score(analysis) * EXPOSURE_WEIGHT
+        + motion * MOTION_WEIGHT
+}
+
+/// 1 without any clipping down to 0 at `MAX_CLIPPING`
+pub(super) fn exposure_score(analysis: &ImageAnalysis) -> f32 {
+    let clipping = analysis.clipped_highlights + analysis.crushed_shadows;
+    1. - (clipping / MAX_CLIPPING).min(1.)
+}
+
+/// The weakest scenes first, a stack is rated by its best frame and keeps its frames together
+/// in the capture order
+///
+/// Imgs and stacks without a current analysis are left at the end in their order
+pub(crate) fn sort_by_quality(imgs: &mut Vec<Image>, stacks: &[Vec<usize>]) {
+    let quality = |img: &Image| {
+        img.analysis
+            .as_ref()
+            .filter(|analysis| analysis.is_current())
+            .map(|analysis| analysis.quality)
+    };
+
+    // stacks take the place of their first frame
+    let mut stack_of = vec![None; imgs.len()];
+    for (s, stack) in stacks.iter().enumerate() {
+        for i in stack {
+            stack_of[*i] = Some(s);
+        }
+    }
+    let mut scenes: Vec<(Option<f32>, Vec<usize>)> = Vec::new();
+    for (i, stack) in stack_of.into_iter().enumerate() {
+        let scene = match stack {
+            Some(s) if stacks[s][0] == i => stacks[s].clone(),
+            Some(_) => continue,
+            None => vec![i],
+        };
+        let best = scene
+            .iter()
+            .filter_map(|i| quality(&imgs[*i]))
+            .max_by(f32::total_cmp);
+        scenes.push((best, scene));
+    }
+
+    scenes.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+
+    let mut unsorted: Vec<Option<Image>> = std::mem::take(imgs).into_iter().map(Some).collect();
+    imgs.extend(
+        scenes
+            .into_iter()
+            .flat_map(|(_, scene)| scene)
+            .map(|i| unsorted[i].take().expect("Every img is in a single scene")),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::{analyzed, image, names};
+    use std::path::Path;
+
+    /// Imgs named after their position, the ones without a quality are not analyzed
+    fn rated(qualities: &[Option<f32>]) -> Vec<Image> {
+        qualities
+            .iter()
+            .enumerate()
+            .map(|(i, quality)| match quality {
+                Some(quality) => analyzed(&i.to_string(), |analysis| analysis.quality = *quality),
+                None => image(Path::new(""), &i.to_string()),
+            })
+            .collect()
+    }
+
+    fn sorted(qualities: &[Option<f32>], stacks: &[Vec<usize>]) -> Vec<String> {
+        let mut imgs = rated(qualities);
+        sort_by_quality(&mut imgs, stacks);
+        names(imgs.iter().map(|img| &img.preview_path))
+    }
+
+    #[test]
+    fn weakest_first() {
+        let qualities = [Some(0.5), Some(0.2), None, Some(0.8), None];
+        assert_eq!(sorted(&qualities, &[]), ["1", "0", "3", "2", "4"]);
+        assert!(sorted(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn stacks_by_their_best_frame() {
+        let qualities = [
+            Some(0.9),
+            Some(0.3),
+            Some(0.5),
+            Some(0.1),
+            Some(0.6),
+            None,
+            None,
+            Some(0.4),
+        ];
+        // 2 and 7 stay together and the unanalyzed stack is left at the end
+        let stacks = [vec![0, 1], vec![3, 4], vec![5, 6], vec![2, 7]];
+        assert_eq!(
+            sorted(&qualities, &stacks),
+            ["2", "7", "3", "4", "0", "1", "5", "6"]
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use anyhow::anyhow;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{CullState, Image, PreviewSize};
+
+// imgs around the focused one get all their sizes before the rest of the dir
+const FOCUS_WINDOW: usize = 3;
+/// Requested imgs and the ones in the focus window, which are about to be viewed
+const VIEWED_TIER: u8 = 1;
+/// A failed job is retried once, starting with the next backend
+pub(crate) const PREVIEW_ATTEMPTS: usize = 2;
+
+/// Shared by all jobs of a batch, running backends poll it to abort early
+#[derive(Clone, Default)]
+pub(crate) struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub(crate) fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Imgs of an opened dir to generate the previews for
+///
+/// An empty batch just wakes up the workers for the jobs queued by [`PreviewQueue::retry`]
+pub(crate) struct PreviewBatch {
+    pub(crate) dir: PathBuf,
+    pub(crate) imgs: Vec<Image>,
+    pub(crate) cancel: CancelToken,
+}
+
+pub(crate) struct PreviewJob {
+    pub(crate) img: Image,
+    pub(crate) size: PreviewSize,
+    /// Path of the size
+    pub(crate) path: PathBuf,
+    pub(crate) cancel: CancelToken,
+    pub(crate) attempt: usize,
+    index: usize,
+}
+
+#[cfg(test)]
+impl PreviewJob {
+    /// Job outside of any batch
+    pub(super) fn new(img: Image, size: PreviewSize) -> Self {
+        Self {
+            path: size.path(&img.preview_path),
+            img,
+            size,
+            cancel: CancelToken::default(),
+            attempt: 0,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Pending jobs of every size by the index of their img
+    jobs: BTreeMap<PreviewSize, BTreeMap<usize, PreviewJob>>,
+    /// Imgs of the current batch
+    imgs: Vec<Image>,
+    /// Size and img index of the paths of every size of the batch
+    paths: HashMap<PathBuf, (PreviewSize, usize)>,
+    cancel: CancelToken,
+    focus: usize,
+    /// Indices of imgs asked for by the viewer, the most recent last
+    requested: Vec<usize>,
+    /// Full res sizes are only prioritized when asked for explicitly
+    requested_full: HashSet<usize>,
+}
+
+/// Pending preview jobs of the open dir ordered by what the viewer needs next
+#[derive(Default)]
+pub(crate) struct PreviewQueue {
+    state: Mutex<QueueState>,
+}
+
+impl PreviewQueue {
+    /// Cancels the current batch including its running jobs and starts a new one
+    pub(crate) fn start_batch(&self, dir: PathBuf, imgs: Vec<Image>) -> PreviewBatch {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        state.cancel.cancel();
+        state.cancel = CancelToken::default();
+        state.jobs.clear();
+        state.imgs.clone_from(&imgs);
+        state.paths = imgs
+            .iter()
+            .enumerate()
+            .flat_map(|(index, img)| {
+                PreviewSize::ALL
+                    .into_iter()
+                    .map(move |size| (size.path(&img.preview_path), (size, index)))
+            })
+            .collect();
+
+        PreviewBatch {
+            dir,
+            imgs,
+            cancel: state.cancel.clone(),
+        }
+    }
+
+    /// Queues the jobs of the batch, the focus starts at the first img which has not been culled yet
+    ///
+    /// Returns the number of queued jobs
+    pub(crate) fn reset(&self, batch: &PreviewBatch, pending: impl Fn(&Path) -> bool) -> usize {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        let imgs = &batch.imgs;
+
+        state.focus = imgs
+            .iter()
+            .position(|img| img.state == CullState::New)
+            .unwrap_or_default();
+        state.requested.clear();
+        state.requested_full.clear();
+        state.jobs = PreviewSize::ALL
+            .into_iter()
+            .map(|size| {
+                let jobs = imgs
+                    .iter()
+                    .enumerate()
+                    .map(|(index, img)| PreviewJob {
+                        path: size.path(&img.preview_path),
+                        img: img.clone(),
+                        size,
+                        cancel: batch.cancel.clone(),
+                        attempt: 0,
+                        index,
+                    })
+                    .filter(|job| pending(&job.path))
+                    .map(|job| (job.index, job))
+                    .collect();
+                (size, jobs)
+            })
+            .collect();
+
+        state.jobs.values().map(BTreeMap::len).sum()
+    }
+
+    /// Queues the failed job again
+    pub(crate) fn requeue(&self, job: PreviewJob) {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        if !job.cancel.is_cancelled() {
+            state.push(PreviewJob {
+                attempt: job.attempt + 1,
+                ..job
+            });
+        }
+    }
+
+    /// Queues the given sizes of an img of the current batch with all attempts available again
+    pub(crate) fn retry(
+        &self,
+        preview_path: &Path,
+        sizes: &[PreviewSize],
+    ) -> anyhow::Result<PreviewBatch> {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        let (index, img) = state
+            .img(preview_path)
+            .ok_or(anyhow!("{preview_path:?} is not in the open dir"))?;
+
+        let cancel = state.cancel.clone();
+        for size in sizes {
+            if !state.is_pending(*size, index) {
+                state.push(PreviewJob {
+                    path: size.path(preview_path),
+                    img: img.clone(),
+                    size: *size,
+                    cancel: cancel.clone(),
+                    attempt: 0,
+                    index,
+                });
+            }
+        }
+
+        // the viewer is waiting for it
+        state.requested.retain(|i| *i != index);
+        state.requested.push(index);
+
+        Ok(PreviewBatch {
+            dir: PathBuf::new(),
+            imgs: Vec::new(),
+            cancel,
+        })
+    }
+
+    /// Img of the open dir with the preview
+    pub(crate) fn img(&self, preview_path: &Path) -> Option<Image> {
+        self.state
+            .lock()
+            .expect("Queue lock is not poisoned")
+            .img(preview_path)
+            .map(|(_, img)| img)
+    }
+
+    pub(crate) fn set_focus(&self, index: usize) {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        state.focus = index;
+        // the viewer has moved on
+        state.requested.clear();
+        state.requested_full.clear();
+    }
+
+    /// Moves the imgs to the front of the queue
+    pub(crate) fn prefetch(&self, indices: &[usize]) {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        for index in indices {
+            state.requested.retain(|i| i != index);
+            state.requested.push(*index);
+        }
+    }
+
+    /// Moves the img of a requested preview to the front of the queue
+    pub(crate) fn prioritize(&self, path: &Path) {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        let Some((size, index)) = state.paths.get(path).copied() else {
+            return;
+        };
+        if !state.is_pending(size, index) {
+            return;
+        }
+
+        state.requested.retain(|i| *i != index);
+        state.requested.push(index);
+        if size.is_full_res() {
+            state.requested_full.insert(index);
+        }
+    }
+
+    /// Takes the next job, the imgs about to be viewed along with their other pending sizes
+    /// so the source is decoded once
+    ///
+    /// The rest of the dir goes size by size, so every img has a thumbnail before any preview.
+    /// Full res sizes only go along with each other as they'd hold up the worker
+    pub(crate) fn pop(&self) -> Option<Vec<PreviewJob>> {
+        let mut state = self.state.lock().expect("Queue lock is not poisoned");
+        let (size, index, tier) = state.next()?;
+        let mut jobs: Vec<PreviewJob> = state.take(size, index).into_iter().collect();
+
+        if tier <= VIEWED_TIER {
+            let siblings: Vec<PreviewSize> = state
+                .jobs
+                .keys()
+                .filter(|other| **other != size && other.is_full_res() == size.is_full_res())
+                .copied()
+                .collect();
+            jobs.extend(
+                siblings
+                    .into_iter()
+                    .filter_map(|sibling| state.take(sibling, index)),
+            );
+        }
+
+        Some(jobs)
+    }
+}
+
+impl QueueState {
+    fn img(&self, preview_path: &Path) -> Option<(usize, Image)> {
+        match self.paths.get(preview_path) {
+            Some((PreviewSize::Preview, index)) => Some((*index, self.imgs[*index].clone())),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, job: PreviewJob) {
+        self.jobs
+            .entry(job.size)
+            .or_default()
+            .insert(job.index, job);
+    }
+
+    fn take(&mut self, size: PreviewSize, index: usize) -> Option<PreviewJob> {
+        self.jobs.get_mut(&size)?.remove(&index)
+    }
+
+    fn is_pending(&self, size: PreviewSize, index: usize) -> bool {
+        self.jobs
+            .get(&size)
+            .is_some_and(|jobs| jobs.contains_key(&index))
+    }
+
+    /// Size, img index and tier of the job with the highest priority
+    ///
+    /// The priority only drops with the distance to the focus, so besides the requested imgs
+    /// only the nearest imgs either side of the focus are candidates
+    fn next(&self) -> Option<(PreviewSize, usize, u8)> {
+        self.jobs
+            .values()
+            .flat_map(|jobs| {
+                let nearest = jobs
+                    .range(self.focus..)
+                    .next()
+                    .into_iter()
+                    .chain(jobs.range(..self.focus).next_back())
+                    .map(|(_, job)| job);
+                let requested = self.requested.iter().filter_map(|i| jobs.get(i));
+                nearest.chain(requested)
+            })
+            .map(|job| (self.priority(job), job))
+            .min_by_key(|(priority, _)| *priority)
+            .map(|((tier, ..), job)| (job.size, job.index, tier))
+    }
+
+    /// Lower goes first
+    fn priority(&self, job: &PreviewJob) -> (u8, usize, bool, PreviewSize) {
+        if !job.size.is_full_res() || self.requested_full.contains(&job.index) {
+            if let Some(pos) = self.requested.iter().position(|i| *i == job.index) {
+                // most recent requests first
+                return (0, self.requested.len() - pos, false, job.size);
+            }
+        }
+
+        // culling goes forward, so the following imgs are more important than the previous ones
+        let distance = if job.index >= self.focus {
+            job.index - self.focus
+        } else {
+            (self.focus - job.index) * 2
+        };
+
+        let tier = match job.size {
+            _ if distance <= FOCUS_WINDOW && !job.size.is_full_res() => VIEWED_TIER,
+            PreviewSize::Thumbnail => 2,
+            PreviewSize::Preview => 3,
+            PreviewSize::Clipping => 4,
+            PreviewSize::Full => 5,
+        };
+
+        // the following img wins a tie
+        (tier, distance, job.index < self.focus, job.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::{image, temp_dir};
+
+    fn queue(imgs: usize) -> (PreviewQueue, Vec<Image>) {
+        let dir = temp_dir("queue");
+        let imgs: Vec<_> = (0..imgs).map(|i| image(&dir, &i.to_string())).collect();
+        let queue = PreviewQueue::default();
+        let batch = queue.start_batch(dir, imgs.clone());
+        queue.reset(&batch, |_| true);
+        (queue, imgs)
+    }
+
+    /// Indices of the popped imgs along with the sizes generated together
+    fn pop(queue: &PreviewQueue) -> Option<(usize, Vec<PreviewSize>)> {
+        let jobs = queue.pop()?;
+        assert!(jobs.iter().all(|job| job.index == jobs[0].index));
+        Some((jobs[0].index, jobs.iter().map(|job| job.size).collect()))
+    }
+
+    fn pop_all(queue: &PreviewQueue) -> Vec<(usize, Vec<PreviewSize>)> {
+        std::iter::from_fn(|| pop(queue)).collect()
+    }
+
+    const SCALED: [PreviewSize; 3] = [
+        PreviewSize::Thumbnail,
+        PreviewSize::Preview,
+        PreviewSize::Clipping,
+    ];
+    const THUMBNAIL: [PreviewSize; 1] = [PreviewSize::Thumbnail];
+    const PREVIEW: [PreviewSize; 1] = [PreviewSize::Preview];
+    const CLIPPING: [PreviewSize; 1] = [PreviewSize::Clipping];
+    const FULL_RES: [PreviewSize; 1] = [PreviewSize::Full];
+
+    #[test]
+    fn thumbnails_first() {
+        let (queue, _) = queue(10);
+        let order = pop_all(&queue);
+        // the focus window gets all its sizes at once, the rest of the dir size by size
+        let expected: Vec<_> = (0..4)
+            .map(|i| (i, SCALED.to_vec()))
+            .chain((4..10).map(|i| (i, THUMBNAIL.to_vec())))
+            .chain((4..10).map(|i| (i, PREVIEW.to_vec())))
+            .chain((4..10).map(|i| (i, CLIPPING.to_vec())))
+            .chain((0..10).map(|i| (i, FULL_RES.to_vec())))
+            .collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn following_imgs_first() {
+        let (queue, mut imgs) = queue(9);
+        queue.set_focus(4);
+        let order: Vec<_> = pop_all(&queue).into_iter().map(|(i, _)| i).collect();
+        // the ones behind count twice, ties go to the following img
+        assert_eq!(&order[..5], [4, 5, 6, 3, 7]);
+        assert_eq!(&order[5..9], [8, 2, 1, 0]);
+        assert_eq!(&order[9..13], [8, 2, 1, 0]);
+        assert_eq!(&order[13..17], [8, 2, 1, 0]);
+        assert_eq!(&order[17..], [4, 5, 6, 3, 7, 8, 2, 1, 0]);
+
+        // the focus starts at the first img to cull
+        imgs[0].state = CullState::Selected;
+        imgs[1].state = CullState::Rejected;
+        let batch = queue.start_batch(PathBuf::new(), imgs);
+        queue.reset(&batch, |_| true);
+        assert_eq!(pop(&queue).unwrap().0, 2);
+    }
+
+    #[test]
+    fn requested_first() {
+        let (queue, imgs) = queue(8);
+        queue.prioritize(&PreviewSize::Preview.path(&imgs[6].preview_path));
+        queue.prioritize(&PreviewSize::Thumbnail.path(&imgs[7].preview_path));
+        queue.prefetch(&[5]);
+        // full res sizes only when asked for, after the scaled ones
+        queue.prioritize(&PreviewSize::Full.path(&imgs[2].preview_path));
+
+        assert_eq!(pop(&queue), Some((2, SCALED.to_vec())));
+        assert_eq!(pop(&queue), Some((2, FULL_RES.to_vec())));
+        assert_eq!(pop(&queue), Some((5, SCALED.to_vec())));
+        assert_eq!(pop(&queue), Some((7, SCALED.to_vec())));
+        assert_eq!(pop(&queue), Some((6, SCALED.to_vec())));
+        assert_eq!(pop(&queue), Some((0, SCALED.to_vec())));
+
+        // not in the queue anymore
+        queue.prioritize(&PreviewSize::Preview.path(&imgs[6].preview_path));
+        queue.set_focus(7);
+        queue.prefetch(&[4]);
+        assert_eq!(pop(&queue), Some((4, SCALED.to_vec())));
+        assert_eq!(pop(&queue), Some((3, THUMBNAIL.to_vec())));
+        assert_eq!(pop(&queue), Some((1, THUMBNAIL.to_vec())));
+        assert_eq!(pop(&queue), Some((3, PREVIEW.to_vec())));
+        assert_eq!(pop(&queue), Some((1, PREVIEW.to_vec())));
+        assert_eq!(pop(&queue), Some((3, CLIPPING.to_vec())));
+        assert_eq!(pop(&queue), Some((1, CLIPPING.to_vec())));
+        assert_eq!(pop(&queue), Some((7, FULL_RES.to_vec())));
+    }
+
+    #[test]
+    fn requeue_and_retry() {
+        let (queue, imgs) = queue(2);
+        let mut jobs = queue.pop().unwrap();
+        let thumbnail = jobs.remove(0);
+        queue.requeue(thumbnail);
+        let retried = queue.pop().unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(
+            (retried[0].size, retried[0].attempt),
+            (PreviewSize::Thumbnail, 1)
+        );
+
+        // a job which has been queued already isn't doubled
+        queue
+            .retry(&imgs[1].preview_path, &[PreviewSize::Preview])
+            .unwrap();
+        assert_eq!(pop(&queue), Some((1, SCALED.to_vec())));
+        assert!(queue.retry(Path::new("missing.webp"), &SCALED).is_err());
+
+        // a new dir cancels the jobs of the old one
+        let old = queue.pop().unwrap().remove(0);
+        queue.start_batch(PathBuf::new(), Vec::new());
+        assert!(old.cancel.is_cancelled());
+        queue.requeue(old);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn empty_batch() {
+        let (queue, _) = queue(0);
+        assert!(queue.pop().is_none());
+        queue.set_focus(3);
+        queue.prefetch(&[1]);
+        assert!(queue.pop().is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use ts_rs::TS;
+
+use super::{analysis::ImageAnalysis, quality::exposure_score, Image};
+
+/// Relative weights of the scores, 0 leaves a score out
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct RankingConfig {
+    pub(crate) sharpness: f32,
+    pub(crate) exposure: f32,
+    pub(crate) subject_contrast: f32,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            sharpness: 0.6,
+            exposure: 0.3,
+            subject_contrast: 0.1,
+        }
+    }
+}
+
+/// Scores of a frame between 0 and 1, sharpness and contrast are relative to the best frame of the group
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct FrameScore {
+    /// Preview path of the img
+    image: PathBuf,
+    /// Weighted score the frames are ranked by
+    score: f32,
+    sharpness: f32,
+    exposure: f32,
+    subject_contrast: f32,
+}
+
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct GroupRanking {
+    /// Preview path of the best frame, the rest are suggested rejects
+    keeper: PathBuf,
+    /// Best first, frames which have not been analyzed yet are left out
+    scores: Vec<FrameScore>,
+}
+
+/// Merges the groups sharing an img, eg. a burst and the similar frames following it
+pub(crate) fn merge_groups(groups: impl IntoIterator<Item = Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut merged: Vec<Vec<usize>> = Vec::new();
+    for group in groups {
+        let (overlapping, mut rest): (Vec<_>, Vec<_>) = merged
+            .into_iter()
+            .partition(|other| other.iter().any(|i| group.contains(i)));
+
+        let mut group: Vec<usize> = overlapping.into_iter().flatten().chain(group).collect();
+        group.sort_unstable();
+        group.dedup();
+        rest.push(group);
+        merged = rest;
+    }
+
+    merged.sort_by_key(|group| group.first().copied());
+    merged
+}
+
+/// Proposes a keeper for every group of the imgs
+pub(crate) fn rank_groups(
+    imgs: &[Image],
+    groups: &[Vec<usize>],
+    config: &RankingConfig,
+) -> Vec<GroupRanking> {
+    groups
+        .iter()
+        .filter_map(|group| rank_group(imgs, group, config))
+        .collect()
+}
+
+fn rank_group(imgs: &[Image], group: &[usize], config: &RankingConfig) -> Option<GroupRanking> {
+    let analyzed: Vec<(&Image, &ImageAnalysis)> = group
+        .iter()
+        .filter_map(|i| {
+            let img = imgs.get(*i)?;
+            // frames of a sequence are kept or rejected as a whole
+            if img.sequence.is_some() {
+                return None;
+            }
+            let analysis = img.analysis.as_ref().filter(|a| a.is_current())?;
+            Some((img, analysis))
+        })
+        .collect();
+    // nothing to compare with
+    if analyzed.len() < 2 {
+        return None;
+    }
+
+    let max_sharpness = analyzed.iter().map(|(_, a)| a.sharpness).fold(0., f32::max);
+    let max_contrast = analyzed
+        .iter()
+        .map(|(_, a)| a.subject_contrast)
+        .fold(0., f32::max);
+    let weights = config.sharpness + config.exposure + config.subject_contrast;
+
+    let mut scores: Vec<FrameScore> = analyzed
+        .into_iter()
+        .map(|(img, analysis)| {
+            let sharpness = relative(analysis.sharpness, max_sharpness);
+            let exposure = exposure_score(analysis);
+            let subject_contrast = relative(analysis.subject_contrast, max_contrast);
+            let score = (sharpness * config.sharpness
+                + exposure * config.exposure
+                + subject_contrast * config.subject_contrast)
+                / weights.max(f32::EPSILON);
+
+            FrameScore {
+                image: img.preview_path.clone(),
+                score,
+                sharpness,
+                exposure,
+                subject_contrast,
+            }
+        })
+        .collect();
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    Some(GroupRanking {
+        keeper: scores[0].image.clone(),
+        scores,
+    })
+}
+
+fn relative(value: f32, max: f32) -> f32 {
+    if max > 0. {
+        value / max
+    } else {
+        1.
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::image::{sequence::SequenceKind, test_util::analyzed, Sequence};
+
+    fn frame(name: &str, sharpness: f32, clipping: f32, subject_contrast: f32) -> Image {
+        analyzed(name, |analysis| {
+            analysis.sharpness = sharpness;
+            analysis.clipped_highlights = clipping;
+            analysis.subject_contrast = subject_contrast;
+        })
+    }
+
+    fn rank(imgs: &[Image], config: &RankingConfig) -> Option<GroupRanking> {
+        let group: Vec<_> = (0..imgs.len()).collect();
+        rank_groups(imgs, &[group], config).pop()
+    }
+
+    fn order(ranking: &GroupRanking) -> Vec<String> {
+        ranking
+            .scores
+            .iter()
+            .map(|score| score.image.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn sharpest_first() {
+        let imgs = [
+            frame("soft", 100., 0., 20.),
+            frame("sharp", 400., 0., 20.),
+            frame("ok", 300., 0., 20.),
+        ];
+        let ranking = rank(&imgs, &RankingConfig::default()).unwrap();
+        assert_eq!(ranking.keeper.to_string_lossy(), "sharp.webp");
+        assert_eq!(order(&ranking), ["sharp.webp", "ok.webp", "soft.webp"]);
+        assert!((ranking.scores[0].score - 1.).abs() < 1e-6);
+        assert!((ranking.scores[2].sharpness - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weighted_scores() {
+        // slightly sharper, but blown out
+        let imgs = [
+            frame("blown", 400., 10., 20.),
+            frame("exposed", 360., 0., 20.),
+        ];
+        let ranking = rank(&imgs, &RankingConfig::default()).unwrap();
+        assert_eq!(ranking.keeper.to_string_lossy(), "exposed.webp");
+
+        let sharpness_only = RankingConfig {
+            sharpness: 1.,
+            exposure: 0.,
+            subject_contrast: 0.,
+        };
+        let ranking = rank(&imgs, &sharpness_only).unwrap();
+        assert_eq!(ranking.keeper.to_string_lossy(), "blown.webp");
+
+        let nothing = RankingConfig {
+            sharpness: 0.,
+            exposure: 0.,
+            subject_contrast: 0.,
+        };
+        let ranking = rank(&imgs, &nothing).unwrap();
+        assert!(ranking.scores.iter().all(|score| score.score == 0.));
+    }
+
+    #[test]
+    fn ties_keep_the_first() {
+        let imgs = [frame("a", 0., 0., 0.), frame("b", 0., 0., 0.)];
+        let ranking = rank(&imgs, &RankingConfig::default()).unwrap();
+        assert_eq!(order(&ranking), ["a.webp", "b.webp"]);
+        // nothing is relative to 0
+        assert!(ranking.scores.iter().all(|score| score.sharpness == 1.));
+    }
+
+    #[test]
+    fn unranked_frames() {
+        let mut imgs = vec![
+            frame("a", 100., 0., 0.),
+            frame("b", 200., 0., 0.),
+            frame("c", 300., 0., 0.),
+            frame("d", 400., 0., 0.),
+        ];
+        imgs[2].analysis = None;
+        imgs[3].sequence = Some(Sequence {
+            kind: SequenceKind::Panorama,
+            id: "pano".to_owned(),
+        });
+        let ranking = rank(&imgs, &RankingConfig::default()).unwrap();
+        assert_eq!(order(&ranking), ["b.webp", "a.webp"]);
+
+        assert!(rank(&imgs[..1], &RankingConfig::default()).is_none());
+        assert!(rank(&imgs[2..], &RankingConfig::default()).is_none());
+        assert!(rank(&[], &RankingConfig::default()).is_none());
+        // out of range indices are skipped
+        assert!(rank_groups(&imgs, &[vec![0, 7]], &RankingConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn merged_groups() {
+        let groups = [vec![5, 6], vec![0, 1], vec![1, 2], vec![9], vec![2, 6]];
+        assert_eq!(merge_groups(groups), [vec![0, 1, 2, 5, 6], vec![9]]);
+        assert!(merge_groups([]).is_empty());
+    }
+}
@@ -0,0 +1,967 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use chrono::{NaiveDateTime, TimeDelta};
+use image::metadata::Orientation;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
+};
+
+use super::{
+    burst::{Capture, DriveMode},
+    color::{ColorTarget, ExifColorSpace},
+    generator::{GeneratedPreview, PreviewBackend, PreviewGenerator},
+    native,
+    scheduler::JobContext,
+    Image, PreviewSize,
+};
+
+/// Raw formats with an embedded JPEG preview, in the order of preference
+pub(crate) const RAW_EXTENSIONS: [&str; 6] = ["arw", "cr2", "nef", "dng", "orf", "rw2"];
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_EXPOSURE_TIME: u16 = 0x829A;
+const TAG_F_NUMBER: u16 = 0x829D;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_ISO: u16 = 0x8827;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_EXPOSURE_BIAS: u16 = 0x9204;
+const TAG_SUBJECT_DISTANCE: u16 = 0x9206;
+const TAG_FOCAL_LENGTH: u16 = 0x920A;
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+const TAG_MAKER_NOTE: u16 = 0x927C;
+const TAG_COLOR_SPACE: u16 = 0xA001;
+const TAG_INTEROP_IFD: u16 = 0xA005;
+const TAG_INTEROP_INDEX: u16 = 0x0001;
+// RW2 stores the preview as a blob
+const TAG_PANASONIC_JPG_FROM_RAW: u16 = 0x002E;
+// ORF stores the preview in the camera settings IFD of the maker note
+const TAG_OLYMPUS_CAMERA_SETTINGS: u16 = 0x2020;
+const TAG_OLYMPUS_PREVIEW_START: u16 = 0x0101;
+const TAG_OLYMPUS_PREVIEW_LENGTH: u16 = 0x0102;
+const OLYMPUS_MAKER_NOTE_HEADER: &[u8] = b"OLYMPUS\0";
+// https://exiftool.org/TagNames/Sony.html
+const TAG_SONY_RELEASE_MODE: u16 = 0xB049;
+const TAG_SONY_SEQUENCE_NUMBER: u16 = 0xB04A;
+// some models prefix the IFD with eg. "SONY DSC \0\0\0"
+const SONY_MAKER_NOTE_HEADER: &[u8] = b"SONY";
+const SONY_MAKER_NOTE_HEADER_LEN: u64 = 12;
+const SONY_NOT_APPLICABLE: u32 = 0xFFFF;
+
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+
+const DATE_TIME_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+const COLOR_SPACE_SRGB: u32 = 1;
+// not in the spec, but written by some cameras
+const COLOR_SPACE_ADOBE_RGB: u32 = 2;
+const COLOR_SPACE_UNCALIBRATED: u32 = 0xFFFF;
+// DCF marks Adobe RGB by the interoperability index
+const INTEROP_ADOBE_RGB: &[u8] = b"R03";
+
+// guards against malformed or cyclic files
+const MAX_IFDS: usize = 64;
+const MAX_IFD_ENTRIES: u16 = 1000;
+const MAX_VALUES: u32 = 4096;
+// enough to get past the EXIF segment of the embedded JPEG to its frame header
+const JPEG_HEADER_PEEK: u64 = 256 * 1024;
+
+pub(crate) fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            RAW_EXTENSIONS
+                .iter()
+                .any(|supported| ext.eq_ignore_ascii_case(supported))
+        })
+}
+
+pub(crate) struct EmbeddedJpegGenerator {
+    pub(crate) color: Arc<ColorTarget>,
+}
+
+impl PreviewGenerator for EmbeddedJpegGenerator {
+    fn backend(&self) -> PreviewBackend {
+        PreviewBackend::EmbeddedJpeg
+    }
+
+    fn probe(&self) -> bool {
+        true
+    }
+
+    fn generate(
+        &self,
+        raw_img: &Image,
+        size: PreviewSize,
+        _output: &Path,
+        ctx: &JobContext,
+    ) -> anyhow::Result<GeneratedPreview> {
+        let decoded = ctx.decoded.get_or_decode(self.backend(), || {
+            ctx.scheduler
+                .disk_read(|| extract_largest_jpeg(&raw_img.path))?
+                .decode(&raw_img.path)
+        })?;
+
+        let diagnostics = vec![format!(
+            "embedded JPEG {}x{}",
+            decoded.img.width(),
+            decoded.img.height()
+        )];
+
+        native::encode_preview(&decoded, size, &self.color, diagnostics)
+    }
+}
+
+pub(crate) struct EmbeddedJpeg {
+    pub(crate) jpeg: Vec<u8>,
+    pub(crate) meta: RawMeta,
+}
+
+impl EmbeddedJpeg {
+    pub(crate) fn decode(self, raw_path: &Path) -> anyhow::Result<native::DecodedImage> {
+        // the EXIF of the raw takes precedence as embedded JPEGs often don't have any
+        let mut decoded = native::decode_oriented(self.jpeg, raw_path, self.meta.orientation)
+            .context(format!("Invalid embedded JPEG in {raw_path:?}"))?;
+        decoded.color.exif = self.meta.color_space.or(decoded.color.exif);
+
+        Ok(decoded)
+    }
+}
+
+/// What the preview needs from the EXIF of the raw
+#[derive(Default)]
+pub(crate) struct RawMeta {
+    pub(crate) orientation: Option<Orientation>,
+    pub(crate) color_space: Option<ExifColorSpace>,
+}
+
+/// Walks the TIFF structure of the raw and returns the largest embedded JPEG
+pub(crate) fn extract_largest_jpeg(path: &Path) -> anyhow::Result<EmbeddedJpeg> {
+    let file = File::open(path).context(format!("Failed to open {path:?}"))?;
+    let mut reader = TiffReader::new(BufReader::new(file))?;
+    let (candidates, meta) = reader.find_jpegs()?;
+
+    let (largest, _) = candidates
+        .into_iter()
+        .filter_map(|c| {
+            let header = reader.read_at(c.offset, c.len.min(JPEG_HEADER_PEEK)).ok()?;
+            Some((c, jpeg_dimensions(&header)?))
+        })
+        .max_by_key(|(_, (width, height))| u32::from(*width) * u32::from(*height))
+        .ok_or(anyhow!("No embedded JPEG found in {path:?}"))?;
+
+    Ok(EmbeddedJpeg {
+        jpeg: reader.read_at(largest.offset, largest.len)?,
+        meta,
+    })
+}
+
+/// Reads the colour space from the EXIF of a JPEG
+pub(crate) fn exif_color_space(exif: &[u8]) -> Option<ExifColorSpace> {
+    let mut reader = TiffReader::new(Cursor::new(exif)).ok()?;
+    let ifd = reader.read_ifd(u64::from(reader.first_ifd)).ok()?;
+    let exif_offset = reader.value(0, &ifd, TAG_EXIF_IFD)?;
+    let exif_ifd = reader.read_ifd(u64::from(exif_offset)).ok()?;
+
+    reader.color_space(&exif_ifd)
+}
+
+/// Reads the capture time, drive mode and exposure settings of a raw or a JPEG,
+/// imgs without an EXIF date have none
+pub(crate) fn read_capture(path: &Path) -> Option<Capture> {
+    if is_supported(path) {
+        let file = File::open(path).ok()?;
+        TiffReader::new(BufReader::new(file)).ok()?.capture()
+    } else {
+        TiffReader::new(Cursor::new(jpeg_exif(path)?))
+            .ok()?
+            .capture()
+    }
+}
+
+/// Reads just the EXIF segment instead of the whole JPEG
+fn jpeg_exif(path: &Path) -> Option<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut soi = [0; 2];
+    reader.read_exact(&mut soi).ok()?;
+    if soi != [0xFF, 0xD8] {
+        return None;
+    }
+
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).ok()?;
+        // the EXIF precedes the img data
+        if header[0] != 0xFF || matches!(header[1], 0xD9 | 0xDA) {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([header[2], header[3]]).checked_sub(2)?;
+        if header[1] == 0xE1 {
+            let mut segment = vec![0; usize::from(len)];
+            reader.read_exact(&mut segment).ok()?;
+            if let Some(exif) = segment.strip_prefix(JPEG_EXIF_HEADER) {
+                return Some(exif.to_vec());
+            }
+        } else {
+            reader.seek_relative(i64::from(len)).ok()?;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(b),
+            ByteOrder::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(b),
+            ByteOrder::Big => u32::from_be_bytes(b),
+        }
+    }
+}
+
+struct IfdEntry {
+    field_type: u16,
+    count: u32,
+    value: [u8; 4],
+}
+
+struct Ifd {
+    entries: HashMap<u16, IfdEntry>,
+    next: u32,
+}
+
+struct JpegCandidate {
+    offset: u64,
+    len: u64,
+}
+
+struct TiffReader<R> {
+    inner: R,
+    order: ByteOrder,
+    len: u64,
+    first_ifd: u32,
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    fn new(mut inner: R) -> anyhow::Result<Self> {
+        let mut header = [0; 8];
+        inner.read_exact(&mut header)?;
+
+        let order = match &header[..2] {
+            b"II" => ByteOrder::Little,
+            b"MM" => ByteOrder::Big,
+            _ => bail!("Not a TIFF based raw"),
+        };
+        // TIFF, ORF and RW2 magic numbers
+        ensure!(
+            matches!(order.u16(&header[2..4]), 42 | 0x4F52 | 0x5352 | 0x55),
+            "Unsupported TIFF variant"
+        );
+
+        let len = inner.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            inner,
+            order,
+            len,
+            first_ifd: order.u32(&header[4..8]),
+        })
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            offset.checked_add(len).is_some_and(|end| end <= self.len),
+            "Read out of bounds"
+        );
+
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; usize::try_from(len)?];
+        self.inner.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn read_ifd(&mut self, offset: u64) -> anyhow::Result<Ifd> {
+        let count = self.order.u16(&self.read_at(offset, 2)?);
+        ensure!(count <= MAX_IFD_ENTRIES, "Too many IFD entries");
+
+        let raw = self.read_at(offset + 2, u64::from(count) * 12 + 4)?;
+        let (entries, next) = raw.split_at(usize::from(count) * 12);
+
+        Ok(Ifd {
+            entries: entries
+                .chunks_exact(12)
+                .map(|e| {
+                    (
+                        self.order.u16(&e[0..2]),
+                        IfdEntry {
+                            field_type: self.order.u16(&e[2..4]),
+                            count: self.order.u32(&e[4..8]),
+                            value: [e[8], e[9], e[10], e[11]],
+                        },
+                    )
+                })
+                .collect(),
+            next: self.order.u32(next),
+        })
+    }
+
+    /// Reads SHORT, LONG and IFD values, other types are ignored
+    fn values(&mut self, base: u64, entry: &IfdEntry) -> anyhow::Result<Vec<u32>> {
+        let size: u8 = match entry.field_type {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Ok(Vec::new()),
+        };
+        ensure!(entry.count <= MAX_VALUES, "Too many IFD values");
+
+        let total = u64::from(size) * u64::from(entry.count);
+        let bytes = if total <= 4 {
+            entry.value[..usize::try_from(total)?].to_vec()
+        } else {
+            self.read_at(base + u64::from(self.order.u32(&entry.value)), total)?
+        };
+
+        Ok(bytes
+            .chunks_exact(usize::from(size))
+            .map(|b| {
+                if size == 2 {
+                    u32::from(self.order.u16(b))
+                } else {
+                    self.order.u32(b)
+                }
+            })
+            .collect())
+    }
+
+    fn value(&mut self, base: u64, ifd: &Ifd, tag: u16) -> Option<u32> {
+        let entry = ifd.entries.get(&tag)?;
+        self.values(base, entry).ok()?.first().copied()
+    }
+
+    /// Reads an ASCII value without the NUL terminator
+    fn ascii(&mut self, ifd: &Ifd, tag: u16) -> Option<String> {
+        let entry = ifd.entries.get(&tag)?;
+        if entry.field_type != 2 || entry.count > MAX_VALUES {
+            return None;
+        }
+
+        let bytes = if entry.count <= 4 {
+            entry.value[..usize::try_from(entry.count).ok()?].to_vec()
+        } else {
+            self.read_at(u64::from(self.order.u32(&entry.value)), entry.count.into())
+                .ok()?
+        };
+        let value = String::from_utf8_lossy(&bytes);
+        Some(value.trim_end_matches('\0').trim().to_owned())
+    }
+
+    /// Reads the first RATIONAL or SRATIONAL value
+    #[allow(clippy::cast_possible_wrap)]
+    fn rational(&mut self, ifd: &Ifd, tag: u16) -> Option<f64> {
+        let entry = ifd.entries.get(&tag)?;
+        let signed = match entry.field_type {
+            5 => false,
+            10 => true,
+            _ => return None,
+        };
+
+        // 8 bytes don't fit the entry, so it's always an offset
+        let bytes = self
+            .read_at(u64::from(self.order.u32(&entry.value)), 8)
+            .ok()?;
+        let (numerator, denominator) = (self.order.u32(&bytes[..4]), self.order.u32(&bytes[4..]));
+        if denominator == 0 {
+            return None;
+        }
+
+        Some(if signed {
+            f64::from(numerator as i32) / f64::from(denominator as i32)
+        } else {
+            f64::from(numerator) / f64::from(denominator)
+        })
+    }
+
+    fn capture(&mut self) -> Option<Capture> {
+        let ifd = self.read_ifd(u64::from(self.first_ifd)).ok()?;
+        let exif_offset = self.value(0, &ifd, TAG_EXIF_IFD)?;
+        let exif_ifd = self.read_ifd(u64::from(exif_offset)).ok()?;
+
+        let mut taken = NaiveDateTime::parse_from_str(
+            &self.ascii(&exif_ifd, TAG_DATE_TIME_ORIGINAL)?,
+            DATE_TIME_FORMAT,
+        )
+        .ok()?;
+        // fraction of the second, eg. "05" is 50ms
+        if let Some(sub_sec) = self
+            .ascii(&exif_ifd, TAG_SUB_SEC_TIME_ORIGINAL)
+            .filter(|s| !s.is_empty() && s.len() <= 9 && s.bytes().all(|b| b.is_ascii_digit()))
+        {
+            let nanos = format!("{sub_sec:0<9}").parse().unwrap_or_default();
+            taken += TimeDelta::nanoseconds(nanos);
+        }
+
+        let is_sony = self
+            .ascii(&ifd, TAG_MAKE)
+            .is_some_and(|make| make.to_uppercase().starts_with("SONY"));
+        let (drive_mode, sequence) = if is_sony {
+            self.sony_drive_mode(&exif_ifd)
+        } else {
+            (None, None)
+        };
+
+        Some(Capture {
+            taken,
+            camera: self.ascii(&ifd, TAG_MODEL),
+            drive_mode,
+            sequence,
+            exposure_time: self.rational(&exif_ifd, TAG_EXPOSURE_TIME),
+            f_number: self.rational(&exif_ifd, TAG_F_NUMBER),
+            iso: self.value(0, &exif_ifd, TAG_ISO),
+            exposure_bias: self.rational(&exif_ifd, TAG_EXPOSURE_BIAS),
+            focal_length: self.rational(&exif_ifd, TAG_FOCAL_LENGTH),
+            // 0 is unknown and 0xFFFFFFFF infinity
+            subject_distance: self
+                .rational(&exif_ifd, TAG_SUBJECT_DISTANCE)
+                .filter(|distance| *distance > 0. && *distance < f64::from(u32::MAX)),
+        })
+    }
+
+    fn sony_drive_mode(&mut self, exif_ifd: &Ifd) -> (Option<DriveMode>, Option<u32>) {
+        let Some(maker_note) = exif_ifd.entries.get(&TAG_MAKER_NOTE) else {
+            return (None, None);
+        };
+        // the offsets in the maker note are relative to the TIFF header
+        let mut offset = u64::from(self.order.u32(&maker_note.value));
+        if self
+            .read_at(offset, SONY_MAKER_NOTE_HEADER.len() as u64)
+            .is_ok_and(|header| header == SONY_MAKER_NOTE_HEADER)
+        {
+            offset += SONY_MAKER_NOTE_HEADER_LEN;
+        }
+        let Ok(maker_ifd) = self.read_ifd(offset) else {
+            return (None, None);
+        };
+
+        let drive_mode = match self.value(0, &maker_ifd, TAG_SONY_RELEASE_MODE) {
+            Some(0) => Some(DriveMode::Single),
+            Some(2) => Some(DriveMode::Continuous),
+            // exposure, white balance & DRO
+            Some(5 | 6 | 8) => Some(DriveMode::Bracketing),
+            _ => None,
+        };
+        let sequence = self
+            .value(0, &maker_ifd, TAG_SONY_SEQUENCE_NUMBER)
+            .filter(|sequence| *sequence != SONY_NOT_APPLICABLE);
+
+        (drive_mode, sequence)
+    }
+
+    fn find_jpegs(&mut self) -> anyhow::Result<(Vec<JpegCandidate>, RawMeta)> {
+        let mut candidates = Vec::new();
+        let mut meta = RawMeta::default();
+        let mut visited = HashSet::new();
+        let mut pending = vec![u64::from(self.first_ifd)];
+
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || !visited.insert(offset) || visited.len() > MAX_IFDS {
+                continue;
+            }
+
+            let Ok(ifd) = self.read_ifd(offset) else {
+                // a broken sub IFD shouldn't prevent using the other ones
+                continue;
+            };
+
+            if offset == u64::from(self.first_ifd) {
+                meta.orientation = self
+                    .value(0, &ifd, TAG_ORIENTATION)
+                    .and_then(|o| u8::try_from(o).ok())
+                    .and_then(Orientation::from_exif);
+            }
+
+            candidates.extend(self.ifd_jpegs(&ifd));
+
+            if let Some(entry) = ifd.entries.get(&TAG_SUB_IFDS) {
+                pending.extend(
+                    self.values(0, entry)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(u64::from),
+                );
+            }
+            if let Some(exif) = self.value(0, &ifd, TAG_EXIF_IFD) {
+                if let Ok(exif_ifd) = self.read_ifd(u64::from(exif)) {
+                    candidates.extend(self.olympus_jpeg(&exif_ifd));
+                    meta.color_space = meta.color_space.or_else(|| self.color_space(&exif_ifd));
+                }
+            }
+            pending.push(u64::from(ifd.next));
+        }
+
+        Ok((candidates, meta))
+    }
+
+    fn ifd_jpegs(&mut self, ifd: &Ifd) -> Vec<JpegCandidate> {
+        let mut candidates = Vec::new();
+
+        if let (Some(offset), Some(len)) = (
+            self.value(0, ifd, TAG_JPEG_OFFSET),
+            self.value(0, ifd, TAG_JPEG_LENGTH),
+        ) {
+            candidates.push(JpegCandidate {
+                offset: offset.into(),
+                len: len.into(),
+            });
+        }
+
+        // CR2 and DNG store JPEGs as single strip imgs
+        // the lossless raw data are filtered out when reading the JPEG frame header
+        if matches!(
+            self.value(0, ifd, TAG_COMPRESSION),
+            Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG)
+        ) {
+            if let (Some(offset), Some(len)) = (
+                self.value(0, ifd, TAG_STRIP_OFFSETS),
+                self.value(0, ifd, TAG_STRIP_BYTE_COUNTS),
+            ) {
+                candidates.push(JpegCandidate {
+                    offset: offset.into(),
+                    len: len.into(),
+                });
+            }
+        }
+
+        if let Some(entry) = ifd.entries.get(&TAG_PANASONIC_JPG_FROM_RAW) {
+            candidates.push(JpegCandidate {
+                offset: self.order.u32(&entry.value).into(),
+                len: entry.count.into(),
+            });
+        }
+
+        candidates
+    }
+
+    fn color_space(&mut self, exif_ifd: &Ifd) -> Option<ExifColorSpace> {
+        match self.value(0, exif_ifd, TAG_COLOR_SPACE)? {
+            COLOR_SPACE_SRGB => Some(ExifColorSpace::Srgb),
+            COLOR_SPACE_ADOBE_RGB => Some(ExifColorSpace::AdobeRgb),
+            COLOR_SPACE_UNCALIBRATED => {
+                let interop = self.value(0, exif_ifd, TAG_INTEROP_IFD)?;
+                let interop = self.read_ifd(u64::from(interop)).ok()?;
+                // the ASCII index fits into the entry
+                let index = interop.entries.get(&TAG_INTEROP_INDEX)?;
+                index
+                    .value
+                    .starts_with(INTEROP_ADOBE_RGB)
+                    .then_some(ExifColorSpace::AdobeRgb)
+            }
+            _ => None,
+        }
+    }
+
+    fn olympus_jpeg(&mut self, exif_ifd: &Ifd) -> Option<JpegCandidate> {
+        let maker_note = exif_ifd.entries.get(&TAG_MAKER_NOTE)?;
+        // the offsets in the maker note are relative to its start
+        let base = u64::from(self.order.u32(&maker_note.value));
+
+        let header = self
+            .read_at(base, OLYMPUS_MAKER_NOTE_HEADER.len() as u64)
+            .ok()?;
+        if header != OLYMPUS_MAKER_NOTE_HEADER {
+            return None;
+        }
+
+        // the header is followed by a byte order mark and a version
+        let maker_ifd = self.read_ifd(base + 12).ok()?;
+        let settings_offset = self.value(base, &maker_ifd, TAG_OLYMPUS_CAMERA_SETTINGS)?;
+        let settings = self.read_ifd(base + u64::from(settings_offset)).ok()?;
+
+        Some(JpegCandidate {
+            offset: base + u64::from(self.value(base, &settings, TAG_OLYMPUS_PREVIEW_START)?),
+            len: self
+                .value(base, &settings, TAG_OLYMPUS_PREVIEW_LENGTH)?
+                .into(),
+        })
+    }
+}
+
+/// Reads the dimensions from the frame header of a baseline or progressive JPEG
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    if jpeg.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut pos = 2;
+    loop {
+        if *jpeg.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *jpeg.get(pos + 1)?;
+        match marker {
+            // padding
+            0xFF => pos += 1,
+            // baseline, extended & progressive huffman frames
+            // lossless (raw data) and arithmetic frames can't be decoded
+            0xC0..=0xC2 => {
+                let frame = jpeg.get(pos + 5..pos + 9)?;
+                let height = u16::from_be_bytes([frame[0], frame[1]]);
+                let width = u16::from_be_bytes([frame[2], frame[3]]);
+                return (width > 0 && height > 0).then_some((width, height));
+            }
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xD9 | 0xDA => return None,
+            _ => {
+                let len = jpeg.get(pos + 2..pos + 4)?;
+                pos += 2 + usize::from(u16::from_be_bytes([len[0], len[1]]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_util::{jpeg, temp_dir};
+    use Entry::{Ascii, Long, Rational, SRational, Short, Undefined};
+
+    #[derive(Clone, Copy)]
+    enum Entry {
+        Short(u16, u16),
+        Long(u16, u32),
+        Ascii(u16, &'static str),
+        Rational(u16, u32, u32),
+        SRational(u16, i32, i32),
+        /// Offset and length of a blob
+        Undefined(u16, u32, u32),
+    }
+
+    /// Lays out a TIFF in the order the values and IFDs are added,
+    /// so an IFD has to be added before the ones pointing to it
+    struct TiffBuilder {
+        order: ByteOrder,
+        buf: Vec<u8>,
+    }
+
+    impl TiffBuilder {
+        fn new(order: ByteOrder) -> Self {
+            let mut tiff = Self {
+                order,
+                buf: match order {
+                    ByteOrder::Little => b"II".to_vec(),
+                    ByteOrder::Big => b"MM".to_vec(),
+                },
+            };
+            let magic = tiff.u16_bytes(42);
+            tiff.buf.extend(magic);
+            tiff.buf.extend([0; 4]);
+            tiff
+        }
+
+        fn u16_bytes(&self, value: u16) -> [u8; 2] {
+            match self.order {
+                ByteOrder::Little => value.to_le_bytes(),
+                ByteOrder::Big => value.to_be_bytes(),
+            }
+        }
+
+        fn u32_bytes(&self, value: u32) -> [u8; 4] {
+            match self.order {
+                ByteOrder::Little => value.to_le_bytes(),
+                ByteOrder::Big => value.to_be_bytes(),
+            }
+        }
+
+        fn offset(&self) -> u32 {
+            u32::try_from(self.buf.len()).unwrap()
+        }
+
+        fn blob(&mut self, data: &[u8]) -> u32 {
+            let offset = self.offset();
+            self.buf.extend(data);
+            offset
+        }
+
+        fn rational(&mut self, numerator: u32, denominator: u32) -> [u8; 4] {
+            let bytes = [self.u32_bytes(numerator), self.u32_bytes(denominator)].concat();
+            let offset = self.blob(&bytes);
+            self.u32_bytes(offset)
+        }
+
+        fn ifd(&mut self, entries: &[Entry]) -> u32 {
+            let entries: Vec<(u16, u16, u32, [u8; 4])> = entries
+                .iter()
+                .map(|entry| match *entry {
+                    Short(tag, value) => {
+                        let [a, b] = self.u16_bytes(value);
+                        (tag, 3, 1, [a, b, 0, 0])
+                    }
+                    Long(tag, value) => (tag, 4, 1, self.u32_bytes(value)),
+                    Ascii(tag, value) => {
+                        let mut bytes = value.as_bytes().to_vec();
+                        bytes.push(0);
+                        let count = u32::try_from(bytes.len()).unwrap();
+                        if bytes.len() <= 4 {
+                            bytes.resize(4, 0);
+                            (tag, 2, count, bytes.try_into().unwrap())
+                        } else {
+                            let offset = self.blob(&bytes);
+                            (tag, 2, count, self.u32_bytes(offset))
+                        }
+                    }
+                    Rational(tag, numerator, denominator) => {
+                        (tag, 5, 1, self.rational(numerator, denominator))
+                    }
+                    SRational(tag, numerator, denominator) => {
+                        let (numerator, denominator) = (
+                            u32::from_ne_bytes(numerator.to_ne_bytes()),
+                            u32::from_ne_bytes(denominator.to_ne_bytes()),
+                        );
+                        (tag, 10, 1, self.rational(numerator, denominator))
+                    }
+                    Undefined(tag, offset, len) => (tag, 7, len, self.u32_bytes(offset)),
+                })
+                .collect();
+
+            let offset = self.offset();
+            let count = self.u16_bytes(u16::try_from(entries.len()).unwrap());
+            self.buf.extend(count);
+            for (tag, field_type, count, value) in entries {
+                let entry = [
+                    &self.u16_bytes(tag)[..],
+                    &self.u16_bytes(field_type),
+                    &self.u32_bytes(count),
+                    &value,
+                ]
+                .concat();
+                self.buf.extend(entry);
+            }
+            self.buf.extend([0; 4]);
+            offset
+        }
+
+        fn jpeg_ifd(&mut self, jpeg: &[u8], extra: &[Entry]) -> u32 {
+            let len = u32::try_from(jpeg.len()).unwrap();
+            let offset = self.blob(jpeg);
+            let mut entries = vec![Long(TAG_JPEG_OFFSET, offset), Long(TAG_JPEG_LENGTH, len)];
+            entries.extend_from_slice(extra);
+            self.ifd(&entries)
+        }
+
+        fn set_first(&mut self, ifd: u32) {
+            let bytes = self.u32_bytes(ifd);
+            self.buf[4..8].copy_from_slice(&bytes);
+        }
+
+        fn set_next(&mut self, ifd: u32, next: u32) {
+            let ifd = usize::try_from(ifd).unwrap();
+            let count = usize::from(self.order.u16(&self.buf[ifd..ifd + 2]));
+            let at = ifd + 2 + count * 12;
+            let bytes = self.u32_bytes(next);
+            self.buf[at..at + 4].copy_from_slice(&bytes);
+        }
+
+        fn write(&self, dir: &Path, name: &str) -> std::path::PathBuf {
+            let path = dir.join(name);
+            std::fs::write(&path, &self.buf).unwrap();
+            path
+        }
+    }
+
+    /// Thumbnail in IFD0, the preview in IFD1 and the raw data in its sub IFD
+    fn raw(order: ByteOrder) -> TiffBuilder {
+        let mut tiff = TiffBuilder::new(order);
+        // a lossless frame, which can't be decoded
+        let strip = tiff.blob(&[0xFF, 0xD8, 0xFF, 0xC3, 0, 11, 8, 0, 16, 0, 16, 1]);
+        let raw_data = tiff.ifd(&[
+            Short(TAG_COMPRESSION, 7),
+            Long(TAG_STRIP_OFFSETS, strip),
+            Long(TAG_STRIP_BYTE_COUNTS, 12),
+        ]);
+        let preview = tiff.jpeg_ifd(&jpeg(96, 64), &[Long(TAG_SUB_IFDS, raw_data)]);
+        let thumbnail = tiff.jpeg_ifd(&jpeg(32, 24), &[Short(TAG_ORIENTATION, 6)]);
+        tiff.set_first(thumbnail);
+        tiff.set_next(thumbnail, preview);
+        tiff
+    }
+
+    #[test]
+    fn largest_jpeg() {
+        let dir = temp_dir("largest-jpeg");
+        for (order, name) in [(ByteOrder::Little, "le.arw"), (ByteOrder::Big, "be.nef")] {
+            let path = raw(order).write(&dir, name);
+            let embedded = extract_largest_jpeg(&path).unwrap();
+            assert_eq!(jpeg_dimensions(&embedded.jpeg), Some((96, 64)));
+            assert_eq!(embedded.meta.orientation, Some(Orientation::Rotate90));
+
+            let decoded = embedded.decode(&path).unwrap();
+            assert_eq!((decoded.img.width(), decoded.img.height()), (64, 96));
+        }
+    }
+
+    #[test]
+    fn truncated_ifd() {
+        let dir = temp_dir("truncated-ifd");
+        let mut tiff = TiffBuilder::new(ByteOrder::Little);
+        let first = tiff.jpeg_ifd(&jpeg(32, 24), &[]);
+        tiff.set_first(first);
+        // claims more entries than there are bytes left
+        let broken = tiff.blob(&[10, 0, 0x01, 0x02, 4, 0]);
+        tiff.set_next(first, broken);
+        let embedded = extract_largest_jpeg(&tiff.write(&dir, "next.arw")).unwrap();
+        assert_eq!(jpeg_dimensions(&embedded.jpeg), Some((32, 24)));
+
+        tiff.buf.truncate(usize::try_from(first).unwrap() + 8);
+        assert!(extract_largest_jpeg(&tiff.write(&dir, "first.arw")).is_err());
+    }
+
+    #[test]
+    fn cyclic_ifds() {
+        let dir = temp_dir("cyclic-ifds");
+        let mut tiff = TiffBuilder::new(ByteOrder::Big);
+        let first = tiff.jpeg_ifd(&jpeg(32, 24), &[]);
+        let second = tiff.jpeg_ifd(&jpeg(48, 32), &[Long(TAG_SUB_IFDS, first)]);
+        tiff.set_first(first);
+        tiff.set_next(first, second);
+        tiff.set_next(second, first);
+
+        let embedded = extract_largest_jpeg(&tiff.write(&dir, "cyclic.cr2")).unwrap();
+        assert_eq!(jpeg_dimensions(&embedded.jpeg), Some((48, 32)));
+    }
+
+    #[test]
+    fn no_jpeg() {
+        let dir = temp_dir("no-jpeg");
+        let mut tiff = TiffBuilder::new(ByteOrder::Little);
+        let first = tiff.ifd(&[Short(TAG_ORIENTATION, 1)]);
+        tiff.set_first(first);
+        assert!(extract_largest_jpeg(&tiff.write(&dir, "none.dng")).is_err());
+
+        // a JPEG running past the end of the file
+        let jpeg = jpeg(32, 24);
+        let offset = tiff.blob(&jpeg[..100]);
+        let len = u32::try_from(jpeg.len()).unwrap();
+        let first = tiff.ifd(&[Long(TAG_JPEG_OFFSET, offset), Long(TAG_JPEG_LENGTH, len)]);
+        tiff.set_first(first);
+        assert!(extract_largest_jpeg(&tiff.write(&dir, "cut.dng")).is_err());
+
+        std::fs::write(dir.join("empty.dng"), []).unwrap();
+        assert!(extract_largest_jpeg(&dir.join("empty.dng")).is_err());
+        std::fs::write(dir.join("jpeg.dng"), jpeg).unwrap();
+        assert!(extract_largest_jpeg(&dir.join("jpeg.dng")).is_err());
+    }
+
+    fn exif(order: ByteOrder, make: &'static str) -> TiffBuilder {
+        let mut tiff = TiffBuilder::new(order);
+        let maker_note = tiff.blob(b"SONY DSC \0\0\0");
+        tiff.ifd(&[
+            Short(TAG_SONY_RELEASE_MODE, 2),
+            Short(TAG_SONY_SEQUENCE_NUMBER, 3),
+        ]);
+        let maker_note_len = tiff.offset() - maker_note;
+        let exif = tiff.ifd(&[
+            Ascii(TAG_DATE_TIME_ORIGINAL, "2024:05:01 10:00:00"),
+            Ascii(TAG_SUB_SEC_TIME_ORIGINAL, "45"),
+            Rational(TAG_EXPOSURE_TIME, 1, 400),
+            Rational(TAG_F_NUMBER, 28, 10),
+            Short(TAG_ISO, 800),
+            SRational(TAG_EXPOSURE_BIAS, -2, 3),
+            Rational(TAG_SUBJECT_DISTANCE, u32::MAX, 1),
+            Undefined(TAG_MAKER_NOTE, maker_note, maker_note_len),
+        ]);
+        let first = tiff.ifd(&[
+            Ascii(TAG_MAKE, make),
+            Ascii(TAG_MODEL, "ILCE-7M4"),
+            Long(TAG_EXIF_IFD, exif),
+        ]);
+        tiff.set_first(first);
+        tiff
+    }
+
+    fn with_exif(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg_exif = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg_exif.extend(u16::try_from(tiff.len() + 8).unwrap().to_be_bytes());
+        jpeg_exif.extend(JPEG_EXIF_HEADER);
+        jpeg_exif.extend(tiff);
+        jpeg_exif.extend(&jpeg(16, 16)[2..]);
+        jpeg_exif
+    }
+
+    #[test]
+    fn capture() {
+        let dir = temp_dir("capture");
+        for (order, name) in [(ByteOrder::Little, "le"), (ByteOrder::Big, "be")] {
+            let tiff = exif(order, "SONY");
+            std::fs::write(dir.join(format!("{name}.jpg")), with_exif(&tiff.buf)).unwrap();
+            for path in [
+                tiff.write(&dir, &format!("{name}.arw")),
+                dir.join(format!("{name}.jpg")),
+            ] {
+                let capture = read_capture(&path).unwrap();
+                assert_eq!(capture.taken.to_string(), "2024-05-01 10:00:00.450");
+                assert_eq!(capture.camera.as_deref(), Some("ILCE-7M4"));
+                assert_eq!(
+                    (capture.drive_mode, capture.sequence),
+                    (Some(DriveMode::Continuous), Some(3))
+                );
+                assert_eq!(
+                    (capture.exposure_time, capture.f_number, capture.iso),
+                    (Some(0.0025), Some(2.8), Some(800))
+                );
+                assert_eq!(capture.exposure_bias, Some(-2. / 3.));
+                // infinity
+                assert_eq!(capture.subject_distance, None);
+            }
+        }
+
+        // the maker note is only read for Sony
+        let capture = read_capture(&exif(ByteOrder::Little, "Canon").write(&dir, "canon.cr2"));
+        assert_eq!(capture.unwrap().drive_mode, None);
+    }
+
+    #[test]
+    fn missing_capture() {
+        let dir = temp_dir("missing-capture");
+        let mut tiff = TiffBuilder::new(ByteOrder::Big);
+        let exif = tiff.ifd(&[Short(TAG_ISO, 100)]);
+        let first = tiff.ifd(&[Long(TAG_EXIF_IFD, exif)]);
+        tiff.set_first(first);
+        assert!(read_capture(&tiff.write(&dir, "undated.arw")).is_none());
+
+        let mut tiff = self::exif(ByteOrder::Little, "SONY");
+        let first = usize::try_from(tiff.order.u32(&tiff.buf[4..8])).unwrap();
+        tiff.buf.truncate(first + 20);
+        assert!(read_capture(&tiff.write(&dir, "truncated.arw")).is_none());
+        std::fs::write(dir.join("truncated.jpg"), with_exif(&tiff.buf)).unwrap();
+        assert!(read_capture(&dir.join("truncated.jpg")).is_none());
+
+        std::fs::write(dir.join("plain.jpg"), jpeg(16, 16)).unwrap();
+        assert!(read_capture(&dir.join("plain.jpg")).is_none());
+        assert!(read_capture(&dir.join("missing.jpg")).is_none());
+    }
+}
@@ -0,0 +1,265 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex, MutexGuard,
+    },
+};
+use ts_rs::TS;
+
+use super::{native::DecodedSource, queue::CancelToken};
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase", default)]
+#[ts(export)]
+pub(crate) struct SchedulerConfig {
+    /// Preview workers, defaults to the physical cores minus 3 to keep the PC usable
+    pub(crate) threads: Option<usize>,
+    /// Threads a single backend may use
+    pub(crate) job_threads: usize,
+    /// Imgs read by the app at once, slow disks prefer fewer
+    pub(crate) max_disk_reads: usize,
+    /// Runs the workers and the backends with a low CPU and IO priority
+    pub(crate) low_priority: bool,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            threads: None,
+            job_threads: 1,
+            max_disk_reads: 4,
+            low_priority: false,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    fn threads(&self) -> usize {
+        self.threads
+            .unwrap_or_else(|| num_cpus::get_physical().saturating_sub(3))
+            .max(1)
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    workers: usize,
+    disk_reads: usize,
+}
+
+/// Limits the background work, the limits can change while previews are being generated
+pub(crate) struct Scheduler {
+    config: Mutex<SchedulerConfig>,
+    usage: Mutex<Usage>,
+    released: Condvar,
+    /// A worker has lowered its priority since the last [`Scheduler::take_lowered`]
+    lowered: AtomicBool,
+}
+
+impl Scheduler {
+    pub(crate) fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config: Mutex::new(config),
+            usage: Mutex::default(),
+            released: Condvar::new(),
+            lowered: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn config(&self) -> SchedulerConfig {
+        self.config
+            .lock()
+            .expect("Scheduler lock is not poisoned")
+            .clone()
+    }
+
+    pub(crate) fn set_config(&self, config: SchedulerConfig) {
+        *self.config.lock().expect("Scheduler lock is not poisoned") = config;
+        // waiting workers might fit into the new limits
+        self.released.notify_all();
+    }
+
+    /// Upper bound of workers, the config decides how many of them run at once
+    pub(crate) fn max_threads() -> usize {
+        num_cpus::get().max(1)
+    }
+
+    pub(crate) fn job_threads(&self) -> usize {
+        self.config().job_threads.max(1)
+    }
+
+    pub(crate) fn low_priority(&self) -> bool {
+        self.config().low_priority
+    }
+
+    /// Blocks until another worker is allowed to run
+    pub(crate) fn acquire_worker(&self) -> WorkerPermit<'_> {
+        let mut usage = self.wait_for(|usage, config| usage.workers < config.threads());
+        usage.workers += 1;
+
+        let low_priority = self.low_priority();
+        if set_thread_priority(low_priority) && low_priority {
+            self.lowered.store(true, Ordering::Relaxed);
+        }
+
+        WorkerPermit(self)
+    }
+
+    /// Whether the workers have to be respawned to get their priority back
+    pub(crate) fn take_lowered(&self) -> bool {
+        self.lowered.swap(false, Ordering::Relaxed)
+    }
+
+    /// Waits for a free disk read slot while running `read`
+    pub(crate) fn disk_read<T>(&self, read: impl FnOnce() -> T) -> T {
+        self.wait_for(|usage, config| usage.disk_reads < config.max_disk_reads.max(1))
+            .disk_reads += 1;
+
+        let res = read();
+
+        self.usage
+            .lock()
+            .expect("Scheduler lock is not poisoned")
+            .disk_reads -= 1;
+        self.released.notify_all();
+
+        res
+    }
+
+    fn wait_for(
+        &self,
+        available: impl Fn(&Usage, &SchedulerConfig) -> bool,
+    ) -> MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().expect("Scheduler lock is not poisoned");
+        while !available(&usage, &self.config()) {
+            usage = self
+                .released
+                .wait(usage)
+                .expect("Scheduler lock is not poisoned");
+        }
+
+        usage
+    }
+}
+
+pub(crate) struct WorkerPermit<'a>(&'a Scheduler);
+
+impl Drop for WorkerPermit<'_> {
+    fn drop(&mut self) {
+        self.0
+            .usage
+            .lock()
+            .expect("Scheduler lock is not poisoned")
+            .workers -= 1;
+        self.0.released.notify_all();
+    }
+}
+
+/// What a backend gets to know about the job it runs
+pub(crate) struct JobContext<'a> {
+    pub(crate) cancel: &'a CancelToken,
+    pub(crate) scheduler: &'a Scheduler,
+    /// Shared by the sizes of the img generated together
+    pub(crate) decoded: &'a DecodedSource,
+}
+
+thread_local! {
+    static LOW_PRIORITY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lowers the CPU and IO priority of the current thread, spawned processes inherit it
+///
+/// Raising the priority back might need privileges on unix,
+/// in which case it's restored once the workers are respawned for the next dir
+///
+/// Returns whether the priority has changed
+fn set_thread_priority(low: bool) -> bool {
+    if LOW_PRIORITY.get() == low {
+        return false;
+    }
+
+    if let Err(e) = os_priority::set(low) {
+        println!("Failed to change the worker priority: {e}");
+    }
+
+    LOW_PRIORITY.set(low);
+    true
+}
+
+#[cfg(target_os = "linux")]
+mod os_priority {
+    // https://man7.org/linux/man-pages/man2/ioprio_set.2.html
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_BE_NORM: libc::c_int = 4;
+
+    pub(super) fn set(low: bool) -> std::io::Result<()> {
+        let (nice, ioprio) = if low {
+            (19, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT)
+        } else {
+            (0, (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | IOPRIO_BE_NORM)
+        };
+
+        // 0 is the calling thread
+        // SAFETY: plain syscalls without pointers
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0
+                || libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod os_priority {
+    pub(super) fn set(low: bool) -> std::io::Result<()> {
+        // background threads get throttled CPU and IO
+        let prio = if low { libc::PRIO_DARWIN_BG } else { 0 };
+
+        // SAFETY: plain syscall without pointers
+        if unsafe { libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, prio) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod os_priority {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_MODE_BACKGROUND_BEGIN,
+        THREAD_MODE_BACKGROUND_END,
+    };
+
+    pub(super) fn set(low: bool) -> std::io::Result<()> {
+        // background mode lowers both the CPU and the IO priority
+        let mode = if low {
+            THREAD_MODE_BACKGROUND_BEGIN
+        } else {
+            THREAD_MODE_BACKGROUND_END
+        };
+
+        // SAFETY: the pseudo handle of the current thread is always valid
+        if unsafe { SetThreadPriority(GetCurrentThread(), mode) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod os_priority {
+    pub(super) fn set(_low: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+}
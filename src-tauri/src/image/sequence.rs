@@ -0,0 +1,424 @@
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{
+    burst::{Capture, DriveMode},
+    Image,
+};
+
+/// Relative difference below which two settings are equal, eg. a rounded focal length
+const SETTINGS_TOLERANCE: f64 = 0.01;
+/// Frames of a panorama are composed one by one, faster runs are bursts following action
+const MIN_PANORAMA_GAP_MS: i64 = 250;
+/// Hash distance above which frames share no content, half of the bits differ for unrelated ones
+const MAX_PANORAMA_DISTANCE: u32 = 24;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct SequenceConfig {
+    /// Frames of a sequence are taken at most this far apart
+    pub(crate) max_gap_ms: i64,
+    /// Shorter runs are left alone, eg. a single exposure correction isn't a bracket
+    pub(crate) min_frames: usize,
+}
+
+impl Default for SequenceConfig {
+    fn default() -> Self {
+        Self {
+            max_gap_ms: 2000,
+            min_frames: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum SequenceKind {
+    /// Exposure bracket for HDR
+    Bracket,
+    FocusStack,
+    Panorama,
+}
+
+impl SequenceKind {
+    fn dir_prefix(self) -> &'static str {
+        match self {
+            SequenceKind::Bracket => "HDR",
+            SequenceKind::FocusStack => "Stack",
+            SequenceKind::Panorama => "Pano",
+        }
+    }
+}
+
+/// Frames shot to be merged into a single img, so they're culled as a whole
+#[derive(Clone, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct Sequence {
+    pub(crate) kind: SequenceKind,
+    /// Unique within the dir, the selected frames are moved to a subfolder of this name
+    pub(crate) id: String,
+}
+
+/// Tags the frames of brackets, focus stacks and panoramas among the imgs sorted by capture
+///
+/// Panoramas are told apart from bursts by the frames not being near-identical,
+/// so they're found only once the previews have been analyzed
+pub(crate) fn tag_sequences(
+    imgs: &mut [Image],
+    config: &SequenceConfig,
+    similarity_threshold: u32,
+) {
+    let mut start = 0;
+    while start < imgs.len() {
+        let run = &imgs[start..];
+        let detected = [
+            (SequenceKind::Bracket, bracket_len(run, config)),
+            (SequenceKind::FocusStack, focus_stack_len(run, config)),
+            (
+                SequenceKind::Panorama,
+                panorama_len(run, config, similarity_threshold),
+            ),
+        ]
+        .into_iter()
+        .find(|(_, len)| *len >= config.min_frames.max(2));
+
+        let Some((kind, len)) = detected else {
+            imgs[start].sequence = None;
+            start += 1;
+            continue;
+        };
+
+        let stem = imgs[start].path.file_stem().unwrap_or_default();
+        let id = format!("{}_{}", kind.dir_prefix(), stem.to_string_lossy());
+        for img in &mut imgs[start..start + len] {
+            img.sequence = Some(Sequence {
+                kind,
+                id: id.clone(),
+            });
+        }
+        start += len;
+    }
+}
+
+/// Indices of the sorted imgs grouped by sequences
+pub(crate) fn sequence_indices(imgs: &[Image]) -> Vec<Vec<usize>> {
+    let mut sequences: Vec<Vec<usize>> = Vec::new();
+    for (i, img) in imgs.iter().enumerate() {
+        let Some(sequence) = &img.sequence else {
+            continue;
+        };
+
+        let id = |i: usize| imgs[i].sequence.as_ref().map(|sequence| &sequence.id);
+        match sequences.last_mut() {
+            Some(last) if id(last[0]) == Some(&sequence.id) => last.push(i),
+            _ => sequences.push(vec![i]),
+        }
+    }
+
+    sequences
+}
+
+/// Stepping exposure bias, or anything the camera reports as a bracket, at a fixed aperture
+fn bracket_len(run: &[Image], config: &SequenceConfig) -> usize {
+    chain_len(run, config, |chain, next| {
+        let bracketing = next.drive_mode == Some(DriveMode::Bracketing);
+        // the bias stays at 0 in the manual mode, so the exposure time is what steps
+        let exposure = |capture: &Capture| {
+            if bracketing {
+                (capture.exposure_bias, capture.exposure_time)
+            } else {
+                (capture.exposure_bias, None)
+            }
+        };
+
+        (bracketing || next.exposure_bias.is_some())
+            && chain
+                .first()
+                .is_none_or(|first| same(first.f_number, next.f_number))
+            // a repeated exposure starts the next bracket
+            && !chain.iter().any(|prev| {
+                let (prev, next) = (exposure(prev), exposure(next));
+                same(prev.0, next.0) && same(prev.1, next.1)
+            })
+    })
+}
+
+/// Focus distance stepping in one direction at fixed exposure settings
+fn focus_stack_len(run: &[Image], config: &SequenceConfig) -> usize {
+    chain_len(run, config, |chain, next| {
+        let Some(distance) = next.subject_distance else {
+            return false;
+        };
+        let (Some(first), Some(prev)) = (chain.first(), chain.last()) else {
+            return true;
+        };
+        if !same_exposure(first, next) {
+            return false;
+        }
+
+        // the whole chain has a distance
+        let distance_of = |capture: &Capture| capture.subject_distance.unwrap_or_default();
+        let step = distance - distance_of(prev);
+        let first_step = chain
+            .get(1)
+            .map_or(step, |second| distance_of(second) - distance_of(first));
+        !same(Some(distance), prev.subject_distance) && step.signum() == first_step.signum()
+    })
+}
+
+/// Fixed exposure settings with every frame overlapping the previous one only partially,
+/// near-identical frames are a burst rather than a panorama and unrelated ones are neither
+fn panorama_len(run: &[Image], config: &SequenceConfig, similarity_threshold: u32) -> usize {
+    let fixed_len = chain_len(run, config, |chain, next| {
+        next.drive_mode != Some(DriveMode::Continuous)
+            && chain.first().is_none_or(|first| same_exposure(first, next))
+            && chain.last().is_none_or(|prev| {
+                next.taken - prev.taken >= TimeDelta::milliseconds(MIN_PANORAMA_GAP_MS)
+            })
+    });
+
+    let hashes: Vec<u64> = run[..fixed_len]
+        .iter()
+        .map_while(|img| {
+            img.analysis
+                .as_ref()
+                .filter(|analysis| analysis.is_current())
+                .map(|analysis| analysis.dhash)
+        })
+        .collect();
+    if hashes.is_empty() {
+        return 0;
+    }
+
+    1 + hashes
+        .windows(2)
+        .take_while(|pair| {
+            let distance = (pair[0] ^ pair[1]).count_ones();
+            distance > similarity_threshold && distance <= MAX_PANORAMA_DISTANCE
+        })
+        .count()
+}
+
+/// Number of the leading frames of the run taken in quick succession by the same camera and lens,
+/// where every one continues the chain of the previous ones
+fn chain_len(
+    run: &[Image],
+    config: &SequenceConfig,
+    continues: impl Fn(&[&Capture], &Capture) -> bool,
+) -> usize {
+    let mut chain: Vec<&Capture> = Vec::new();
+    for img in run {
+        let Some(capture) = &img.capture else {
+            break;
+        };
+        if let Some(prev) = chain.last() {
+            if prev.camera != capture.camera
+                || !same(prev.focal_length, capture.focal_length)
+                || capture.taken - prev.taken > TimeDelta::milliseconds(config.max_gap_ms)
+            {
+                break;
+            }
+        }
+        if !continues(&chain, capture) {
+            break;
+        }
+        chain.push(capture);
+    }
+
+    chain.len()
+}
+
+/// Exposure time, aperture, ISO and bias are equal and the exposure time is known at least
+fn same_exposure(a: &Capture, b: &Capture) -> bool {
+    a.exposure_time.is_some()
+        && same(a.exposure_time, b.exposure_time)
+        && same(a.f_number, b.f_number)
+        && a.iso == b.iso
+        && same(a.exposure_bias, b.exposure_bias)
+}
+
+/// Settings missing in both are equal as well
+fn same(a: Option<f64>, b: Option<f64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() <= SETTINGS_TOLERANCE * a.abs().max(b.abs()),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{
+        test_util::{capture, captured},
+        ImageAnalysis,
+    };
+
+    /// Frames 1s apart with the same aperture and exposure time
+    fn frames(update: impl Fn(usize, &mut Capture), count: usize) -> Vec<Image> {
+        captured((0..count).map(|i| {
+            let mut capture = Capture {
+                exposure_time: Some(0.01),
+                f_number: Some(8.),
+                iso: Some(100),
+                focal_length: Some(24.),
+                ..capture(i64::try_from(i).unwrap() * 1000)
+            };
+            update(i, &mut capture);
+            capture
+        }))
+    }
+
+    fn tagged(mut imgs: Vec<Image>) -> Vec<(Option<SequenceKind>, Option<String>)> {
+        tag_sequences(&mut imgs, &SequenceConfig::default(), 10);
+        imgs.into_iter()
+            .map(|img| {
+                let sequence = img.sequence;
+                (sequence.as_ref().map(|s| s.kind), sequence.map(|s| s.id))
+            })
+            .collect()
+    }
+
+    fn kinds(imgs: Vec<Image>) -> Vec<Option<SequenceKind>> {
+        tagged(imgs).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn brackets() {
+        // a repeated bias starts the next bracket
+        let biases = [-2., 0., 2., -2., 0., 2., 0.];
+        let imgs = frames(|i, c| c.exposure_bias = Some(biases[i]), 7);
+        let tagged = tagged(imgs);
+        let ids: Vec<_> = tagged.iter().map(|(_, id)| id.as_deref()).collect();
+        assert_eq!(
+            ids,
+            [
+                Some("HDR_0"),
+                Some("HDR_0"),
+                Some("HDR_0"),
+                Some("HDR_3"),
+                Some("HDR_3"),
+                Some("HDR_3"),
+                None
+            ]
+        );
+
+        // the exposure time steps in the manual mode
+        let times = [0.01, 0.04, 0.0025];
+        let manual = frames(
+            |i, c| {
+                c.drive_mode = Some(DriveMode::Bracketing);
+                c.exposure_bias = Some(0.);
+                c.exposure_time = Some(times[i]);
+            },
+            3,
+        );
+        assert_eq!(kinds(manual), [Some(SequenceKind::Bracket); 3]);
+    }
+
+    #[test]
+    fn broken_brackets() {
+        let bias = |i: usize| Some([-1., 0., 1.][i]);
+        // the aperture changes
+        let imgs = frames(
+            |i, c| {
+                c.exposure_bias = bias(i);
+                c.f_number = Some(if i < 2 { 8. } else { 11. });
+            },
+            3,
+        );
+        assert_eq!(kinds(imgs), [None; 3]);
+        // too far apart
+        let imgs = frames(
+            |i, c| {
+                c.exposure_bias = bias(i);
+                c.taken += TimeDelta::seconds(i64::try_from(i).unwrap() * 2);
+            },
+            3,
+        );
+        assert_eq!(kinds(imgs), [None; 3]);
+        // another body
+        let imgs = frames(
+            |i, c| {
+                c.exposure_bias = bias(i);
+                c.camera = Some(format!("body {}", i / 2));
+            },
+            3,
+        );
+        assert_eq!(kinds(imgs), [None; 3]);
+        assert!(tagged(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn focus_stacks() {
+        // the distance turns around after the 4th frame
+        let distances = [1., 1.2, 1.4, 1.6, 1.5, 1.4];
+        let imgs = frames(|i, c| c.subject_distance = Some(distances[i]), 6);
+        let stack = Some(SequenceKind::FocusStack);
+        assert_eq!(kinds(imgs), [stack, stack, stack, stack, None, None]);
+
+        // exposure settings have to be known
+        let imgs = frames(
+            |i, c| {
+                c.subject_distance = Some(distances[i]);
+                c.exposure_time = None;
+            },
+            3,
+        );
+        assert_eq!(kinds(imgs), [None; 3]);
+    }
+
+    /// Frames analyzed with the given hashes
+    fn hashed(mut imgs: Vec<Image>, hashes: &[u64]) -> Vec<Image> {
+        for (img, hash) in imgs.iter_mut().zip(hashes) {
+            let mut analysis = ImageAnalysis::zeroed();
+            analysis.dhash = *hash;
+            img.analysis = Some(analysis);
+        }
+        imgs
+    }
+
+    /// Every frame shifts by a quarter of the hash
+    const PANNING: [u64; 4] = [0, 0xffff, 0xffff_ffff, 0xffff_ffff_ffff];
+
+    #[test]
+    fn panoramas() {
+        let slow = |hashes: &[u64]| hashed(frames(|_, _| {}, hashes.len()), hashes);
+
+        // the frames overlap only partially
+        let pano = Some(SequenceKind::Panorama);
+        assert_eq!(kinds(slow(&PANNING)), [pano; 4]);
+        // near-identical frames are a burst
+        assert_eq!(kinds(slow(&[0, 1, 3])), [None; 3]);
+        // unrelated frames share no content
+        assert_eq!(kinds(slow(&[0, u64::MAX, 0xffff_0000])), [None; 3]);
+        // not analyzed yet
+        assert_eq!(kinds(frames(|_, _| {}, 3)), [None; 3]);
+    }
+
+    #[test]
+    fn fast_runs_are_no_panoramas() {
+        // a fixed exposure burst following a subject across the frame
+        let fast = frames(
+            |i, c| c.taken = capture(i64::try_from(i).unwrap() * 100).taken,
+            PANNING.len(),
+        );
+        assert_eq!(kinds(hashed(fast, &PANNING)), [None; 4]);
+    }
+
+    #[test]
+    fn retagging() {
+        let mut imgs = frames(|i, c| c.subject_distance = Some([1., 2., 3., 4.][i]), 4);
+        tag_sequences(&mut imgs, &SequenceConfig::default(), 10);
+        assert_eq!(sequence_indices(&imgs), [vec![0, 1, 2, 3]]);
+
+        // the distance of a frame was read wrong before
+        imgs[3].capture.as_mut().unwrap().subject_distance = None;
+        tag_sequences(&mut imgs, &SequenceConfig::default(), 10);
+        assert_eq!(sequence_indices(&imgs), [vec![0, 1, 2]]);
+        assert!(imgs[3].sequence.is_none());
+    }
+}
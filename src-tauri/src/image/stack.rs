@@ -0,0 +1,447 @@
+use anyhow::{anyhow, ensure};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+use ts_rs::TS;
+
+use super::{
+    burst::{burst_indices, BurstConfig},
+    sequence::sequence_indices,
+    Image,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) enum StackKind {
+    Burst,
+    /// Bracket, focus stack or panorama
+    Sequence,
+    /// Near-identical imgs
+    Duplicates,
+    /// Grouped by the user
+    Manual,
+}
+
+impl StackKind {
+    fn id_prefix(self) -> &'static str {
+        match self {
+            StackKind::Burst => "Burst",
+            StackKind::Sequence => "Sequence",
+            StackKind::Duplicates => "Duplicates",
+            StackKind::Manual => "Group",
+        }
+    }
+}
+
+/// Stack of an img edited by the user, stored in the cull meta so it overrides the detection
+///
+/// An img split off a stack keeps a stack of its own, so it's not detected again
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StackMembership {
+    pub(crate) id: String,
+    pub(crate) kind: StackKind,
+}
+
+/// Imgs the cull screen steps through as a single scene and culls as a whole
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct ImageStack {
+    /// Unique within the dir
+    id: String,
+    kind: StackKind,
+    /// Preview paths in the capture order
+    images: Vec<PathBuf>,
+}
+
+/// Stacks of the sorted imgs, the ones edited by the user take precedence
+/// over the detected bursts and sequences
+pub(crate) fn group_stacks(imgs: &[Image], config: &BurstConfig) -> Vec<ImageStack> {
+    StackEditor::new(imgs, config).stacks()
+}
+
+/// Edits the stacks of the sorted imgs and tracks the imgs whose membership has to be persisted
+pub(crate) struct StackEditor<'a> {
+    imgs: &'a [Image],
+    /// Stack of every img
+    members: Vec<Option<StackMembership>>,
+    edited: BTreeSet<usize>,
+}
+
+impl<'a> StackEditor<'a> {
+    pub(crate) fn new(imgs: &'a [Image], config: &BurstConfig) -> Self {
+        let mut editor = Self {
+            imgs,
+            members: imgs.iter().map(|img| img.stack.clone()).collect(),
+            edited: BTreeSet::new(),
+        };
+
+        // the detection fills in the imgs the user hasn't touched
+        let detected = burst_indices(imgs, config)
+            .into_iter()
+            .map(|burst| (StackKind::Burst, burst))
+            .chain(
+                sequence_indices(imgs)
+                    .into_iter()
+                    .map(|sequence| (StackKind::Sequence, sequence)),
+            );
+        for (kind, stack) in detected {
+            let stack: Vec<usize> = stack
+                .into_iter()
+                .filter(|i| editor.members[*i].is_none())
+                .collect();
+            if stack.len() < 2 {
+                continue;
+            }
+
+            // sequences are named after their subfolder
+            let id = match &imgs[stack[0]].sequence {
+                Some(sequence) if kind == StackKind::Sequence => editor.unique_id(&sequence.id),
+                _ => editor.new_id(kind, stack[0]),
+            };
+            for i in stack {
+                editor.members[i] = Some(StackMembership {
+                    id: id.clone(),
+                    kind,
+                });
+            }
+        }
+
+        editor
+    }
+
+    /// Stacks of at least 2 imgs in the capture order
+    pub(crate) fn stacks(&self) -> Vec<ImageStack> {
+        self.stack_indices()
+            .into_iter()
+            .map(|stack| {
+                let member = self.members[stack[0]]
+                    .as_ref()
+                    .expect("Stacked imgs have a stack");
+                ImageStack {
+                    id: member.id.clone(),
+                    kind: member.kind,
+                    images: stack
+                        .into_iter()
+                        .map(|i| self.imgs[i].preview_path.clone())
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Indices of the imgs grouped by the stacks of at least 2 imgs
+    pub(crate) fn stack_indices(&self) -> Vec<Vec<usize>> {
+        let mut stacks: Vec<Vec<usize>> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for (i, member) in self.members.iter().enumerate() {
+            let Some(member) = member else {
+                continue;
+            };
+
+            let pos = *positions.entry(&member.id).or_insert_with(|| {
+                stacks.push(Vec::new());
+                stacks.len() - 1
+            });
+            stacks[pos].push(i);
+        }
+
+        stacks.retain(|stack| stack.len() > 1);
+        stacks
+    }
+
+    /// Indices of the imgs in the stack of the img, which is just the img when it's not stacked
+    pub(crate) fn stack_of(&self, image: &Path) -> anyhow::Result<Vec<usize>> {
+        let i = self.index(image)?;
+        Ok(match &self.members[i] {
+            Some(member) => self.members_of(&member.id),
+            None => vec![i],
+        })
+    }
+
+    /// Moves the imgs from their stacks to a new one
+    pub(crate) fn create(&mut self, images: &[PathBuf], kind: StackKind) -> anyhow::Result<()> {
+        let mut stack = images
+            .iter()
+            .map(|image| self.index(image))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        stack.sort_unstable();
+        stack.dedup();
+        ensure!(stack.len() > 1, "A stack needs at least 2 imgs");
+
+        // the rest of the stacks the imgs are taken from stays stacked as it is
+        let sources: Vec<usize> = stack
+            .iter()
+            .filter_map(|i| self.members[*i].as_ref())
+            .flat_map(|member| self.members_of(&member.id))
+            .collect();
+        self.edited.extend(sources);
+
+        let id = self.new_id(kind, stack[0]);
+        self.assign(&stack, &id, kind);
+        Ok(())
+    }
+
+    /// The img and the following frames of its stack are split off to a new stack
+    pub(crate) fn split(&mut self, image: &Path) -> anyhow::Result<()> {
+        let i = self.index(image)?;
+        let member = self.members[i]
+            .clone()
+            .ok_or_else(|| anyhow!("{image:?} is not stacked"))?;
+        let stack = self.members_of(&member.id);
+        ensure!(stack[0] != i, "{image:?} starts the stack already");
+
+        self.edited.extend(&stack);
+        let tail: Vec<usize> = stack.into_iter().filter(|j| *j >= i).collect();
+        let id = self.new_id(member.kind, i);
+        self.assign(&tail, &id, member.kind);
+        Ok(())
+    }
+
+    /// Joins the stacks of the imgs into the first one, unstacked imgs join as they are
+    pub(crate) fn merge(&mut self, images: &[PathBuf]) -> anyhow::Result<()> {
+        let mut stack = Vec::new();
+        for image in images {
+            stack.extend(self.stack_of(image)?);
+        }
+        stack.sort_unstable();
+        stack.dedup();
+        ensure!(stack.len() > 1, "Nothing to merge");
+
+        let first = self.members[stack[0]].clone();
+        // eg. a burst joined with another one stays a burst
+        let kind = match &first {
+            Some(first)
+                if stack
+                    .iter()
+                    .all(|i| self.members[*i].as_ref().map(|m| m.kind) == Some(first.kind)) =>
+            {
+                first.kind
+            }
+            _ => StackKind::Manual,
+        };
+        let id = first.map_or_else(|| self.new_id(kind, stack[0]), |first| first.id);
+        self.assign(&stack, &id, kind);
+        Ok(())
+    }
+
+    /// Imgs whose stack has changed along with the stack to persist
+    pub(crate) fn edited(&self) -> Vec<(&Image, StackMembership)> {
+        self.edited
+            .iter()
+            .filter_map(|i| Some((&self.imgs[*i], self.members[*i].clone()?)))
+            .collect()
+    }
+
+    fn assign(&mut self, stack: &[usize], id: &str, kind: StackKind) {
+        for i in stack {
+            self.members[*i] = Some(StackMembership {
+                id: id.to_owned(),
+                kind,
+            });
+        }
+        self.edited.extend(stack);
+    }
+
+    fn index(&self, image: &Path) -> anyhow::Result<usize> {
+        self.imgs
+            .iter()
+            .position(|img| img.preview_path == image)
+            .ok_or_else(|| anyhow!("{image:?} is not in the open dir"))
+    }
+
+    fn members_of(&self, id: &str) -> Vec<usize> {
+        self.members
+            .iter()
+            .enumerate()
+            .filter(|(_, member)| member.as_ref().is_some_and(|member| member.id == id))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Named after the first img
+    fn new_id(&self, kind: StackKind, first: usize) -> String {
+        let stem = self.imgs[first]
+            .path
+            .file_stem()
+            .map_or(Cow::Borrowed(""), |stem| stem.to_string_lossy());
+        self.unique_id(&format!("{}_{stem}", kind.id_prefix()))
+    }
+
+    /// The first img might have left a stack named after it
+    fn unique_id(&self, id: &str) -> String {
+        let taken = |id: &str| {
+            self.members
+                .iter()
+                .any(|member| member.as_ref().is_some_and(|member| member.id == id))
+        };
+
+        let mut unique = id.to_owned();
+        let mut n = 1;
+        while taken(&unique) {
+            n += 1;
+            unique = format!("{id}_{n}");
+        }
+        unique
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{
+        burst::sort_by_capture,
+        sequence::SequenceKind,
+        test_util::{capture, captured, names},
+        Sequence,
+    };
+
+    fn preview(name: &str) -> PathBuf {
+        format!("{name}.webp").into()
+    }
+
+    /// Id, kind and the file stems of every stack
+    fn summary(stacks: &[ImageStack]) -> Vec<(String, StackKind, Vec<String>)> {
+        stacks
+            .iter()
+            .map(|stack| (stack.id.clone(), stack.kind, names(&stack.images)))
+            .collect()
+    }
+
+    fn stack(id: &str, kind: StackKind, names: &[&str]) -> (String, StackKind, Vec<String>) {
+        (
+            id.to_owned(),
+            kind,
+            names.iter().map(|name| (*name).to_owned()).collect(),
+        )
+    }
+
+    #[test]
+    fn detection() {
+        let mut imgs = captured([0, 100, 200, 300, 400, 5000].map(capture));
+        for img in &mut imgs[..3] {
+            img.sequence = Some(Sequence {
+                kind: SequenceKind::Bracket,
+                id: "Bracket_0".to_owned(),
+            });
+        }
+
+        // the frames of a sequence are not part of the burst right after it
+        let editor = StackEditor::new(&imgs, &BurstConfig::default());
+        assert_eq!(
+            summary(&editor.stacks()),
+            [
+                stack("Bracket_0", StackKind::Sequence, &["0", "1", "2"]),
+                stack("Burst_3", StackKind::Burst, &["3", "4"]),
+            ]
+        );
+        assert!(editor.edited().is_empty());
+        assert_eq!(editor.stack_of(&preview("1")).unwrap(), [0, 1, 2]);
+        assert_eq!(editor.stack_of(&preview("5")).unwrap(), [5]);
+    }
+
+    #[test]
+    fn edits() {
+        let mut imgs = captured([0, 100, 2000, 2100, 2200, 5000].map(capture));
+        let config = BurstConfig::default();
+        let mut editor = StackEditor::new(&imgs, &config);
+
+        // the rest of the burst is left on its own
+        editor.split(&preview("3")).unwrap();
+        assert_eq!(
+            summary(&editor.stacks()),
+            [
+                stack("Burst_0", StackKind::Burst, &["0", "1"]),
+                stack("Burst_3", StackKind::Burst, &["3", "4"]),
+            ]
+        );
+        assert!(editor.split(&preview("3")).is_err());
+        assert!(editor.split(&preview("5")).is_err());
+
+        // an unstacked img makes it a manual stack
+        editor.merge(&[preview("1"), preview("5")]).unwrap();
+        assert_eq!(
+            summary(&editor.stacks()),
+            [
+                stack("Burst_0", StackKind::Manual, &["0", "1", "5"]),
+                stack("Burst_3", StackKind::Burst, &["3", "4"]),
+            ]
+        );
+        assert!(editor.merge(&[preview("2")]).is_err());
+
+        editor
+            .create(&[preview("5"), preview("4")], StackKind::Duplicates)
+            .unwrap();
+        assert_eq!(
+            summary(&editor.stacks()),
+            [
+                stack("Burst_0", StackKind::Manual, &["0", "1"]),
+                stack("Duplicates_4", StackKind::Duplicates, &["4", "5"]),
+            ]
+        );
+        assert!(editor.create(&[preview("0")], StackKind::Manual).is_err());
+        assert!(editor
+            .create(&[preview("0"), preview("missing")], StackKind::Manual)
+            .is_err());
+
+        // the persisted stacks override the detection
+        let edited: Vec<(PathBuf, StackMembership)> = editor
+            .edited()
+            .into_iter()
+            .map(|(img, stack)| (img.preview_path.clone(), stack))
+            .collect();
+        assert_eq!(
+            names(edited.iter().map(|(path, _)| path)),
+            ["0", "1", "2", "3", "4", "5"]
+        );
+        let stacks = editor.stacks();
+        for (path, stack) in edited {
+            let img = imgs
+                .iter_mut()
+                .find(|img| img.preview_path == path)
+                .unwrap();
+            img.stack = Some(stack);
+        }
+        assert_eq!(summary(&group_stacks(&imgs, &config)), summary(&stacks));
+    }
+
+    #[test]
+    fn empty_dirs() {
+        let config = BurstConfig::default();
+        assert!(group_stacks(&[], &config).is_empty());
+
+        let mut editor = StackEditor::new(&[], &config);
+        assert!(editor.stack_indices().is_empty());
+        assert!(editor.stack_of(&preview("0")).is_err());
+        assert!(editor.split(&preview("0")).is_err());
+        assert!(editor.merge(&[]).is_err());
+        assert!(editor.create(&[], StackKind::Manual).is_err());
+        assert!(editor.edited().is_empty());
+    }
+
+    #[test]
+    fn ties() {
+        // frames taken at the same time keep the order they were listed in
+        let mut imgs = captured([2000, 2000, 0, 0, 0].map(capture));
+        sort_by_capture(&mut imgs);
+        assert_eq!(
+            summary(&group_stacks(&imgs, &BurstConfig::default())),
+            [
+                stack("Burst_2", StackKind::Burst, &["2", "3", "4"]),
+                stack("Burst_0", StackKind::Burst, &["0", "1"]),
+            ]
+        );
+
+        // stacks starting with imgs of the same name
+        let mut imgs = captured([0, 100, 2000, 2100].map(capture));
+        imgs[2].path = PathBuf::from("other").join("0.jpg");
+        let stacks = group_stacks(&imgs, &BurstConfig::default());
+        let ids: Vec<&str> = stacks.iter().map(|stack| stack.id.as_str()).collect();
+        assert_eq!(ids, ["Burst_0", "Burst_0_2"]);
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::{NaiveDate, TimeDelta, Utc};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use super::{Capture, CullState, Image, ImageAnalysis};
+
+/// Empty dir of the test in the system temp dir
+pub(super) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chela-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Gradient JPEG of the given size
+pub(super) fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    #[allow(clippy::cast_possible_truncation)]
+    let img = RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+    });
+    let mut buf = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut buf, ImageFormat::Jpeg)
+        .unwrap();
+    buf.into_inner()
+}
+
+/// File stems, to compare the order of imgs
+pub(super) fn names<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> Vec<String> {
+    paths
+        .into_iter()
+        .map(|path| path.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Img named after the file stem without a preview or analysis
+pub(super) fn image(dir: &Path, name: &str) -> Image {
+    Image {
+        path: dir.join(format!("{name}.jpg")),
+        preview_path: dir.join(format!("{name}.webp")),
+        created: Utc::now(),
+        state: CullState::New,
+        preview_error: None,
+        analysis: None,
+        suggested_reject: false,
+        blank: false,
+        capture: None,
+        sequence: None,
+        stack: None,
+    }
+}
+
+/// Img with a current analysis, which is zeroed but for the update
+pub(super) fn analyzed(name: &str, update: impl FnOnce(&mut ImageAnalysis)) -> Image {
+    let mut analysis = ImageAnalysis::zeroed();
+    update(&mut analysis);
+    Image {
+        analysis: Some(analysis),
+        ..image(Path::new(""), name)
+    }
+}
+
+/// Capture the given ms after a fixed time, nothing else is recorded
+pub(super) fn capture(ms: i64) -> Capture {
+    Capture {
+        taken: NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            + TimeDelta::milliseconds(ms),
+        camera: None,
+        drive_mode: None,
+        sequence: None,
+        exposure_time: None,
+        f_number: None,
+        iso: None,
+        exposure_bias: None,
+        focal_length: None,
+        subject_distance: None,
+    }
+}
+
+/// Imgs named after their position with the captures
+pub(super) fn captured(captures: impl IntoIterator<Item = Capture>) -> Vec<Image> {
+    captures
+        .into_iter()
+        .enumerate()
+        .map(|(i, capture)| Image {
+            capture: Some(capture),
+            ..image(Path::new(""), &i.to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, MutexGuard};
+use ts_rs::TS;
+
+use super::{
+    file::{temp_path, write_atomic},
+    generator::PreviewGenerators,
+    native::{self, DecodedImage, DecodedSource},
+    queue::CancelToken,
+    scheduler::{JobContext, Scheduler},
+    Image,
+};
+
+const TILE_SIZE: u32 = 512;
+const TILES_EXT: &str = "tiles";
+const INFO_FILE: &str = "info.json";
+/// Full res rendition of an img the backends write, decoded and removed right away
+const SOURCE_FILE: &str = "source.png";
+/// Tiles of another version are rendered again
+const TILES_VERSION: u32 = 2;
+/// Originals are huge, so only the last zoomed ones are kept
+const MAX_SOURCES: usize = 2;
+
+/// Deep zoom pyramid of an img, the highest level is the original size
+/// and every level below halves it down to a single pixel
+#[derive(Clone, Copy, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub(crate) struct TileInfo {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    max_level: u32,
+    #[ts(skip)]
+    version: u32,
+}
+
+impl TileInfo {
+    fn new(width: u32, height: u32) -> Self {
+        let longest = width.max(height).max(1);
+        Self {
+            width,
+            height,
+            tile_size: TILE_SIZE,
+            // ceil(log2(longest))
+            max_level: u32::BITS - (longest - 1).leading_zeros(),
+            version: TILES_VERSION,
+        }
+    }
+
+    fn tile_bounds(&self, level: u32, x: u32, y: u32) -> Option<TileBounds> {
+        let scale = 1_u32.checked_shl(self.max_level.checked_sub(level)?)?;
+        let level_width = self.width.div_ceil(scale);
+        let level_height = self.height.div_ceil(scale);
+
+        let left = x
+            .checked_mul(TILE_SIZE)
+            .filter(|left| *left < level_width)?;
+        let top = y.checked_mul(TILE_SIZE).filter(|top| *top < level_height)?;
+        let width = TILE_SIZE.min(level_width - left);
+        let height = TILE_SIZE.min(level_height - top);
+
+        let src_left = left * scale;
+        let src_top = top * scale;
+        Some(TileBounds {
+            src_left,
+            src_top,
+            src_width: (width * scale).min(self.width - src_left),
+            src_height: (height * scale).min(self.height - src_top),
+            width,
+            height,
+        })
+    }
+}
+
+/// Pixels of the original covered by a tile and the size they're scaled to
+#[derive(Debug, PartialEq)]
+struct TileBounds {
+    src_left: u32,
+    src_top: u32,
+    src_width: u32,
+    src_height: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Decoded original of a zoomed img, locked per img so others are zoomed meanwhile
+type TileSource = Arc<Mutex<Option<Arc<DecodedImage>>>>;
+
+/// Renders the tiles on demand and stores them next to the preview of the img
+pub(crate) struct TileRenderer {
+    generators: Arc<PreviewGenerators>,
+    scheduler: Arc<Scheduler>,
+    /// Keyed by the img path, the most recently zoomed last
+    sources: std::sync::Mutex<VecDeque<(PathBuf, TileSource)>>,
+}
+
+impl TileRenderer {
+    pub(crate) fn new(generators: &Arc<PreviewGenerators>, scheduler: &Arc<Scheduler>) -> Self {
+        Self {
+            generators: Arc::clone(generators),
+            scheduler: Arc::clone(scheduler),
+            sources: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Source of the img, the least recently zoomed one is dropped for a new one
+    fn source(&self, img: &Image) -> TileSource {
+        let mut sources = self
+            .sources
+            .lock()
+            .expect("Tile sources lock is not poisoned");
+        if let Some(i) = sources.iter().position(|(path, _)| *path == img.path) {
+            let source = sources.remove(i).expect("The source is at the index");
+            sources.push_back(source);
+        } else {
+            if sources.len() == MAX_SOURCES {
+                sources.pop_front();
+            }
+            sources.push_back((img.path.clone(), TileSource::default()));
+        }
+
+        Arc::clone(&sources.back().expect("The source has been pushed").1)
+    }
+
+    /// Dimensions of the original at full res
+    pub(crate) async fn info(&self, img: &Image) -> anyhow::Result<TileInfo> {
+        let dir = tiles_dir(&img.preview_path);
+        let path = dir.join(INFO_FILE);
+        if let Some(info) = read_info(&path).await {
+            return Ok(info);
+        }
+
+        // held until the info is written, so concurrent requests don't clear each other's tiles
+        let source = self.source(img);
+        let mut source = source.lock().await;
+        if let Some(info) = read_info(&path).await {
+            return Ok(info);
+        }
+
+        let decoded = self.decode(&mut source, img).await?;
+        let info = TileInfo::new(decoded.img.width(), decoded.img.height());
+
+        // left over from another version
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+        let json = serde_json::to_vec(&info)?;
+        tokio::task::spawn_blocking(move || write_atomic(&path, json))
+            .await?
+            .context("Failed to write tile info")?;
+
+        Ok(info)
+    }
+
+    /// Path of the rendered tile
+    pub(crate) async fn tile(
+        &self,
+        img: &Image,
+        level: u32,
+        x: u32,
+        y: u32,
+    ) -> anyhow::Result<PathBuf> {
+        // first, so the tiles of another version are gone
+        let info = self.info(img).await?;
+        let path = tiles_dir(&img.preview_path)
+            .join(level.to_string())
+            .join(format!("{x}_{y}.webp"));
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let bounds = info
+            .tile_bounds(level, x, y)
+            .ok_or(anyhow!("Tile {level}/{x}/{y} is out of bounds"))?;
+        let decoded = {
+            let source = self.source(img);
+            let mut source = source.lock().await;
+            self.decode(&mut source, img).await?
+        };
+        let color = self.generators.color();
+
+        let webp = tokio::task::spawn_blocking(move || {
+            let mut tile = decoded.img.crop_imm(
+                bounds.src_left,
+                bounds.src_top,
+                bounds.src_width,
+                bounds.src_height,
+            );
+            if (bounds.src_width, bounds.src_height) != (bounds.width, bounds.height) {
+                tile = tile.thumbnail_exact(bounds.width, bounds.height);
+            }
+
+            native::encode_managed(tile, &decoded.color, &color, &mut Vec::new())
+        })
+        .await??;
+
+        let dir = path.parent().expect("Tile is in a level dir");
+        tokio::fs::create_dir_all(dir).await?;
+        let tile_path = path.clone();
+        tokio::task::spawn_blocking(move || write_atomic(&tile_path, webp))
+            .await?
+            .context(format!("Failed to write tile {path:?}"))?;
+
+        Ok(path)
+    }
+
+    /// Decodes the original unless it's been decoded already
+    async fn decode(
+        &self,
+        source: &mut MutexGuard<'_, Option<Arc<DecodedImage>>>,
+        img: &Image,
+    ) -> anyhow::Result<Arc<DecodedImage>> {
+        if let Some(decoded) = source.as_ref() {
+            return Ok(Arc::clone(decoded));
+        }
+
+        let generators = Arc::clone(&self.generators);
+        let scheduler = Arc::clone(&self.scheduler);
+        let original = img.clone();
+        let decoded = tokio::task::spawn_blocking(move || {
+            let dir = tiles_dir(&original.preview_path);
+            std::fs::create_dir_all(&dir)?;
+            let ctx = JobContext {
+                cancel: &CancelToken::default(),
+                scheduler: &scheduler,
+                decoded: &DecodedSource::default(),
+            };
+            generators.decode_full_res(&original, &temp_path(&dir.join(SOURCE_FILE)), &ctx)
+        })
+        .await??;
+        **source = Some(Arc::clone(&decoded));
+
+        Ok(decoded)
+    }
+}
+
+async fn read_info(path: &Path) -> Option<TileInfo> {
+    let info: TileInfo = serde_json::from_slice(&tokio::fs::read(path).await.ok()?).ok()?;
+    (info.version == TILES_VERSION).then_some(info)
+}
+
+fn tiles_dir(preview_path: &Path) -> PathBuf {
+    preview_path.with_extension(TILES_EXT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{generator::PreviewConfig, scheduler::SchedulerConfig, test_util::image};
+
+    fn bounds(src: (u32, u32, u32, u32), size: (u32, u32)) -> TileBounds {
+        TileBounds {
+            src_left: src.0,
+            src_top: src.1,
+            src_width: src.2,
+            src_height: src.3,
+            width: size.0,
+            height: size.1,
+        }
+    }
+
+    #[test]
+    fn full_res_tiles() {
+        let info = TileInfo::new(1000, 600);
+        assert_eq!(info.max_level, 10);
+
+        assert_eq!(
+            info.tile_bounds(10, 0, 0),
+            Some(bounds((0, 0, 512, 512), (512, 512)))
+        );
+        // the last tiles are partial
+        assert_eq!(
+            info.tile_bounds(10, 1, 1),
+            Some(bounds((512, 512, 488, 88), (488, 88)))
+        );
+        assert_eq!(info.tile_bounds(10, 2, 0), None);
+        assert_eq!(info.tile_bounds(10, 0, 2), None);
+        assert_eq!(info.tile_bounds(11, 0, 0), None);
+    }
+
+    #[test]
+    fn scaled_tiles() {
+        let info = TileInfo::new(1000, 600);
+        // a single tile from the whole original
+        assert_eq!(
+            info.tile_bounds(9, 0, 0),
+            Some(bounds((0, 0, 1000, 600), (500, 300)))
+        );
+        assert_eq!(info.tile_bounds(9, 1, 0), None);
+        // a single pixel
+        assert_eq!(
+            info.tile_bounds(0, 0, 0),
+            Some(bounds((0, 0, 1000, 600), (1, 1)))
+        );
+        assert_eq!(info.tile_bounds(0, 0, 1), None);
+    }
+
+    #[test]
+    fn odd_sized_tiles() {
+        // just above a power of 2
+        let info = TileInfo::new(1025, 3);
+        assert_eq!(info.max_level, 11);
+        assert_eq!(
+            info.tile_bounds(11, 2, 0),
+            Some(bounds((1024, 0, 1, 3), (1, 3)))
+        );
+        // the last column of the level covers a single px of the original, its 2 rows all 3
+        assert_eq!(
+            info.tile_bounds(10, 1, 0),
+            Some(bounds((1024, 0, 1, 3), (1, 2)))
+        );
+
+        let pixel = TileInfo::new(1, 1);
+        assert_eq!(pixel.max_level, 0);
+        assert_eq!(
+            pixel.tile_bounds(0, 0, 0),
+            Some(bounds((0, 0, 1, 1), (1, 1)))
+        );
+    }
+
+    #[test]
+    fn sources_per_img() {
+        let renderer = TileRenderer::new(
+            &Arc::new(PreviewGenerators::new(&PreviewConfig::default())),
+            &Arc::new(Scheduler::new(SchedulerConfig::default())),
+        );
+        let [a, b, c] = ["a", "b", "c"].map(|name| image(Path::new("dir"), name));
+
+        let first = renderer.source(&a);
+        assert!(Arc::ptr_eq(&first, &renderer.source(&a)));
+        assert!(!Arc::ptr_eq(&first, &renderer.source(&b)));
+        // the least recently zoomed one is dropped
+        renderer.source(&a);
+        renderer.source(&c);
+        assert!(Arc::ptr_eq(&first, &renderer.source(&a)));
+        let paths: Vec<_> = renderer
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        assert_eq!(paths, [c.path, a.path]);
+    }
+}
@@ -3,6 +3,7 @@
 #![allow(clippy::cast_precision_loss)]
 
 mod app;
+mod config;
 mod image;
 mod preview_api;
 
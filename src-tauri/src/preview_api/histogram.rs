@@ -0,0 +1,40 @@
+use super::preview::wait_for_preview;
+use crate::image::{exposure, Exposure, PreviewMap, PreviewQueue};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc};
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    /// Preview path of the img
+    image: PathBuf,
+}
+
+/// The histogram is computed from the screen sized preview
+pub(crate) async fn histogram(
+    query: Query<QueryParams>,
+    previews: State<PreviewMap>,
+    queue: State<Arc<PreviewQueue>>,
+) -> Result<Json<Exposure>, StatusCode> {
+    if wait_for_preview(&previews, &queue, &query.image)
+        .await?
+        .is_some()
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // normally analyzed along with the preview gen already
+    let preview_path = query.image.clone();
+    tokio::task::spawn_blocking(move || exposure(&preview_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| {
+            println!("Failed to get the histogram of {:?}: {e:#}", query.image);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
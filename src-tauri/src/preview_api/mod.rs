@@ -1,22 +1,33 @@
-use crate::image::PreviewMap;
+use crate::image::PreviewPipeline;
 use axum::{
+    http::{HeaderValue, Method},
     routing::{get, IntoMakeService},
     serve::Serve,
     Router,
 };
 use std::net::SocketAddr;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 mod health;
+mod histogram;
+mod overlay;
 mod preview;
 mod state;
+mod tiles;
 
 pub(crate) async fn get_preview_api_server(
-    previews: PreviewMap,
+    pipeline: &PreviewPipeline,
 ) -> (SocketAddr, Serve<IntoMakeService<Router>, Router>) {
     let app = Router::new()
         .route("/", get(health::health))
         .route("/preview", get(preview::preview))
-        .with_state(state::PreviewApiState::new(previews));
+        .route("/overlay/focus", get(overlay::focus))
+        .route("/overlay/clipping", get(overlay::clipping))
+        .route("/histogram", get(histogram::histogram))
+        .route("/tiles/:image", get(tiles::tile_info))
+        .route("/tiles/:image/:level/:x/:y", get(tiles::tile))
+        .with_state(state::PreviewApiState::new(pipeline))
+        .layer(cors());
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
 
@@ -25,3 +36,19 @@ pub(crate) async fn get_preview_api_server(
         axum::serve(listener, app.into_make_service()),
     )
 }
+
+/// The webview fetches the JSON endpoints, only the app itself may read them
+fn cors() -> CorsLayer {
+    let mut origins = vec![
+        HeaderValue::from_static("tauri://localhost"),
+        HeaderValue::from_static("https://tauri.localhost"),
+    ];
+    // vite dev server
+    if cfg!(debug_assertions) {
+        origins.push(HeaderValue::from_static("http://localhost:1420"));
+    }
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET])
+}
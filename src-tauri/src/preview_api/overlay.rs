@@ -0,0 +1,68 @@
+use super::preview::{serve_webp, wait_for_preview};
+use crate::image::{create_focus_overlay, PreviewCache, PreviewMap, PreviewQueue, PreviewSize};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Response,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc};
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    /// Preview path of the img
+    image: PathBuf,
+    /// Size of the preview the overlay is aligned with
+    #[serde(default)]
+    size: PreviewSize,
+}
+
+pub(crate) async fn focus(
+    query: Query<QueryParams>,
+    previews: State<PreviewMap>,
+    cache: State<Option<Arc<PreviewCache>>>,
+    queue: State<Arc<PreviewQueue>>,
+) -> Result<Response, StatusCode> {
+    let preview_path = query.size.path(&query.image);
+
+    // the overlay is computed from the preview
+    if wait_for_preview(&previews, &queue, &preview_path)
+        .await?
+        .is_some()
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let path = tokio::task::spawn_blocking(move || create_focus_overlay(&preview_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            println!("Failed to create focus overlay: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    serve_webp(&path, cache.as_deref()).await
+}
+
+#[derive(Deserialize)]
+pub struct ClippingQueryParams {
+    /// Preview path of the img
+    image: PathBuf,
+}
+
+/// Queued after the previews, unlike the placeholder of a failed preview
+/// a failed overlay is not served to keep the img visible
+pub(crate) async fn clipping(
+    query: Query<ClippingQueryParams>,
+    previews: State<PreviewMap>,
+    cache: State<Option<Arc<PreviewCache>>>,
+    queue: State<Arc<PreviewQueue>>,
+) -> Result<Response, StatusCode> {
+    let path = PreviewSize::Clipping.path(&query.image);
+
+    if wait_for_preview(&previews, &queue, &path).await?.is_some() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    serve_webp(&path, cache.as_deref()).await
+}
@@ -1,38 +1,112 @@
-use crate::image::PreviewMap;
+use crate::image::{PreviewCache, PreviewMap, PreviewQueue, PreviewSize, PreviewStatus};
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
+    http::{HeaderName, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use axum_extra::body::AsyncReadBody;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const PREVIEW_ERROR_HEADER: &str = "x-preview-error";
+
+/// Served for previews which have failed to generate
+const FAILED_PLACEHOLDER: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 300 200"><rect width="300" height="200" fill="#2d2d2d"/><path d="M120 70h60v50h-60z" fill="none" stroke="#888" stroke-width="4"/><path d="M120 120l20-22 14 14 10-10 16 18" fill="none" stroke="#888" stroke-width="4"/><path d="M110 60l80 80" stroke="#c55" stroke-width="5"/><text x="150" y="170" fill="#aaa" font-family="sans-serif" font-size="16" text-anchor="middle">Preview failed</text></svg>"##;
 
 #[derive(Deserialize)]
 pub struct QueryParams {
     path: PathBuf,
+    #[serde(default)]
+    size: PreviewSize,
 }
 
 pub(crate) async fn preview(
     query: Query<QueryParams>,
     previews: State<PreviewMap>,
+    cache: State<Option<Arc<PreviewCache>>>,
+    queue: State<Arc<PreviewQueue>>,
 ) -> Result<Response, StatusCode> {
-    match previews.read().await.get(&query.path) {
-        Some(file_notif) => {
-            // if the img is being processed then wait for the img generation to finish
-            if let Some(notify) = file_notif.read().await.as_ref() {
-                notify.notified().await;
-            }
+    let path = query.size.path(&query.path);
 
-            let file = tokio::fs::File::open(&query.path)
-                .await
-                .map_err(|_| StatusCode::NOT_FOUND)?;
+    if let Some(error) = wait_for_preview(&previews, &queue, &path).await? {
+        let headers = [
+            (
+                hyper::header::CONTENT_TYPE,
+                HeaderValue::from_static("image/svg+xml"),
+            ),
+            (
+                HeaderName::from_static(PREVIEW_ERROR_HEADER),
+                // the reason might contain chars which are not allowed in a header
+                HeaderValue::from_str(&error).unwrap_or(HeaderValue::from_static("invalid reason")),
+            ),
+        ];
+        return Ok((headers, FAILED_PLACEHOLDER).into_response());
+    }
 
-            let body = AsyncReadBody::new(file);
-            let headers = [(hyper::header::CONTENT_TYPE, "image/webp")];
+    serve_webp(&path, cache.as_deref()).await
+}
 
-            Ok((headers, body).into_response())
+/// Waits for the generation of a pending preview to finish
+///
+/// Returns the error of a failed preview
+pub(super) async fn wait_for_preview(
+    previews: &PreviewMap,
+    queue: &PreviewQueue,
+    path: &Path,
+) -> Result<Option<String>, StatusCode> {
+    let notify;
+    let notified = {
+        let previews = previews.read().await;
+        let status = previews
+            .get(path)
+            .ok_or(StatusCode::NOT_FOUND)?
+            .read()
+            .await;
+        match &*status {
+            PreviewStatus::Pending(pending) => {
+                notify = Arc::clone(pending);
+                // the status can't settle before it's registered, so the notification isn't missed
+                notify.notified()
+            }
+            PreviewStatus::Ready => return Ok(None),
+            PreviewStatus::Failed(error) => return Ok(Some(error.clone())),
         }
-        None => Err(StatusCode::NOT_FOUND),
+    };
+
+    // the viewer is waiting, so generate it next
+    queue.prioritize(path);
+    notified.await;
+
+    let previews = previews.read().await;
+    let status = previews
+        .get(path)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .read()
+        .await;
+    let error = match &*status {
+        PreviewStatus::Failed(error) => Some(error.clone()),
+        _ => None,
+    };
+    Ok(error)
+}
+
+pub(super) async fn serve_webp(
+    path: &Path,
+    cache: Option<&PreviewCache>,
+) -> Result<Response, StatusCode> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    if let Some(cache) = cache {
+        cache.touch(path);
     }
+
+    let body = AsyncReadBody::new(file);
+    let headers = [(hyper::header::CONTENT_TYPE, "image/webp")];
+
+    Ok((headers, body).into_response())
 }
@@ -1,18 +1,24 @@
-use crate::image::PreviewMap;
+use crate::image::{PreviewCache, PreviewMap, PreviewPipeline, PreviewQueue, TileRenderer};
 use axum::extract::FromRef;
 use std::{sync::Arc, time::Instant};
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub(crate) struct PreviewApiState {
     health: HealthState,
     previews: PreviewMap,
+    cache: Option<Arc<PreviewCache>>,
+    queue: Arc<PreviewQueue>,
+    tiles: Arc<TileRenderer>,
 }
 
 impl PreviewApiState {
-    pub(crate) fn new(previews: PreviewMap) -> Self {
+    pub(crate) fn new(pipeline: &PreviewPipeline) -> Self {
         Self {
             health: HealthState::default(),
-            previews,
+            previews: Arc::clone(&pipeline.previews),
+            cache: pipeline.cache.clone(),
+            queue: Arc::clone(&pipeline.queue),
+            tiles: Arc::clone(&pipeline.tiles),
         }
     }
 }
@@ -41,3 +47,21 @@ impl FromRef<PreviewApiState> for PreviewMap {
         Arc::clone(&app_state.previews)
     }
 }
+
+impl FromRef<PreviewApiState> for Option<Arc<PreviewCache>> {
+    fn from_ref(app_state: &PreviewApiState) -> Option<Arc<PreviewCache>> {
+        app_state.cache.clone()
+    }
+}
+
+impl FromRef<PreviewApiState> for Arc<PreviewQueue> {
+    fn from_ref(app_state: &PreviewApiState) -> Arc<PreviewQueue> {
+        Arc::clone(&app_state.queue)
+    }
+}
+
+impl FromRef<PreviewApiState> for Arc<TileRenderer> {
+    fn from_ref(app_state: &PreviewApiState) -> Arc<TileRenderer> {
+        Arc::clone(&app_state.tiles)
+    }
+}
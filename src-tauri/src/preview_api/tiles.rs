@@ -0,0 +1,41 @@
+use super::preview::serve_webp;
+use crate::image::{PreviewCache, PreviewQueue, TileInfo, TileRenderer};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Response,
+    Json,
+};
+use std::{path::PathBuf, sync::Arc};
+
+/// The img is identified by its URL encoded preview path
+pub(crate) async fn tile_info(
+    Path(preview_path): Path<PathBuf>,
+    queue: State<Arc<PreviewQueue>>,
+    tiles: State<Arc<TileRenderer>>,
+) -> Result<Json<TileInfo>, StatusCode> {
+    let img = queue.img(&preview_path).ok_or(StatusCode::NOT_FOUND)?;
+
+    tiles.info(&img).await.map(Json).map_err(|e| {
+        println!("Failed to get tile info of {:?}: {e:#}", img.path);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+pub(crate) async fn tile(
+    Path((preview_path, level, x, y)): Path<(PathBuf, u32, u32, u32)>,
+    queue: State<Arc<PreviewQueue>>,
+    tiles: State<Arc<TileRenderer>>,
+    cache: State<Option<Arc<PreviewCache>>>,
+) -> Result<Response, StatusCode> {
+    let img = queue.img(&preview_path).ok_or(StatusCode::NOT_FOUND)?;
+
+    let path = tiles.tile(&img, level, x, y).await.map_err(|e| {
+        println!(
+            "Failed to render tile {level}/{x}/{y} of {:?}: {e:#}",
+            img.path
+        );
+        StatusCode::NOT_FOUND
+    })?;
+    serve_webp(&path, cache.as_deref()).await
+}
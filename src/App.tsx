@@ -4,6 +4,7 @@ import { invoke } from "@tauri-apps/api/tauri";
 import { appWindow } from "@tauri-apps/api/window";
 import { AppConfig } from "../src-tauri/bindings/AppConfig";
 import { ImageDir } from "../src-tauri/bindings/ImageDir";
+import { ImageSort } from "../src-tauri/bindings/ImageSort";
 import {
   ChakraProvider,
   Button,
@@ -43,6 +44,7 @@ const settingsStore = new Store(".settings.dat");
 const settingsKey = "app-settings";
 interface AppSettings {
   imageDirPath: string | undefined;
+  imageSort?: ImageSort;
 }
 
 export function App() {
@@ -58,14 +60,17 @@ export function App() {
   }
 
   async function storeAppSettings(settings: AppSettings) {
-    await settingsStore.set(settingsKey, settings);
+    // keep the rest of the stored settings
+    await settingsStore.set(settingsKey, { ...(await getAppSettings()), ...settings });
     await settingsStore.save();
   }
 
   // open dir
   async function openDir() {
     try {
-      const imgDir = await invoke<ImageDir>("open_dir_picker");
+      const imgDir = await invoke<ImageDir>("open_dir_picker", {
+        sort: (await getAppSettings())?.imageSort,
+      });
       setImageDir(imgDir);
       await storeAppSettings({
         imageDirPath: imgDir?.path,
@@ -75,6 +80,21 @@ export function App() {
     }
   }
 
+  // reopens the dir in the other order
+  async function sortDir(sort: ImageSort) {
+    if (!imageDir) {
+      return;
+    }
+
+    try {
+      setImageDir(await invoke<ImageDir>("open_dir", { path: imageDir.path, sort }));
+      await storeAppSettings({ imageDirPath: imageDir.path, imageSort: sort });
+      successToast(sort === "quality" ? "Weakest imgs first" : "Capture order");
+    } catch (error) {
+      errorToastHandler(error, "Could not sort directory");
+    }
+  }
+
   // culling done
   async function onCullFinished() {
     setImageDir(undefined);
@@ -94,6 +114,7 @@ export function App() {
         setImageDir(
           await invoke<ImageDir>("open_dir", {
             path: settings.imageDirPath,
+            sort: settings.imageSort,
           }),
         );
       } catch (e) {
@@ -143,7 +164,11 @@ export function App() {
       {appConf ? (
         <div className="chela--app tw-flex tw-overflow-hidden tw-h-full">
           {imageDir?.images.length ? (
-            <CullScreen imageDir={imageDir} onCullFinished={onCullFinished} />
+            <CullScreen
+              imageDir={imageDir}
+              onCullFinished={onCullFinished}
+              onSort={sortDir}
+            />
           ) : (
             <div className="tw-flex tw-h-full tw-w-full tw-items-center tw-justify-center">
               <Button
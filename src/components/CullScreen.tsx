@@ -3,9 +3,17 @@ import { mod } from "../utils/math";
 import { ImageDir } from "../../src-tauri/bindings/ImageDir";
 import { CulledImages } from "../../src-tauri/bindings/CulledImages";
 import { PreviewImage } from "./PreviewImage";
+import { ZoomView } from "./ZoomView";
+import { HistogramPanel } from "./HistogramPanel";
 import { ProgressBar } from "./ProgressBar";
 import { CullState } from "../../src-tauri/bindings/CullState";
 import { Image } from "../../src-tauri/bindings/Image";
+import { GroupRanking } from "../../src-tauri/bindings/GroupRanking";
+import { FrameScore } from "../../src-tauri/bindings/FrameScore";
+import { SequenceKind } from "../../src-tauri/bindings/SequenceKind";
+import { ImageStack } from "../../src-tauri/bindings/ImageStack";
+import { StackKind } from "../../src-tauri/bindings/StackKind";
+import { ImageSort } from "../../src-tauri/bindings/ImageSort";
 import { useSetAtom } from "jotai";
 import { titleAtom } from "../store/navStore";
 import { invoke } from "@tauri-apps/api";
@@ -15,20 +23,56 @@ import { FinishCullDialog } from "./FinishCullDialog";
 import { indexOrUndefined } from "../utils/array";
 import { useSuccessToast, useErrorToast } from "../hooks/toast";
 import { FaChevronDown } from "react-icons/fa";
+import {
+  formatPreviewProgress,
+  usePreviewProgress,
+} from "../hooks/previewProgress";
 
 export type ImageStateMap = Map<CullState, number>;
 
+const sequenceLabels: Record<SequenceKind, string> = {
+  bracket: "HDR",
+  focusStack: "focus stack",
+  panorama: "pano",
+};
+
+const stackLabels: Record<StackKind, string> = {
+  burst: "burst",
+  sequence: "sequence",
+  duplicates: "duplicates",
+  manual: "stack",
+};
+
 export function CullScreen({
   imageDir,
   onCullFinished,
+  onSort,
 }: {
   imageDir: ImageDir;
   onCullFinished: () => void;
+  onSort: (sort: ImageSort) => Promise<void>;
 }) {
   const successToast = useSuccessToast();
   const errorToast = useErrorToast();
   const [imageIndex, setImageIndex] = useState(0);
   const [showRejected, setShowRejected] = useState(false);
+  const [focusPeaking, setFocusPeaking] = useState(false);
+  const [clipping, setClipping] = useState(false);
+  const [zoom, setZoom] = useState(false);
+  const [histogram, setHistogram] = useState(false);
+  const [similarGroups, setSimilarGroups] = useState<string[][]>();
+  const [keepers, setKeepers] = useState<GroupRanking[]>();
+  const [stacks, setStacks] = useState<ImageStack[]>(imageDir.stacks);
+
+  const scores = useMemo(
+    () =>
+      new Map(
+        keepers?.flatMap((ranking) =>
+          ranking.scores.map((score): [string, FrameScore] => [score.image, score]),
+        ),
+      ),
+    [keepers],
+  );
 
   const images = useMemo(() => {
     return imageDir.images.flat();
@@ -54,6 +98,10 @@ export function CullScreen({
     }
   }, [imageIndex, images, visibleImages, showRejected]);
 
+  useEffect(() => {
+    setStacks(imageDir.stacks);
+  }, [imageDir]);
+
   useEffect(() => {
     setUnprocessedIndex();
     setImageIndex(images.findIndex((i) => i.state === "new") ?? 0);
@@ -79,12 +127,32 @@ export function CullScreen({
     } else if (["ArrowRight", "KeyO"].includes(ev.code)) {
       ev.preventDefault();
       nextImage();
+    } else if (ev.code === "ArrowDown") {
+      ev.preventDefault();
+      nextScene();
+    } else if (ev.code === "ArrowUp") {
+      ev.preventDefault();
+      prevScene();
     } else if (ev.code === "Tab") {
       ev.preventDefault();
       setUnprocessedIndex();
     } else if (ev.code === "Escape") {
       ev.preventDefault();
       await setImgCullState("new");
+    } else if (ev.code === "Space" && ev.ctrlKey) {
+      // the whole stack, eg. a bracket
+      ev.preventDefault();
+      await setStackCullState("selected");
+      nextScene();
+    } else if (ev.code === "Backspace" && ev.ctrlKey) {
+      ev.preventDefault();
+      await setStackCullState("rejected");
+      nextScene();
+    } else if (ev.code === "Space" && ev.shiftKey) {
+      // the best frame of the burst
+      ev.preventDefault();
+      await setImgCullState("selected");
+      nextScene();
     } else if (ev.code === "Space") {
       ev.preventDefault();
       await setImgCullState("selected");
@@ -100,6 +168,47 @@ export function CullScreen({
       // const currImg = images[imageIndex]
       // images.filter((i) => isImageVisible(i, rejected));
       // setImageIndex();
+    } else if (ev.code === "KeyF") {
+      ev.preventDefault();
+      setFocusPeaking(!focusPeaking);
+    } else if (ev.code === "KeyC") {
+      ev.preventDefault();
+      setClipping(!clipping);
+    } else if (ev.code === "KeyH") {
+      ev.preventDefault();
+      setHistogram(!histogram);
+    } else if (ev.code === "KeyZ") {
+      // 1:1 to check the focus
+      ev.preventDefault();
+      setZoom(!zoom);
+    } else if (ev.code === "KeyK" && ev.shiftKey) {
+      ev.preventDefault();
+      await applyKeepers();
+    } else if (ev.code === "KeyK") {
+      ev.preventDefault();
+      await suggestKeepers();
+    } else if (ev.code === "KeyS" && ev.shiftKey) {
+      ev.preventDefault();
+      await editStacks("split_stack", { image: images[imageIndex].previewPath });
+    } else if (ev.code === "KeyM") {
+      ev.preventDefault();
+      await mergeWithPrevScene();
+    } else if (ev.code === "KeyG" && ev.shiftKey) {
+      ev.preventDefault();
+      await stackSimilarGroup();
+    } else if (ev.code === "KeyG") {
+      ev.preventDefault();
+      await toggleSimilarGroups();
+    } else if (ev.code === "KeyQ") {
+      // the weakest imgs first
+      ev.preventDefault();
+      await onSort(imageDir.sort === "quality" ? "capture" : "quality");
+    } else if (ev.code === "KeyB" && ev.shiftKey) {
+      ev.preventDefault();
+      await rejectBlank();
+    } else if (ev.code === "KeyB") {
+      ev.preventDefault();
+      await rejectBlurred();
     } else if (ev.code === "Enter") {
       ev.preventDefault();
 
@@ -131,6 +240,35 @@ export function CullScreen({
     moveImageIndex(-1);
   }
 
+  function getStack(image: Image | undefined) {
+    return stacks.find((stack) => image && stack.images.includes(image.previewPath));
+  }
+
+  function getImageIndex(previewPath: string) {
+    return images.findIndex((img) => img.previewPath === previewPath);
+  }
+
+  // first img of the following burst or single shot
+  function nextScene() {
+    const stack = getStack(images[imageIndex]);
+    const last = stack ? getImageIndex(stack.images[stack.images.length - 1]) : imageIndex;
+    const next = images.findIndex((img, i) => i > last && isImageVisible(img, showRejected));
+    if (next >= 0) {
+      setImageIndex(next);
+    }
+  }
+
+  // start of the current scene or the previous one if already there
+  function prevScene() {
+    function sceneStart(index: number) {
+      const stack = getStack(images[index]);
+      return stack ? getImageIndex(stack.images[0]) : index;
+    }
+
+    const start = sceneStart(imageIndex);
+    setImageIndex(start === imageIndex && imageIndex > 0 ? sceneStart(imageIndex - 1) : start);
+  }
+
   async function setImgCullState(state: CullState) {
     const img = images[imageIndex];
     img.state = state;
@@ -144,6 +282,141 @@ export function CullScreen({
     });
   }
 
+  // the whole stack is culled in a single call
+  async function setStackCullState(state: CullState) {
+    try {
+      const culled = await invoke<string[]>("cull_stack", {
+        image: images[imageIndex].previewPath,
+        state,
+      });
+      for (const img of images) {
+        if (culled.includes(img.previewPath)) {
+          img.state = state;
+        }
+      }
+    } catch (e) {
+      errorToast("Failed to cull the stack", `${e}`);
+    }
+  }
+
+  async function editStacks(command: string, args: Record<string, unknown>) {
+    try {
+      setStacks(await invoke<ImageStack[]>(command, args));
+    } catch (e) {
+      errorToast("Failed to edit the stacks", `${e}`);
+    }
+  }
+
+  // joins the current scene with the one before it
+  async function mergeWithPrevScene() {
+    const stack = getStack(images[imageIndex]);
+    const start = stack ? getImageIndex(stack.images[0]) : imageIndex;
+    if (start > 0) {
+      await editStacks("merge_stacks", {
+        images: [images[start - 1].previewPath, images[imageIndex].previewPath],
+      });
+    }
+  }
+
+  async function stackSimilarGroup() {
+    const group = getSimilarGroup(images[imageIndex]);
+    if (!group) {
+      errorToast("Not in a similar group", "Press G first");
+      return;
+    }
+
+    await editStacks("create_stack", { images: group, kind: "duplicates" });
+  }
+
+  async function rejectBlurred() {
+    try {
+      const rejected = await invoke<string[]>("apply_reject_suggestions");
+      for (const img of images) {
+        if (rejected.includes(img.previewPath)) {
+          img.state = "rejected";
+        }
+      }
+      // jump over the rejected ones, which re-renders the states as well
+      setUnprocessedIndex();
+      successToast("Blurred imgs rejected", `${rejected.length} imgs`);
+    } catch (e) {
+      errorToast("Failed to reject blurred imgs", `${e}`);
+    }
+  }
+
+  async function rejectBlank() {
+    try {
+      const rejected = await invoke<string[]>("reject_blank_frames");
+      for (const img of images) {
+        if (rejected.includes(img.previewPath)) {
+          img.state = "rejected";
+        }
+      }
+      setUnprocessedIndex();
+      successToast("Blank frames rejected", `${rejected.length} imgs`);
+    } catch (e) {
+      errorToast("Failed to reject blank frames", `${e}`);
+    }
+  }
+
+  async function toggleSimilarGroups() {
+    if (similarGroups) {
+      setSimilarGroups(undefined);
+      return;
+    }
+
+    try {
+      const groups = await invoke<string[][]>("find_similar");
+      setSimilarGroups(groups);
+      successToast("Similar imgs grouped", `${groups.length} groups`);
+    } catch (e) {
+      errorToast("Failed to group similar imgs", `${e}`);
+    }
+  }
+
+  async function suggestKeepers() {
+    try {
+      const rankings = await invoke<GroupRanking[]>("suggest_keepers");
+      setKeepers(rankings);
+      successToast("Keepers suggested", `${rankings.length} groups, Shift+K applies them`);
+    } catch (e) {
+      errorToast("Failed to suggest keepers", `${e}`);
+    }
+  }
+
+  // the keeper is selected and the rest of the group rejected in a single write
+  async function applyKeepers() {
+    if (!keepers) {
+      errorToast("No keepers suggested", "Press K first");
+      return;
+    }
+
+    const culled: CulledImages = {};
+    for (const ranking of keepers) {
+      for (const { image } of ranking.scores) {
+        const img = images.find((i) => i.previewPath === image);
+        // already culled imgs are left as they are
+        if (img?.state === "new") {
+          img.state = image === ranking.keeper ? "selected" : "rejected";
+          culled[image] = img.state;
+        }
+      }
+    }
+
+    try {
+      await invoke<void>("cull_images", { culled });
+      setKeepers(undefined);
+      setUnprocessedIndex();
+      successToast("Keepers applied", `${Object.keys(culled).length} imgs culled`);
+    } catch (e) {
+      errorToast("Failed to apply keepers", `${e}`);
+    }
+  }
+
+  function getSimilarGroup(image: Image | undefined) {
+    return similarGroups?.find((group) => image && group.includes(image.previewPath));
+  }
+
   useEffect(() => {
     const handler = forgetFnReturn(onKeyDown);
     document.addEventListener("keydown", handler);
@@ -155,9 +428,36 @@ export function CullScreen({
 
   // title
   const setTitle = useSetAtom(titleAtom);
+  const previewProgress = usePreviewProgress();
+  const similarGroup = getSimilarGroup(images[imageIndex]);
+  const stack = getStack(images[imageIndex]);
   useEffect(() => {
-    setTitle(`${imageIndex + 1}/${images.length}`);
-  }, [images.length, imageIndex, setTitle]);
+    const previews =
+      previewProgress && previewProgress.done < previewProgress.total
+        ? ` (${formatPreviewProgress(previewProgress)})`
+        : "";
+    const similar = similarGroup
+      ? ` [similar ${similarGroup.indexOf(images[imageIndex].previewPath) + 1}/${
+          similarGroup.length
+        }]`
+      : "";
+    const image = images[imageIndex];
+    const stackLabel =
+      stack?.kind === "sequence" && image?.sequence
+        ? sequenceLabels[image.sequence.kind]
+        : stack && stackLabels[stack.kind];
+    const scene = stack
+      ? ` [${stackLabel} ${stack.images.indexOf(image.previewPath) + 1}/${
+          stack.images.length
+        }]`
+      : "";
+    setTitle(`${imageIndex + 1}/${images.length}${scene}${similar}${previews}`);
+  }, [images, imageIndex, previewProgress, similarGroup, stack, setTitle]);
+
+  // generate previews around the current image first
+  useEffect(() => {
+    void invoke("set_focus_index", { index: imageIndex });
+  }, [imageIndex]);
 
   // progress
   const stateCounts: ImageStateMap = images.reduce((map, img) => {
@@ -201,12 +501,31 @@ export function CullScreen({
           )}
 
           {/* Processed preview */}
-          <PreviewImage
-            image={visibleImages[getVisibleImageIndex(visibleImageIndex)]}
-            active={true}
-            key={getVisibleImageIndex(visibleImageIndex)}
-            thumbnail={false}
-          />
+          {zoom && visibleImages.length > 0 ? (
+            <ZoomView
+              image={visibleImages[getVisibleImageIndex(visibleImageIndex)]}
+              key={getVisibleImageIndex(visibleImageIndex)}
+            />
+          ) : (
+            <PreviewImage
+              image={visibleImages[getVisibleImageIndex(visibleImageIndex)]}
+              active={true}
+              key={getVisibleImageIndex(visibleImageIndex)}
+              thumbnail={false}
+              focusPeaking={focusPeaking}
+              clipping={clipping}
+              grouped={!!similarGroup || !!stack}
+              score={scores.get(
+                visibleImages[getVisibleImageIndex(visibleImageIndex)]?.previewPath,
+              )}
+            />
+          )}
+
+          {histogram && visibleImages.length > 0 && (
+            <HistogramPanel
+              image={visibleImages[getVisibleImageIndex(visibleImageIndex)]}
+            />
+          )}
 
           {/* Next preview */}
           {images.length >= 2 ? (
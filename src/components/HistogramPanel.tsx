@@ -0,0 +1,106 @@
+import { useState } from "react";
+import { useAsyncEffect } from "use-async-effect";
+import { useAtomValue } from "jotai";
+import { Image } from "../../src-tauri/bindings/Image";
+import { Exposure } from "../../src-tauri/bindings/Exposure";
+import { configAtom } from "../store/configStore";
+
+const width = 256;
+const height = 100;
+
+const channels = [
+  { key: "luma", stroke: "#e5e7eb", fill: "#e5e7eb4d" },
+  { key: "red", stroke: "#f87171", fill: "none" },
+  { key: "green", stroke: "#34d399", fill: "none" },
+  { key: "blue", stroke: "#60a5fa", fill: "none" },
+] as const;
+
+// outline of the channel, scaled to the highest count of all channels
+function getPoints(counts: number[], max: number) {
+  const points = counts.map(
+    (count, value) => `${value},${height - Math.min(count / max, 1) * height}`,
+  );
+  return `0,${height} ${points.join(" ")} ${width - 1},${height}`;
+}
+
+export function HistogramPanel({ image }: { image: Image }) {
+  const conf = useAtomValue(configAtom);
+  const [exposure, setExposure] = useState<Exposure>();
+  const [error, setError] = useState<string>();
+
+  useAsyncEffect(
+    async (isMounted) => {
+      setExposure(undefined);
+      setError(undefined);
+      try {
+        // waits for the preview to be generated
+        const res = await fetch(
+          `http://${conf.previewApiUrl}/histogram?image=${encodeURIComponent(
+            image.previewPath,
+          )}`,
+        );
+        if (!res.ok) {
+          throw new Error(`${res.status} ${res.statusText}`);
+        }
+
+        const exposure = (await res.json()) as Exposure;
+        if (isMounted()) {
+          setExposure(exposure);
+        }
+      } catch (e) {
+        if (isMounted()) {
+          setError(`${e}`);
+        }
+      }
+    },
+    [conf.previewApiUrl, image.previewPath],
+  );
+
+  function getMax() {
+    if (!exposure) {
+      return 1;
+    }
+
+    // the clipped ends would flatten the rest
+    return Math.max(
+      1,
+      ...channels.flatMap(({ key }) => exposure.histogram[key].slice(1, -1)),
+    );
+  }
+
+  const max = getMax();
+  const format = (percent: number) => `${percent.toFixed(1)}%`;
+
+  return (
+    <div className="tw-absolute tw-right-4 tw-top-4 tw-z-[3] tw-rounded-md tw-bg-dark/80 tw-p-2 tw-text-xs">
+      {exposure ? (
+        <>
+          <svg
+            viewBox={`0 0 ${width} ${height}`}
+            preserveAspectRatio="none"
+            className="tw-h-24 tw-w-64"
+          >
+            {channels.map(({ key, stroke, fill }) => (
+              <polyline
+                key={key}
+                points={getPoints(exposure.histogram[key], max)}
+                stroke={stroke}
+                fill={fill}
+                strokeWidth={1}
+                vectorEffect="non-scaling-stroke"
+              />
+            ))}
+          </svg>
+          <div className="tw-flex tw-justify-between">
+            <span>Shadows {format(exposure.crushedShadows)}</span>
+            <span>Highlights {format(exposure.clippedHighlights)}</span>
+          </div>
+        </>
+      ) : (
+        <div className="tw-flex tw-h-24 tw-w-64 tw-items-center tw-justify-center">
+          {error ? `No histogram: ${error}` : "Loading histogram"}
+        </div>
+      )}
+    </div>
+  );
+}
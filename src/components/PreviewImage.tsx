@@ -2,6 +2,8 @@ import { useMemo } from "react";
 import { useMeasure } from "react-use";
 import { Spinner } from "@chakra-ui/react";
 import { Image } from "../../src-tauri/bindings/Image";
+import { PreviewSize } from "../../src-tauri/bindings/PreviewSize";
+import { FrameScore } from "../../src-tauri/bindings/FrameScore";
 import { useAtomValue } from "jotai";
 import { configAtom } from "../store/configStore";
 
@@ -10,19 +12,38 @@ export function PreviewImage({
   image,
   thumbnail,
   grouped,
+  focusPeaking,
+  clipping,
+  score,
   className,
 }: {
   image: Image;
   active: boolean;
   thumbnail: boolean;
   grouped?: boolean;
+  focusPeaking?: boolean;
+  clipping?: boolean;
+  score?: FrameScore;
   className?: string;
 }) {
   const conf = useAtomValue(configAtom);
 
   function getPreviewUrl() {
+    const size: PreviewSize = thumbnail ? "thumbnail" : "preview";
     return `http://${conf.previewApiUrl}/preview?path=${encodeURIComponent(
       image.previewPath,
+    )}&size=${size}`;
+  }
+
+  function getFocusOverlayUrl() {
+    return `http://${conf.previewApiUrl}/overlay/focus?image=${encodeURIComponent(
+      image.previewPath,
+    )}`;
+  }
+
+  function getClippingOverlayUrl() {
+    return `http://${conf.previewApiUrl}/overlay/clipping?image=${encodeURIComponent(
+      image.previewPath,
     )}`;
   }
 
@@ -92,13 +113,49 @@ export function PreviewImage({
     } ${getFlagStateClass()}`;
   }, [borderClass, image.state, stateColorClass]);
 
+  function getTitle() {
+    if (score) {
+      const format = (value: number) => value.toFixed(2);
+      return `Score ${format(score.score)} (sharpness ${format(score.sharpness)}, exposure ${format(
+        score.exposure,
+      )}, subject contrast ${format(score.subjectContrast)})`;
+    }
+
+    if (image.blank) {
+      return "Suggested reject: blank frame";
+    }
+
+    return image.suggestedReject ? "Suggested reject: blurred" : undefined;
+  }
+
   function getSpinnerSize() {
     return thumbnail ? 65 : 250;
   }
 
   return (
     <div className={wrapperClass}>
-      <img ref={imgRef} src={getPreviewUrl()} className={imgClass} />
+      <img
+        ref={imgRef}
+        src={getPreviewUrl()}
+        className={imgClass}
+        title={image.previewError ?? getTitle()}
+      />
+
+      {focusPeaking && !thumbnail && (
+        <img
+          src={getFocusOverlayUrl()}
+          style={{ width: `${imgWidth}px`, height: `${imgHeight}px` }}
+          className="tw-absolute tw-z-[1] tw-pointer-events-none"
+        />
+      )}
+
+      {clipping && !thumbnail && (
+        <img
+          src={getClippingOverlayUrl()}
+          style={{ width: `${imgWidth}px`, height: `${imgHeight}px` }}
+          className="tw-absolute tw-z-[1] tw-pointer-events-none tw-animate-pulse"
+        />
+      )}
 
       <div
         style={{ width: `${imgWidth}px`, height: `${imgHeight}px` }}
@@ -0,0 +1,133 @@
+import { useState, MouseEvent } from "react";
+import { useMeasure } from "react-use";
+import { useAsyncEffect } from "use-async-effect";
+import { useAtomValue } from "jotai";
+import { Spinner } from "@chakra-ui/react";
+import { Image } from "../../src-tauri/bindings/Image";
+import { TileInfo } from "../../src-tauri/bindings/TileInfo";
+import { configAtom } from "../store/configStore";
+import { useErrorToast } from "../hooks/toast";
+
+function clamp(value: number, max: number) {
+  return Math.min(Math.max(value, 0), max);
+}
+
+// the original at 1:1, only the visible tiles of the highest level are loaded
+export function ZoomView({ image }: { image: Image }) {
+  const conf = useAtomValue(configAtom);
+  const errorToast = useErrorToast();
+  const [info, setInfo] = useState<TileInfo>();
+  // img px in the middle of the view
+  const [center, setCenter] = useState<[number, number]>([0, 0]);
+  const [dragStart, setDragStart] = useState<[number, number]>();
+  const [viewRef, { width, height }] = useMeasure<HTMLDivElement>();
+
+  const tilesUrl = `http://${conf.previewApiUrl}/tiles/${encodeURIComponent(
+    image.previewPath,
+  )}`;
+  // a tile px is a screen px
+  const scale = 1 / window.devicePixelRatio;
+
+  useAsyncEffect(
+    async (isMounted) => {
+      setInfo(undefined);
+      try {
+        const res = await fetch(tilesUrl);
+        if (!res.ok) {
+          throw new Error(`${res.status} ${res.statusText}`);
+        }
+
+        const info = (await res.json()) as TileInfo;
+        if (isMounted()) {
+          setInfo(info);
+          setCenter([info.width / 2, info.height / 2]);
+        }
+      } catch (e) {
+        errorToast("Failed to zoom in", `${e}`);
+      }
+    },
+    [tilesUrl],
+  );
+
+  function getTiles() {
+    if (!info) {
+      return [];
+    }
+
+    const size = info.tileSize;
+    const viewWidth = width / scale;
+    const viewHeight = height / scale;
+    const left = center[0] - viewWidth / 2;
+    const top = center[1] - viewHeight / 2;
+    const firstColumn = Math.max(Math.floor(left / size), 0);
+    const lastColumn = Math.min(
+      Math.ceil((left + viewWidth) / size),
+      Math.ceil(info.width / size),
+    );
+    const firstRow = Math.max(Math.floor(top / size), 0);
+    const lastRow = Math.min(
+      Math.ceil((top + viewHeight) / size),
+      Math.ceil(info.height / size),
+    );
+
+    const tiles = [];
+    for (let y = firstRow; y < lastRow; y++) {
+      for (let x = firstColumn; x < lastColumn; x++) {
+        tiles.push({
+          x,
+          y,
+          left: (x * size - left) * scale,
+          top: (y * size - top) * scale,
+          width: Math.min(size, info.width - x * size) * scale,
+          height: Math.min(size, info.height - y * size) * scale,
+        });
+      }
+    }
+    return tiles;
+  }
+
+  function onMouseMove(ev: MouseEvent) {
+    if (!info || !dragStart) {
+      return;
+    }
+
+    const [startX, startY] = dragStart;
+    setCenter(([x, y]) => [
+      clamp(x - (ev.clientX - startX) / scale, info.width),
+      clamp(y - (ev.clientY - startY) / scale, info.height),
+    ]);
+    setDragStart([ev.clientX, ev.clientY]);
+  }
+
+  return (
+    <div
+      ref={viewRef}
+      className="chela--stripy-bg tw-relative tw-h-full tw-w-full tw-overflow-hidden tw-rounded-md tw-cursor-move"
+      onMouseDown={(ev) => setDragStart([ev.clientX, ev.clientY])}
+      onMouseMove={onMouseMove}
+      onMouseUp={() => setDragStart(undefined)}
+      onMouseLeave={() => setDragStart(undefined)}
+    >
+      {info ? (
+        getTiles().map((tile) => (
+          <img
+            key={`${tile.x}_${tile.y}`}
+            src={`${tilesUrl}/${info.maxLevel}/${tile.x}/${tile.y}`}
+            draggable={false}
+            className="tw-absolute tw-max-w-none"
+            style={{
+              left: `${tile.left}px`,
+              top: `${tile.top}px`,
+              width: `${tile.width}px`,
+              height: `${tile.height}px`,
+            }}
+          />
+        ))
+      ) : (
+        <div className="tw-flex tw-h-full tw-items-center tw-justify-center">
+          <Spinner color="gray.300" opacity={0.8} size="xl" thickness="20px" />
+        </div>
+      )}
+    </div>
+  );
+}
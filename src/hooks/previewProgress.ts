@@ -0,0 +1,31 @@
+import { invoke } from "@tauri-apps/api";
+import { listen } from "@tauri-apps/api/event";
+import { useEffect, useState } from "react";
+import { PreviewEvent } from "../../src-tauri/bindings/PreviewEvent";
+import { PreviewProgress } from "../../src-tauri/bindings/PreviewProgress";
+
+export function usePreviewProgress() {
+  const [progress, setProgress] = useState<PreviewProgress>();
+
+  useEffect(() => {
+    invoke<PreviewProgress>("get_preview_progress")
+      .then(setProgress)
+      .catch(console.error);
+
+    const unlisten = listen<PreviewEvent>("preview-progress", (ev) =>
+      setProgress(ev.payload.progress),
+    );
+
+    return () => {
+      unlisten.then((fn) => fn()).catch(console.error);
+    };
+  }, []);
+
+  return progress;
+}
+
+export function formatPreviewProgress(progress: PreviewProgress) {
+  const eta =
+    progress.etaSecs != null ? ` ~${Math.ceil(progress.etaSecs / 60)}min` : "";
+  return `previews ${progress.done}/${progress.total}${eta}`;
+}